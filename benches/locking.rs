@@ -0,0 +1,86 @@
+//! Compares the in-memory storage's read/write lock with the single exclusive
+//! lock it used to have, under a read-heavy mix: a few tasks adding, getting
+//! and deleting while many others peek and read stats.
+//!
+//! The exclusive lock is emulated by holding one `Mutex` around every call.
+//! Run with `cargo bench --bench locking`.
+
+use smql::storage::{MemoryStorage, Storage};
+use smql::Message;
+use std::future::Future;
+use std::sync::Arc;
+use std::thread::available_parallelism;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Messages in the queue before the tasks start.
+const BACKLOG: usize = 2_000;
+const WRITERS: usize = 2;
+const READERS: usize = 30;
+/// Calls each task makes.
+const CALLS: usize = 2_000;
+
+fn main() {
+    let cores = available_parallelism().map_or(4, |cores| cores.get());
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(cores)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!("{cores} worker threads, {WRITERS} writers, {READERS} readers, {CALLS} calls each");
+    for (name, exclusive) in [("exclusive lock", true), ("read/write lock", false)] {
+        let elapsed = runtime.block_on(run(exclusive));
+        println!("{name:>16}: {elapsed:.2?}");
+    }
+}
+
+async fn run(exclusive: bool) -> Duration {
+    let storage = Arc::new(MemoryStorage::new());
+    for _ in 0..BACKLOG {
+        storage.add("jobs", Message::new("backlog")).await.unwrap();
+    }
+    let lock = Arc::new(exclusive.then(|| Mutex::new(())));
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for _ in 0..WRITERS {
+        let (storage, lock) = (storage.clone(), lock.clone());
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..CALLS {
+                let lock = lock.as_ref().as_ref();
+                locked(lock, storage.add("jobs", Message::new("work"))).await.unwrap();
+                let get = storage.get("jobs", 1, 1, Duration::ZERO, false, None);
+                let taken = locked(lock, get).await.unwrap();
+                let ids = taken.iter().map(|message| message.id.to_string()).collect();
+                locked(lock, storage.delete("jobs", ids, None)).await.unwrap();
+            }
+        }));
+    }
+    for _ in 0..READERS {
+        let (storage, lock) = (storage.clone(), lock.clone());
+        tasks.push(tokio::spawn(async move {
+            for call in 0..CALLS {
+                let lock = lock.as_ref().as_ref();
+                if call % 2 == 0 {
+                    locked(lock, storage.peek("jobs", 0, 10)).await.unwrap();
+                } else {
+                    locked(lock, storage.stats("jobs")).await.unwrap();
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    start.elapsed()
+}
+
+/// Runs `call`, holding `lock` throughout when there is one.
+async fn locked<T>(lock: Option<&Mutex<()>>, call: impl Future<Output = T>) -> T {
+    let _guard = match lock {
+        Some(lock) => Some(lock.lock().await),
+        None => None,
+    };
+    call.await
+}
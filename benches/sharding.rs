@@ -0,0 +1,69 @@
+//! Throughput of `ShardedStorage` by shard count, with many tasks adding,
+//! getting and deleting at once on a multi-threaded runtime.
+//!
+//! Run with `cargo bench --bench sharding`.
+
+use smql::storage::{ShardedStorage, Storage};
+use smql::Message;
+use std::sync::Arc;
+use std::thread::available_parallelism;
+use std::time::{Duration, Instant};
+
+/// Tasks working on the queue at once.
+const TASKS: usize = 32;
+/// Messages each task adds, takes and deletes.
+const MESSAGES_PER_TASK: usize = 1_000;
+/// Messages already in the queue, so gets have something to sort through.
+const BACKLOG: usize = 2_000;
+
+fn main() {
+    let cores = available_parallelism().map_or(4, |cores| cores.get());
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(cores)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!("{cores} worker threads, {TASKS} tasks, {MESSAGES_PER_TASK} messages each");
+    println!("{:>6} {:>10} {:>12}", "shards", "time", "messages/s");
+    let mut counts = vec![1, 2, 4, 8, 16];
+    if !counts.contains(&cores) {
+        counts.push(cores);
+        counts.sort_unstable();
+    }
+    for shards in counts {
+        let elapsed = runtime.block_on(run(shards));
+        let rate = (TASKS * MESSAGES_PER_TASK) as f64 / elapsed.as_secs_f64();
+        println!("{shards:>6} {:>9.0?} {rate:>12.0}", elapsed);
+    }
+}
+
+async fn run(shards: usize) -> Duration {
+    let storage = Arc::new(ShardedStorage::new(shards));
+    for i in 0..BACKLOG {
+        let message = Message { priority: (i % 4) as u8, ..Message::new("backlog") };
+        storage.add("jobs", message).await.unwrap();
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|_| {
+            let storage = storage.clone();
+            tokio::spawn(async move {
+                for _ in 0..MESSAGES_PER_TASK {
+                    storage.add("jobs", Message::new("work")).await.unwrap();
+                    let taken = storage
+                        .get("jobs", 1, 1, Duration::ZERO, false, None)
+                        .await
+                        .unwrap();
+                    let ids = taken.iter().map(|message| message.id.to_string()).collect();
+                    storage.delete("jobs", ids, None).await.unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    start.elapsed()
+}
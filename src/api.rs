@@ -1,47 +1,661 @@
-use crate::{Error, Message, MessageService};
-use axum::extract::State;
+use crate::auth::{constant_time_eq, ApiKeyLayer};
+use crate::rate_limit::RateLimitLayer;
+use crate::storage::StorageError;
+use crate::{
+    config, group_queue, AddOptions, Capabilities, DeleteOutcome, DrainReport, Error, ExpiredMessage,
+    Message, MessageBody, MessageService, MessageState, QueueExport, QueueStats, QueuedMessage,
+    RetryOutcome, SearchFilter,
+    DEFAULT_QUEUE, REQUEST_ID,
+};
+use axum::body::{Body, Bytes};
+use axum::extract::rejection::{BytesRejection, JsonRejection};
+use axum::extract::{DefaultBodyLimit, FromRequest, OptionalFromRequest, Path, Query, Request, State};
+use axum::http::header::{
+    ACCEPT, CONNECTION, CONTENT_TYPE, LOCATION, RETRY_AFTER, SEC_WEBSOCKET_ACCEPT,
+    SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::{from_fn, map_response, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, Router};
+use futures_util::stream::{self, Stream, StreamExt};
+use hyper_util::rt::TokioIo;
 use serde::{Deserialize, Serialize};
 use skyak_axum_core::errors::ApiError;
 use skyak_axum_core::https::{error, success, ApiResponse};
-use tower_http::cors::{Any, CorsLayer};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Room for JSON framing and escaping on top of `max_message_size`. The body
+/// limit is also doubled so base64-encoded binary bodies still fit.
+const REQUEST_BODY_OVERHEAD: usize = 16 * 1024;
+/// Seconds clients are asked to wait before retrying a 503.
+const RETRY_AFTER_SECS: u64 = 1;
+/// Longest `X-Request-Id` taken from a client; longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+fn batch_body_limit() -> usize {
+    config()
+        .max_batch_bytes
+        .saturating_mul(2)
+        .saturating_add(REQUEST_BODY_OVERHEAD)
+        .max(request_body_limit())
+}
+
+fn request_body_limit() -> usize {
+    config()
+        .max_message_size
+        .saturating_mul(2)
+        .saturating_add(REQUEST_BODY_OVERHEAD)
+}
+
+/// A JSON extractor that reports rejections as SMQL's usual 400 responses
+/// instead of axum's default plain-text errors.
+pub struct JsonBody<T>(pub T);
+
+/// Lets a route take an optional body: a request without a `Content-Type`
+/// header extracts as `None`.
+impl<T, S> OptionalFromRequest<S> for JsonBody<T>
+where
+    Json<T>: OptionalFromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        match <Json<T> as OptionalFromRequest<S>>::from_request(request, state).await {
+            Ok(value) => Ok(value.map(|Json(value)| JsonBody(value))),
+            Err(rejection) => Err(rejection_error(rejection.status(), rejection.body_text())),
+        }
+    }
+}
+
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => Ok(JsonBody(value)),
+            Err(rejection) => Err(rejection_error(rejection.status(), rejection.body_text())),
+        }
+    }
+}
+
+fn rejection_error(status: StatusCode, text: String) -> ApiError {
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        ApiError::BadRequest(Some("Message body size is too large".to_string()))
+    } else {
+        ApiError::BadRequest(Some(text))
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AddMessageRequest {
-    pub body: String,
+    /// Either a JSON string or `{"base64": "..."}` for binary data.
+    pub body: MessageBody,
+    /// A UUID to use instead of a generated id.
+    pub id: Option<String>,
+    pub content_type: Option<String>,
+    pub delay_secs: Option<u64>,
+    pub priority: Option<u8>,
+    pub ttl_secs: Option<u64>,
+    pub dedup_id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// Hex HMAC-SHA256 of the body, verified when the server has `SMQL_HMAC_KEY`.
+    pub signature: Option<String>,
+    /// Retries allowed before dead-lettering, overriding `SMQL_MAX_RETRIES`.
+    pub max_retries: Option<i32>,
+    /// Messages with the same `group_id` are delivered one at a time, in order.
+    pub group_id: Option<String>,
+}
+
+/// Starts a chunked upload, with the same settings as an add but no body.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BeginUploadRequest {
+    pub id: Option<String>,
+    pub content_type: Option<String>,
+    pub delay_secs: Option<u64>,
+    pub priority: Option<u8>,
+    pub ttl_secs: Option<u64>,
+    pub dedup_id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// Hex HMAC-SHA256 of the whole body, checked when the upload completes.
+    pub signature: Option<String>,
+    pub max_retries: Option<i32>,
+    pub group_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadChunkRequest {
+    pub upload_id: String,
+    /// Either a JSON string or `{"base64": "..."}`; one binary chunk makes the
+    /// whole body binary.
+    pub chunk: MessageBody,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompleteUploadRequest {
+    pub upload_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadResponse {
+    pub upload_id: String,
+    /// Bytes received so far.
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddBatchRequest {
+    pub bodies: Vec<String>,
+}
+
+/// The outcome of adding one body from a batch: either the created message and
+/// its URL, or the reason it was rejected.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddBatchItem {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
+    /// Where the message can be fetched, as in the `Location` header of an add.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetMessagesRequest {
     pub count: Option<usize>,
+    pub wait_secs: Option<u64>,
+    /// Wait for at least this many messages before returning, until the wait runs out.
+    pub min_count: Option<usize>,
+    /// Same as `count`; takes precedence when both are given.
+    pub max_count: Option<usize>,
+    /// Wait in milliseconds; takes precedence over `wait_secs`.
+    pub wait_ms: Option<u64>,
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+    /// Ready messages to skip before the page, for peek.
+    #[serde(default)]
+    pub offset: usize,
+    /// Remove the messages as they are taken instead of locking them, for get.
+    #[serde(default)]
+    pub auto_ack: bool,
+    /// Lock the messages for this long under a new lease token, for get.
+    pub lease_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchRequest {
+    #[serde(flatten)]
+    pub filter: SearchFilter,
+    /// Matches to skip, for paging through results.
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetAnyRequest {
+    /// Queue names to take from; `*` stands for every existing queue.
+    pub queues: Vec<String>,
+    pub count: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeleteMessagesRequest {
     pub ids: Vec<String>,
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+    /// The lease the messages were taken under, if any.
+    pub lease_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PurgeRequest {
+    /// Only purge messages in this state; every message when unset.
+    pub state: Option<MessageState>,
+    /// Must match `purge_token` when one is configured.
+    pub token: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RetryMessagesRequest {
     pub ids: Vec<String>,
+    /// Requeue ahead of the other ready messages of the same priority.
+    #[serde(default)]
+    pub front: bool,
+    /// Seconds to hold the messages back instead of the retry backoff.
+    pub delay_secs: Option<u64>,
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+}
+
+fn queue_name(queue: Option<Path<String>>) -> String {
+    queue
+        .map(|Path(name)| name)
+        .unwrap_or_else(|| DEFAULT_QUEUE.to_string())
+}
+
+/// Returns the queue a consumer works on: the queue holding its group's copies
+/// when a group is given, otherwise the queue itself.
+fn consumer_queue(queue: Option<Path<String>>, group: Option<&str>) -> String {
+    let queue = queue_name(queue);
+    match group {
+        Some(group) => group_queue(&queue, group),
+        None => queue,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GroupRequest {
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GroupsResponse {
+    pub groups: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AmendMessageRequest {
+    pub id: String,
+    /// The new body, in the same forms as for add.
+    pub body: MessageBody,
+    pub signature: Option<String>,
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExtendLockRequest {
+    pub ids: Vec<String>,
+    pub extra_secs: u64,
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+    /// The lease the messages were taken under, if any.
+    pub lease_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AckMessagesRequest {
+    pub ids: Vec<String>,
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+    /// The lease the messages were taken under, if any.
+    pub lease_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NackMessagesRequest {
+    pub ids: Vec<String>,
+    /// Seconds to hold the messages back instead of the retry backoff.
+    pub delay_secs: Option<u64>,
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+}
+
+/// How `/import` treats messages already stored.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Keep existing messages and skip imported ones with the same id.
+    #[default]
+    Append,
+    /// Empty every queue before importing.
+    Replace,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportParams {
+    #[serde(default)]
+    pub mode: ImportMode,
+    /// Must match `purge_token` when one is configured and `mode` is replace.
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrainToRequest {
+    /// The server to move the messages to, e.g. `http://10.0.0.5:1337`.
+    pub url: String,
+    /// Messages sent per request, 100 when unset, at most `max_get_count`.
+    pub batch: Option<usize>,
+    /// The target's API key, if it requires one.
+    pub api_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrainResponse {
+    pub draining: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CountResponse {
+    pub count: usize,
+}
+
+/// Maps a storage failure to the HTTP error it should surface as.
+fn store_error(e: StorageError) -> ApiError {
+    match e {
+        StorageError::NotFound(_) => ApiError::NotFound(Some(e.to_string())),
+        StorageError::Conflict(_) => ApiError::Conflict(Some(e.to_string())),
+        StorageError::Backend(_) => {
+            tracing::error!("{e}");
+            ApiError::InternalServerError(Some("Internal server error".to_string()))
+        }
+        // Worth retrying, unlike the errors above; `add_retry_after` says when.
+        StorageError::Unavailable(_) => {
+            tracing::warn!("{e}");
+            ApiError::ServiceUnavailable(Some("Storage temporarily unavailable".to_string()))
+        }
+    }
+}
+
+/// Answers a well-formed request whose body the server refuses, e.g. one that
+/// fails `body_schema`, with 422.
+fn unprocessable(e: Error) -> ApiError {
+    ApiError::Other(StatusCode::UNPROCESSABLE_ENTITY.as_u16(), Some(e.to_string()))
+}
+
+/// Adds `Retry-After` to 503 responses, whether from an unavailable storage
+/// backend, a draining server or a failed readiness check.
+async fn add_retry_after(mut response: Response) -> Response {
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        response
+            .headers_mut()
+            .entry(RETRY_AFTER)
+            .or_insert(HeaderValue::from(RETRY_AFTER_SECS));
+    }
+    response
+}
+
+/// Correlates a request with the log lines, events and messages it produced.
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The id of the request being served, as stored in its extensions.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Takes the request's `X-Request-Id`, or generates one if it is missing or
+/// unusable, and echoes it in the response. While the request is handled the
+/// id is in the request extensions, on the tracing span and in `REQUEST_ID`,
+/// from where it reaches queue events and added messages.
+async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let span = tracing::info_span!("request", request_id = %id);
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+    response
+}
+
+/// The body of `/health` and `/ready`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HealthResponse {
+    /// `ok` or `unavailable`.
+    pub status: String,
+    /// The storage error, when the backend is unreachable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 pub async fn check() -> ApiResponse<String> {
     success("Hello World".to_string())
 }
 
+/// Answers 200 when the storage backend is reachable and 503 otherwise.
+pub async fn health(State(service): State<MessageService>) -> (StatusCode, Json<HealthResponse>) {
+    match service.health_check().await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(HealthResponse { status: "ok".to_string(), error: None }),
+        ),
+        Err(e) => {
+            tracing::warn!("Health check failed: {e}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(HealthResponse { status: "unavailable".to_string(), error: Some(e.to_string()) }),
+            )
+        }
+    }
+}
+
+/// Returns the server's limits so clients can adapt to them.
+pub async fn capabilities() -> ApiResponse<Capabilities> {
+    success(config().capabilities())
+}
+
 pub async fn add_message(
     State(service): State<MessageService>,
-    Json(request): Json<AddMessageRequest>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<AddMessageRequest>,
+) -> CreatedResponse<Message> {
+    let options = AddOptions {
+        delay_secs: request.delay_secs,
+        priority: request.priority.unwrap_or_default(),
+        ttl_secs: request.ttl_secs,
+        dedup_id: request.dedup_id,
+        attributes: request.attributes,
+        content_type: request.content_type,
+        id: request.id,
+        signature: request.signature,
+        max_retries: request.max_retries,
+        group_id: request.group_id,
+    };
+    let queue = queue_name(queue);
+    add(&service, &queue, request.body, options).await.and_then(|Json(message)| created(&queue, message))
+}
+
+/// Adds the raw request body as a binary message, keeping its `Content-Type`.
+pub async fn add_bytes_message(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    headers: HeaderMap,
+    body: Result<Bytes, BytesRejection>,
+) -> CreatedResponse<Message> {
+    let body = match body {
+        Ok(body) => body,
+        Err(rejection) => return Err(rejection_error(rejection.status(), rejection.body_text())),
+    };
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream");
+    let options = AddOptions {
+        content_type: Some(content_type.to_string()),
+        ..AddOptions::default()
+    };
+    let queue = queue_name(queue);
+    add(&service, &queue, body.to_vec().into(), options)
+        .await
+        .and_then(|Json(message)| created(&queue, message))
+}
+
+/// How many items there are in all, of which a response holds one page.
+pub static X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+/// Media type of newline-delimited JSON, one value per line.
+const NDJSON: &str = "application/x-ndjson";
+
+/// Returns true if the client asked for newline-delimited JSON in `Accept`.
+fn wants_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains(NDJSON))
+}
+
+/// Answers with a list of messages: a JSON array, or newline-delimited JSON
+/// streamed one message at a time when the client asked for it, so a large
+/// list is never serialized into a single buffer.
+fn messages_response(headers: &HeaderMap, messages: Vec<Message>) -> Response {
+    if !wants_ndjson(headers) {
+        return Json(messages).into_response();
+    }
+
+    let lines = stream::iter(messages).map(|message| {
+        serde_json::to_vec(&message).map(|mut line| {
+            line.push(b'\n');
+            line
+        })
+    });
+    ([(CONTENT_TYPE, NDJSON)], Body::from_stream(lines)).into_response()
+}
+
+/// A 201 Created response, with the URL of the new resource in `Location`.
+pub type CreatedResponse<T> = Result<(StatusCode, [(HeaderName, String); 1], Json<T>), ApiError>;
+
+/// Answers an add with 201 and the message's `/queues/{name}/messages/{id}` URL.
+fn created(queue: &str, message: Message) -> CreatedResponse<Message> {
+    let location = message_location(queue, &message);
+    Ok((StatusCode::CREATED, [(LOCATION, location)], Json(message)))
+}
+
+/// Returns the `/queues/{name}/messages/{id}` URL of a message.
+fn message_location(queue: &str, message: &Message) -> String {
+    format!("/queues/{queue}/messages/{}", message.id)
+}
+
+async fn add(
+    service: &MessageService,
+    queue: &str,
+    body: MessageBody,
+    options: AddOptions,
 ) -> ApiResponse<Message> {
-    match service.add(request.body).await {
+    match service.add(queue, body, options).await {
         Ok(message) => success(message),
+        Err(e) => error(add_error(e)),
+    }
+}
+
+/// Maps a failed add to the HTTP error it should surface as.
+fn add_error(e: Error) -> ApiError {
+    match e {
+        Error::BodyTooLarge { .. } | Error::BodyTooLong { .. } => {
+            ApiError::BadRequest(Some(e.to_string()))
+        }
+        Error::EmptyBody => ApiError::BadRequest(Some("Message body is empty".to_string())),
+        Error::QueueFull => ApiError::TooManyRequests(Some("Queue is full".to_string())),
+        Error::Draining => ApiError::ServiceUnavailable(Some(e.to_string())),
+        Error::InvalidIds(_) | Error::InvalidSignature | Error::InvalidMaxRetries { .. } => {
+            ApiError::BadRequest(Some(e.to_string()))
+        }
+        Error::ValidationFailed(_) => unprocessable(e),
+        Error::InvalidQueueName(name) => {
+            ApiError::BadRequest(Some(format!("Invalid queue name: {name}")))
+        }
+        Error::UploadNotFound(_) => ApiError::NotFound(Some(e.to_string())),
+        Error::Store(e) => store_error(e),
+        _ => ApiError::InternalServerError(Some("Internal server error".to_string())),
+    }
+}
+
+/// Starts a chunked upload; the body follows through `/add/chunk`.
+pub async fn begin_upload(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<BeginUploadRequest>,
+) -> ApiResponse<UploadResponse> {
+    let options = AddOptions {
+        delay_secs: request.delay_secs,
+        priority: request.priority.unwrap_or_default(),
+        ttl_secs: request.ttl_secs,
+        dedup_id: request.dedup_id,
+        attributes: request.attributes,
+        content_type: request.content_type,
+        id: request.id,
+        signature: request.signature,
+        max_retries: request.max_retries,
+        group_id: request.group_id,
+    };
+    match service.begin_upload(&queue_name(queue), options) {
+        Ok(upload_id) => success(UploadResponse { upload_id, size: 0 }),
+        Err(e) => error(add_error(e)),
+    }
+}
+
+pub async fn upload_chunk(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<UploadChunkRequest>,
+) -> ApiResponse<UploadResponse> {
+    match service.append_upload(&queue_name(queue), &request.upload_id, request.chunk) {
+        Ok(size) => success(UploadResponse { upload_id: request.upload_id, size }),
+        Err(e) => error(add_error(e)),
+    }
+}
+
+/// Adds the uploaded body as a message, answering like `/add`.
+pub async fn complete_upload(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<CompleteUploadRequest>,
+) -> CreatedResponse<Message> {
+    let queue = queue_name(queue);
+    match service.complete_upload(&queue, &request.upload_id).await {
+        Ok(message) => created(&queue, message),
+        Err(e) => Err(add_error(e)),
+    }
+}
+
+pub async fn add_batch_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    request: Result<Json<AddBatchRequest>, JsonRejection>,
+) -> ApiResponse<Vec<AddBatchItem>> {
+    let request = match request {
+        Ok(Json(request)) => request,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return error(ApiError::BadRequest(Some("Batch size is too large".to_string())));
+        }
+        Err(rejection) => return error(rejection_error(rejection.status(), rejection.body_text())),
+    };
+    let queue = queue_name(queue);
+    match service.add_batch(&queue, request.bodies).await {
+        Ok(results) => success(
+            results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(message) => AddBatchItem {
+                        location: Some(message_location(&queue, &message)),
+                        message: Some(message),
+                        error: None,
+                    },
+                    Err(e) => AddBatchItem {
+                        message: None,
+                        location: None,
+                        error: Some(e.to_string()),
+                    },
+                })
+                .collect(),
+        ),
         Err(e) => match e {
-            Error::BodyTooLarge => error(ApiError::BadRequest(Some(
-                "Message body size is too large".to_string(),
-            ))),
-            Error::Store(message) => error(ApiError::BadRequest(Some(message))),
+            Error::BatchTooLarge { .. } => error(ApiError::BadRequest(Some(e.to_string()))),
+            Error::Draining => error(ApiError::ServiceUnavailable(Some(e.to_string()))),
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
             _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
         },
     }
@@ -49,13 +663,59 @@ pub async fn add_message(
 
 pub async fn get_messages(
     State(service): State<MessageService>,
-    Json(request): Json<GetMessagesRequest>,
-) -> ApiResponse<Vec<Message>> {
-    let count = request.count.unwrap_or(1);
-    match service.get(count).await {
+    queue: Option<Path<String>>,
+    headers: HeaderMap,
+    JsonBody(request): JsonBody<GetMessagesRequest>,
+) -> Result<Response, ApiError> {
+    get_batch(&service, queue, &headers, request).await
+}
+
+/// `GET /get?count=...`: the same as `POST /get`, with the request in the query string.
+pub async fn get_messages_query(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    headers: HeaderMap,
+    Query(request): Query<GetMessagesRequest>,
+) -> Result<Response, ApiError> {
+    get_batch(&service, queue, &headers, request).await
+}
+
+async fn get_batch(
+    service: &MessageService,
+    queue: Option<Path<String>>,
+    headers: &HeaderMap,
+    request: GetMessagesRequest,
+) -> Result<Response, ApiError> {
+    let queue = consumer_queue(queue, request.group.as_deref());
+    let wait = match request.wait_ms {
+        Some(wait_ms) => Duration::from_millis(wait_ms),
+        None => Duration::from_secs(request.wait_secs.unwrap_or_default()),
+    };
+    let count = request.max_count.or(request.count);
+    let (auto_ack, lease_secs) = (request.auto_ack, request.lease_secs);
+    match service.get_batch(&queue, count, request.min_count, wait, auto_ack, lease_secs).await {
+        Ok(messages) => Ok(messages_response(headers, messages)),
+        Err(e) => Err(match e {
+            Error::InvalidQueueName(name) => {
+                ApiError::BadRequest(Some(format!("Invalid queue name: {name}")))
+            }
+            Error::Store(e) => store_error(e),
+            _ => ApiError::InternalServerError(Some("Internal server error".to_string())),
+        }),
+    }
+}
+
+pub async fn get_any_messages(
+    State(service): State<MessageService>,
+    JsonBody(request): JsonBody<GetAnyRequest>,
+) -> ApiResponse<Vec<QueuedMessage>> {
+    match service.get_any(&request.queues, request.count).await {
         Ok(messages) => success(messages),
         Err(e) => match e {
-            Error::Store(message) => error(ApiError::BadRequest(Some(message))),
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
             _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
         },
     }
@@ -63,45 +723,122 @@ pub async fn get_messages(
 
 pub async fn delete_messages(
     State(service): State<MessageService>,
-    Json(request): Json<DeleteMessagesRequest>,
-) -> ApiResponse<String> {
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<DeleteMessagesRequest>,
+) -> ApiResponse<DeleteOutcome> {
+    let queue = consumer_queue(queue, request.group.as_deref());
     let ids = request.ids;
-    match service.delete(ids).await {
-        Ok(_) => success("Success".to_string()),
+    match service.delete_leased(&queue, ids, request.lease_token.as_deref()).await {
+        Ok(outcome) => success(outcome),
         Err(e) => match e {
             Error::NoIds => error(ApiError::BadRequest(Some("No message IDs provided".to_string()))),
-            Error::InvalidId(id) => {
-                error(ApiError::BadRequest(Some(format!("Invalid message ID: {id}"))))
+            Error::InvalidIds(_) | Error::TooManyIds { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
             }
-            Error::Store(message) => error(ApiError::BadRequest(Some(message))),
+            Error::Store(e) => error(store_error(e)),
             _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
         },
     }
 }
 
-pub async fn purge_messages(State(service): State<MessageService>) -> ApiResponse<String> {
-    match service.purge().await {
-        Ok(_) => success("Success".to_string()),
+pub async fn purge_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    request: Option<JsonBody<PurgeRequest>>,
+) -> ApiResponse<CountResponse> {
+    let queue = queue_name(queue);
+    let request = request.map(|JsonBody(request)| request);
+    let token = request.as_ref().and_then(|request| request.token.as_deref());
+    if let Err(e) = check_purge_token(token) {
+        return error(e);
+    }
+    let state = request.and_then(|request| request.state);
+    match service.purge_state(&queue, state).await {
+        Ok(count) => success(CountResponse { count }),
         Err(e) => match e {
-            Error::Store(message) => error(ApiError::BadRequest(Some(message))),
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
             _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
         },
     }
 }
 
+/// Rejects a request that would empty queues unless it carries the configured
+/// `purge_token`, if there is one.
+fn check_purge_token(token: Option<&str>) -> Result<(), ApiError> {
+    let Some(expected) = &config().purge_token else {
+        return Ok(());
+    };
+    if token.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(Some("Missing or invalid purge token".to_string())))
+    }
+}
+
 pub async fn retry_messages(
     State(service): State<MessageService>,
-    Json(request): Json<RetryMessagesRequest>,
-) -> ApiResponse<String> {
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<RetryMessagesRequest>,
+) -> ApiResponse<RetryOutcome> {
+    let queue = consumer_queue(queue, request.group.as_deref());
     let ids = request.ids;
-    match service.retry(ids).await {
-        Ok(_) => success("Success".to_string()),
+    match service.retry_after(&queue, ids, request.front, request.delay_secs).await {
+        Ok(outcome) => success(outcome),
+        Err(e) => match e {
+            Error::NoIds => error(ApiError::BadRequest(Some("No message IDs provided".to_string()))),
+            Error::InvalidIds(_) | Error::TooManyIds { .. } | Error::InvalidDelay { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn requeue_all_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+) -> ApiResponse<CountResponse> {
+    let queue = queue_name(queue);
+    match service.requeue_all(&queue).await {
+        Ok(count) => success(CountResponse { count }),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn extend_lock(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<ExtendLockRequest>,
+) -> ApiResponse<CountResponse> {
+    let queue = consumer_queue(queue, request.group.as_deref());
+    let lease_token = request.lease_token.as_deref();
+    match service.extend_lock(&queue, request.ids, request.extra_secs, lease_token).await {
+        Ok(count) => success(CountResponse { count }),
         Err(e) => match e {
             Error::NoIds => error(ApiError::BadRequest(Some("No message IDs provided".to_string()))),
-            Error::InvalidId(id) => {
-                error(ApiError::BadRequest(Some(format!("Invalid message ID: {id}"))))
+            Error::InvalidIds(_) | Error::TooManyIds { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
             }
-            Error::Store(message) => error(ApiError::BadRequest(Some(message))),
+            Error::Store(e) => error(store_error(e)),
             _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
         },
     }
@@ -109,32 +846,784 @@ pub async fn retry_messages(
 
 pub async fn peek_messages(
     State(service): State<MessageService>,
-    Json(request): Json<GetMessagesRequest>,
+    queue: Option<Path<String>>,
+    headers: HeaderMap,
+    JsonBody(request): JsonBody<GetMessagesRequest>,
+) -> Result<Response, ApiError> {
+    peek_page(&service, queue, &headers, request).await
+}
+
+/// `GET /peek?count=...&offset=...`: the same as `POST /peek`, with the request
+/// in the query string.
+pub async fn peek_messages_query(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    headers: HeaderMap,
+    Query(request): Query<GetMessagesRequest>,
+) -> Result<Response, ApiError> {
+    peek_page(&service, queue, &headers, request).await
+}
+
+async fn peek_page(
+    service: &MessageService,
+    queue: Option<Path<String>>,
+    headers: &HeaderMap,
+    request: GetMessagesRequest,
+) -> Result<Response, ApiError> {
+    let queue = consumer_queue(queue, request.group.as_deref());
+    match service.peek_page(&queue, request.offset, request.count).await {
+        Ok(page) => {
+            let total = [(X_TOTAL_COUNT.clone(), page.total.to_string())];
+            Ok((total, messages_response(headers, page.messages)).into_response())
+        }
+        Err(e) => Err(match e {
+            Error::InvalidQueueName(name) => {
+                ApiError::BadRequest(Some(format!("Invalid queue name: {name}")))
+            }
+            Error::Store(e) => store_error(e),
+            _ => ApiError::InternalServerError(Some("Internal server error".to_string())),
+        }),
+    }
+}
+
+pub async fn search_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<SearchRequest>,
 ) -> ApiResponse<Vec<Message>> {
-    let count = request.count.unwrap_or(1);
-    match service.peek(count).await {
+    let queue = queue_name(queue);
+    match service.search(&queue, &request.filter, request.offset, request.limit).await {
         Ok(messages) => success(messages),
         Err(e) => match e {
-            Error::Store(message) => error(ApiError::BadRequest(Some(message))),
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
             _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
         },
     }
 }
 
+/// Serves both `/messages/{id}` and `/queues/{name}/messages/{id}`.
+pub async fn get_message(
+    State(service): State<MessageService>,
+    Path(mut params): Path<HashMap<String, String>>,
+) -> ApiResponse<Message> {
+    let queue = params.remove("name").unwrap_or_else(|| DEFAULT_QUEUE.to_string());
+    let id = params.remove("id").unwrap_or_default();
+    match service.get_by_id(&queue, id.clone()).await {
+        Ok(Some(message)) => success(message),
+        Ok(None) => error(ApiError::NotFound(Some(format!("Message not found: {id}")))),
+        Err(e) => match e {
+            Error::InvalidIds(_) | Error::TooManyIds { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn amend_message(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<AmendMessageRequest>,
+) -> ApiResponse<Message> {
+    let queue = consumer_queue(queue, request.group.as_deref());
+    let id = request.id;
+    match service.amend(&queue, id.clone(), request.body, request.signature).await {
+        Ok(Some(message)) => success(message),
+        Ok(None) => error(ApiError::NotFound(Some(format!("Message not found: {id}")))),
+        Err(e) => match e {
+            Error::BodyTooLarge { .. } | Error::BodyTooLong { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::EmptyBody => error(ApiError::BadRequest(Some("Message body is empty".to_string()))),
+            Error::InvalidSignature => error(ApiError::BadRequest(Some(e.to_string()))),
+            Error::ValidationFailed(_) => error(unprocessable(e)),
+            Error::InvalidIds(_) | Error::TooManyIds { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn ack_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<AckMessagesRequest>,
+) -> ApiResponse<DeleteOutcome> {
+    let queue = consumer_queue(queue, request.group.as_deref());
+    let ids = request.ids;
+    match service.delete_leased(&queue, ids, request.lease_token.as_deref()).await {
+        Ok(outcome) => success(outcome),
+        Err(e) => match e {
+            Error::NoIds => error(ApiError::BadRequest(Some("No message IDs provided".to_string()))),
+            Error::InvalidIds(_) | Error::TooManyIds { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn nack_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<NackMessagesRequest>,
+) -> ApiResponse<RetryOutcome> {
+    let queue = consumer_queue(queue, request.group.as_deref());
+    let ids = request.ids;
+    match service.retry_after(&queue, ids, false, request.delay_secs).await {
+        Ok(outcome) => success(outcome),
+        Err(e) => match e {
+            Error::NoIds => error(ApiError::BadRequest(Some("No message IDs provided".to_string()))),
+            Error::InvalidIds(_) | Error::TooManyIds { .. } | Error::InvalidDelay { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn dead_letter_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<GetMessagesRequest>,
+) -> ApiResponse<Vec<Message>> {
+    let queue = queue_name(queue);
+    match service.dead_letters(&queue, request.count).await {
+        Ok(messages) => success(messages),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn expired_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    Query(request): Query<GetMessagesRequest>,
+) -> ApiResponse<Vec<ExpiredMessage>> {
+    let queue = queue_name(queue);
+    match service.expired(&queue, request.count).await {
+        Ok(messages) => success(messages),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn requeue_dead_letter_messages(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<RetryMessagesRequest>,
+) -> ApiResponse<String> {
+    let queue = queue_name(queue);
+    let ids = request.ids;
+    match service.requeue_dead_letters(&queue, ids).await {
+        Ok(_) => success("Success".to_string()),
+        Err(e) => match e {
+            Error::NoIds => error(ApiError::BadRequest(Some("No message IDs provided".to_string()))),
+            Error::InvalidIds(_) | Error::TooManyIds { .. } => {
+                error(ApiError::BadRequest(Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn queue_stats(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+) -> ApiResponse<QueueStats> {
+    let queue = queue_name(queue);
+    match service.stats(&queue).await {
+        Ok(stats) => success(stats),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+/// Stops accepting new messages on every queue until `/resume`.
+pub async fn drain(State(service): State<MessageService>) -> ApiResponse<DrainResponse> {
+    service.drain();
+    tracing::info!("Draining: no longer accepting new messages");
+    success(DrainResponse { draining: true })
+}
+
+pub async fn resume(State(service): State<MessageService>) -> ApiResponse<DrainResponse> {
+    service.resume();
+    tracing::info!("Resumed accepting new messages");
+    success(DrainResponse { draining: false })
+}
+
+/// Streams queue events as server-sent events.
+pub async fn events(
+    State(service): State<MessageService>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = stream::unfold(service.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let sse_event = Event::default().event(event.kind.as_str()).json_data(&event);
+                    return Some((sse_event, receiver));
+                }
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct WebSocketParams {
+    /// Consumer group to act for; see `/groups`.
+    pub group: Option<String>,
+}
+
+/// Upgrades to a WebSocket that pushes the queue's messages as they arrive;
+/// see `websocket` for the frames exchanged.
+pub async fn websocket(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    Query(params): Query<WebSocketParams>,
+    mut request: Request,
+) -> Response {
+    let headers = request.headers();
+    if !has_token(headers, &UPGRADE, "websocket") || !has_token(headers, &CONNECTION, "upgrade") {
+        let message = "Expected a WebSocket upgrade".to_string();
+        return error::<()>(ApiError::BadRequest(Some(message))).into_response();
+    }
+    if headers.get(SEC_WEBSOCKET_VERSION).is_none_or(|version| version != "13") {
+        let message = "Only WebSocket version 13 is supported".to_string();
+        let status = StatusCode::UPGRADE_REQUIRED.as_u16();
+        let body = error::<()>(ApiError::Other(status, Some(message)));
+        return ([(SEC_WEBSOCKET_VERSION, "13")], body).into_response();
+    }
+    let Some(key) = headers.get(SEC_WEBSOCKET_KEY).and_then(|key| key.to_str().ok()) else {
+        let message = "Missing Sec-WebSocket-Key".to_string();
+        return error::<()>(ApiError::BadRequest(Some(message))).into_response();
+    };
+    let accept = crate::websocket::accept_key(key);
+
+    let queue = consumer_queue(queue, params.group.as_deref());
+    if let Err(Error::InvalidQueueName(name)) = MessageService::validate_queue(&queue) {
+        let message = format!("Invalid queue name: {name}");
+        return error::<()>(ApiError::BadRequest(Some(message))).into_response();
+    }
+
+    let upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => crate::websocket::serve(service, queue, TokioIo::new(upgraded)).await,
+            Err(e) => tracing::error!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    let headers = [
+        (UPGRADE, "websocket".to_string()),
+        (CONNECTION, "Upgrade".to_string()),
+        (SEC_WEBSOCKET_ACCEPT, accept),
+    ];
+    (StatusCode::SWITCHING_PROTOCOLS, headers).into_response()
+}
+
+/// Whether a comma-separated header lists `token`, ignoring case.
+fn has_token(headers: &HeaderMap, name: &HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+pub async fn export_messages(State(service): State<MessageService>) -> ApiResponse<Vec<QueueExport>> {
+    match service.export().await {
+        Ok(queues) => success(queues),
+        Err(e) => match e {
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn import_messages(
+    State(service): State<MessageService>,
+    Query(params): Query<ImportParams>,
+    JsonBody(queues): JsonBody<Vec<QueueExport>>,
+) -> ApiResponse<CountResponse> {
+    let replace = params.mode == ImportMode::Replace;
+    if replace {
+        if let Err(e) = check_purge_token(params.token.as_deref()) {
+            return error(e);
+        }
+    }
+    match service.import(queues, replace).await {
+        Ok(count) => success(CountResponse { count }),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+/// Moves the queue's ready messages to another server, answering how many
+/// were moved, or 502 if the target failed part way.
+pub async fn drain_to(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<DrainToRequest>,
+) -> ApiResponse<DrainReport> {
+    let queue = queue_name(queue);
+    let api_key = request.api_key.as_deref();
+    match service.drain_to(&queue, &request.url, request.batch, api_key).await {
+        Ok(report) => success(report),
+        Err(e) => match e {
+            Error::InvalidUrl(_) => error(ApiError::BadRequest(Some(e.to_string()))),
+            Error::DrainStopped { .. } => {
+                error(ApiError::Other(StatusCode::BAD_GATEWAY.as_u16(), Some(e.to_string())))
+            }
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn list_queues(State(service): State<MessageService>) -> ApiResponse<Vec<String>> {
+    match service.list_queues().await {
+        Ok(queues) => success(queues),
+        Err(e) => match e {
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn list_groups(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+) -> ApiResponse<GroupsResponse> {
+    let queue = queue_name(queue);
+    match service.groups(&queue).await {
+        Ok(groups) => success(GroupsResponse { groups }),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn register_group(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<GroupRequest>,
+) -> ApiResponse<GroupsResponse> {
+    let queue = queue_name(queue);
+    match service.register_group(&queue, &request.group).await {
+        Ok(groups) => success(GroupsResponse { groups }),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+pub async fn unregister_group(
+    State(service): State<MessageService>,
+    queue: Option<Path<String>>,
+    JsonBody(request): JsonBody<GroupRequest>,
+) -> ApiResponse<CountResponse> {
+    let queue = queue_name(queue);
+    match service.unregister_group(&queue, &request.group).await {
+        Ok(count) => success(CountResponse { count }),
+        Err(e) => match e {
+            Error::InvalidQueueName(name) => {
+                error(ApiError::BadRequest(Some(format!("Invalid queue name: {name}"))))
+            }
+            Error::Store(e) => error(store_error(e)),
+            _ => error(ApiError::InternalServerError(Some("Internal server error".to_string()))),
+        },
+    }
+}
+
+/// Builds the CORS layer from the config, allowing anything that isn't restricted.
+/// Entries were checked by `Config::validate`; any that still fail to parse are skipped.
+fn cors_layer() -> CorsLayer {
+    let config = config();
+    let origins = match &config.cors_origins {
+        Some(origins) if !origins.iter().any(|origin| origin == "*") => {
+            AllowOrigin::list(origins.iter().filter_map(|origin| origin.parse().ok()))
+        }
+        _ => AllowOrigin::from(Any),
+    };
+    let methods = match &config.cors_methods {
+        Some(methods) if !methods.iter().any(|method| method == "*") => AllowMethods::list(
+            methods.iter().filter_map(|method| method.to_uppercase().parse().ok()),
+        ),
+        _ => AllowMethods::from(Any),
+    };
+    let headers = match &config.cors_headers {
+        Some(headers) if !headers.iter().any(|header| header == "*") => {
+            AllowHeaders::list(headers.iter().filter_map(|header| header.parse().ok()))
+        }
+        _ => AllowHeaders::from(Any),
+    };
+
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(config.cors_allow_credentials)
+        .expose_headers([LOCATION, RETRY_AFTER, X_REQUEST_ID.clone(), X_TOTAL_COUNT.clone()])
+}
+
 pub fn create_api(service: MessageService) -> Router {
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    // Only the add routes are limited; reads, acks and health checks are not.
+    let rate_limit = RateLimitLayer::new(config().rate_limit, config().queue_rate_limit);
 
-    Router::new()
-        .route("/hello", get(check))
-        .route("/add", post(add_message))
-        .route("/get", post(get_messages))
+    let mut router = Router::new()
+        .route("/add", post(add_message).layer(rate_limit.clone()))
+        .route("/add_bytes", post(add_bytes_message).layer(rate_limit.clone()))
+        .route("/add/init", post(begin_upload).layer(rate_limit.clone()))
+        .route("/add/chunk", post(upload_chunk))
+        .route("/add/complete", post(complete_upload))
+        .route(
+            "/add_batch",
+            post(add_batch_messages)
+                .layer((rate_limit.clone(), DefaultBodyLimit::max(batch_body_limit()))),
+        )
+        .route("/get", get(get_messages_query).post(get_messages))
+        .route("/get_any", post(get_any_messages))
         .route("/delete", post(delete_messages))
         .route("/purge", post(purge_messages))
         .route("/retry", post(retry_messages))
-        .route("/peek", post(peek_messages))
+        .route("/peek", get(peek_messages_query).post(peek_messages))
+        .route("/search", post(search_messages))
+        .route("/extend", post(extend_lock))
+        .route("/amend", post(amend_message))
+        .route("/requeue_all", post(requeue_all_messages))
+        .route("/ack", post(ack_messages))
+        .route("/nack", post(nack_messages))
+        .route("/dlq", post(dead_letter_messages))
+        .route("/dlq/requeue", post(requeue_dead_letter_messages))
+        .route("/expired", get(expired_messages))
+        .route("/groups", get(list_groups).post(register_group))
+        .route("/groups/remove", post(unregister_group))
+        .route("/stats", get(queue_stats))
+        .route("/events", get(events))
+        .route("/ws", get(websocket))
+        .route("/messages/{id}", get(get_message))
+        .route("/queues", get(list_queues))
+        .route("/config", get(capabilities))
+        .route("/drain", post(drain))
+        .route("/resume", post(resume))
+        .route("/drain_to", post(drain_to))
+        .route("/export", get(export_messages))
+        // A backup can be far larger than any single message.
+        .route("/import", post(import_messages).layer(DefaultBodyLimit::disable()))
+        .route("/queues/{name}/stats", get(queue_stats))
+        .route("/queues/{name}/drain_to", post(drain_to))
+        .route("/queues/{name}/add", post(add_message).layer(rate_limit.clone()))
+        .route("/queues/{name}/add_bytes", post(add_bytes_message).layer(rate_limit.clone()))
+        .route("/queues/{name}/add/init", post(begin_upload).layer(rate_limit.clone()))
+        .route("/queues/{name}/add/chunk", post(upload_chunk))
+        .route("/queues/{name}/add/complete", post(complete_upload))
+        .route(
+            "/queues/{name}/add_batch",
+            post(add_batch_messages)
+                .layer((rate_limit, DefaultBodyLimit::max(batch_body_limit()))),
+        )
+        .route("/queues/{name}/get", get(get_messages_query).post(get_messages))
+        .route("/queues/{name}/delete", post(delete_messages))
+        .route("/queues/{name}/purge", post(purge_messages))
+        .route("/queues/{name}/retry", post(retry_messages))
+        .route("/queues/{name}/peek", get(peek_messages_query).post(peek_messages))
+        .route("/queues/{name}/search", post(search_messages))
+        .route("/queues/{name}/extend", post(extend_lock))
+        .route("/queues/{name}/amend", post(amend_message))
+        .route("/queues/{name}/requeue_all", post(requeue_all_messages))
+        .route("/queues/{name}/messages/{id}", get(get_message))
+        .route("/queues/{name}/ack", post(ack_messages))
+        .route("/queues/{name}/nack", post(nack_messages))
+        .route("/queues/{name}/dlq", post(dead_letter_messages))
+        .route("/queues/{name}/dlq/requeue", post(requeue_dead_letter_messages))
+        .route("/queues/{name}/expired", get(expired_messages))
+        .route("/queues/{name}/groups", get(list_groups).post(register_group))
+        .route("/queues/{name}/groups/remove", post(unregister_group))
+        .route("/queues/{name}/ws", get(websocket));
+
+    if let Some(api_key) = &config().api_key {
+        router = router.layer(ApiKeyLayer::new(api_key.as_str()));
+    }
+
+    // Added after the auth layer so health checks stay unauthenticated.
+    let router = router
+        .route("/hello", get(check))
+        .route("/health", get(health))
+        .route("/ready", get(health))
         .with_state(service)
-        .layer(cors)
-}
\ No newline at end of file
+        .layer(map_response(add_retry_after))
+        .layer(from_fn(request_id))
+        .layer(DefaultBodyLimit::max(request_body_limit()));
+
+    if config().cors {
+        router.layer(cors_layer())
+    } else {
+        router
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::Request;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn ndjson_has_one_message_per_line() {
+        let messages = vec![Message::new("a\nb"), Message::new(r#"{"n": 1}"#)];
+        let headers = HeaderMap::from_iter([(ACCEPT, HeaderValue::from_static(NDJSON))]);
+        let response = messages_response(&headers, messages.clone());
+        assert_eq!(response.headers()[CONTENT_TYPE], NDJSON);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.ends_with('\n'));
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), messages.len());
+        for (line, message) in lines.iter().zip(&messages) {
+            let parsed: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(parsed["id"], message.id.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn replacing_import_needs_the_purge_token() {
+        crate::set_test_config(Config { purge_token: Some("secret".into()), ..Config::default() });
+        let service = MessageService::new(Arc::new(MemoryStorage::new()));
+        service.add("jobs", "kept".into(), AddOptions::default()).await.unwrap();
+        let import = |mode, token: Option<&str>| {
+            let params = ImportParams { mode, token: token.map(str::to_string) };
+            import_messages(State(service.clone()), Query(params), JsonBody(Vec::new()))
+        };
+
+        for token in [None, Some("wrong")] {
+            let rejected = import(ImportMode::Replace, token).await;
+            assert!(matches!(rejected, Err(ApiError::Forbidden(_))));
+        }
+        assert_eq!(service.stats("jobs").await.unwrap().ready_count, 1);
+
+        assert!(import(ImportMode::Append, None).await.is_ok());
+        assert!(import(ImportMode::Replace, Some("secret")).await.is_ok());
+        assert_eq!(service.stats("jobs").await.unwrap().ready_count, 0);
+    }
+
+    #[tokio::test]
+    async fn add_bytes_round_trips_binary_bodies() {
+        let app = create_api(MessageService::new(Arc::new(MemoryStorage::new())));
+        // Not valid UTF-8, so it can only come back as base64.
+        let bytes = vec![0x00, 0xff, 0xfe, 0x80, b'\n', 0x7f];
+        let request = Request::post("/add_bytes")
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(bytes.clone()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let request = Request::post("/get")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(messages[0]["body"], serde_json::json!({"base64": "AP/+gAp/"}));
+        assert_eq!(messages[0]["content_type"], "application/octet-stream");
+
+        let message: Message = serde_json::from_value(messages[0].clone()).unwrap();
+        assert_eq!(message.body, MessageBody::Binary { base64: bytes });
+    }
+
+    #[tokio::test]
+    async fn add_rejects_bodies_failing_validation_with_422() {
+        let body_schema = Some(serde_json::json!({"required": ["order"]}));
+        crate::set_test_config(Config { body_schema, ..Config::default() });
+        let service = MessageService::new(Arc::new(MemoryStorage::new()));
+        let app = create_api(service.clone());
+        let add = |body: &str| {
+            let body = serde_json::json!({ "body": body }).to_string();
+            let request = Request::post("/add")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = add(r#"{"item": 1}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"/: missing required property "order""#), "{body}");
+
+        let result = service.add("jobs", "not json".into(), AddOptions::default()).await;
+        assert!(matches!(result, Err(Error::ValidationFailed(_))), "{result:?}");
+        assert_eq!(service.stats("jobs").await.unwrap().ready_count, 0);
+
+        let response = add(r#"{"order": 1}"#).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    /// Serves the API on a free local port, returning its address.
+    async fn serve_api(service: MessageService) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = create_api(service);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    /// Opens a WebSocket on a served app, returning the handshake response head
+    /// and the two halves of the connection.
+    async fn open_websocket(
+        addr: std::net::SocketAddr,
+        path: &str,
+        version: &str,
+    ) -> (String, tokio::net::tcp::OwnedReadHalf, tokio::net::tcp::OwnedWriteHalf) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\n\
+             Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: {version}\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        // Byte by byte, so no frame after the head is consumed.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let (read, write) = stream.into_split();
+        (String::from_utf8(head).unwrap(), read, write)
+    }
+
+    #[tokio::test]
+    async fn websocket_pushes_messages_and_requeues_them_on_disconnect() {
+        use crate::websocket::{Frame, Reader};
+        use tokio::io::AsyncWriteExt;
+
+        let service = MessageService::new(Arc::new(MemoryStorage::new()));
+        let addr = serve_api(service.clone()).await;
+
+        let (head, read, mut write) = open_websocket(addr, "/queues/jobs/ws", "13").await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{head}");
+        assert!(head.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{head}");
+        let mut frames = Reader::new(read, false);
+        let mut send = async |text: &str| {
+            let frame = Frame::Text(text.to_string()).encode(Some([1, 2, 3, 4]));
+            write.write_all(&frame).await.unwrap();
+        };
+        send(r#"{"batch": 2}"#).await;
+
+        for body in ["a", "b", "c"] {
+            service.add("jobs", body.into(), AddOptions::default()).await.unwrap();
+        }
+        let mut delivered = Vec::new();
+        while delivered.len() < 2 {
+            let Frame::Text(text) = frames.next().await.unwrap() else { panic!("not text") };
+            let reply: serde_json::Value = serde_json::from_str(&text).unwrap();
+            delivered.extend(reply["messages"].as_array().unwrap().iter().cloned());
+        }
+        assert_eq!(delivered.len(), 2);
+        let more = tokio::time::timeout(Duration::from_millis(200), frames.next()).await;
+        assert!(more.is_err(), "more than the batch was pushed: {more:?}");
+
+        let first = delivered[0]["id"].as_str().unwrap().to_string();
+        send(&serde_json::json!({ "ack": [first] }).to_string()).await;
+        let Frame::Text(text) = frames.next().await.unwrap() else { panic!("not text") };
+        let reply: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(reply["acked"]["acked"], serde_json::json!([first]));
+        let Frame::Text(text) = frames.next().await.unwrap() else { panic!("not text") };
+        let reply: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(reply["messages"][0]["body"], "c");
+
+        drop((frames, write));
+        for _ in 0..100 {
+            let stats = service.stats("jobs").await.unwrap();
+            if stats.ready_count == 2 && stats.processing_count == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("unacknowledged messages were not requeued");
+    }
+
+    #[tokio::test]
+    async fn websocket_refuses_other_versions_and_plain_requests() {
+        let service = MessageService::new(Arc::new(MemoryStorage::new()));
+        let addr = serve_api(service.clone()).await;
+
+        let (head, _, _) = open_websocket(addr, "/ws", "8").await;
+        assert!(head.starts_with("HTTP/1.1 426"), "{head}");
+        assert!(head.to_ascii_lowercase().contains("sec-websocket-version: 13"), "{head}");
+        let (head, _, _) = open_websocket(addr, "/queues/bad%20name/ws", "13").await;
+        assert!(head.starts_with("HTTP/1.1 400"), "{head}");
+
+        let request = Request::get("/ws").body(Body::empty()).unwrap();
+        let response = create_api(service).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
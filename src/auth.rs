@@ -0,0 +1,115 @@
+use axum::extract::Request;
+use axum::http::header::AUTHORIZATION;
+use axum::response::{IntoResponse, Response};
+use skyak_axum_core::errors::ApiError;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// A `tower::Layer` that rejects requests without a matching API key.
+///
+/// Requests must carry an `Authorization: Bearer <key>` header. Anything else
+/// is answered with 401 before reaching the wrapped service.
+#[derive(Clone)]
+pub struct ApiKeyLayer {
+    key: Arc<str>,
+}
+
+impl ApiKeyLayer {
+    pub fn new(key: impl Into<Arc<str>>) -> Self {
+        Self { key: key.into() }
+    }
+}
+
+impl<S> Layer<S> for ApiKeyLayer {
+    type Service = ApiKeyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ApiKeyService {
+            inner,
+            key: self.key.clone(),
+        }
+    }
+}
+
+/// The service produced by `ApiKeyLayer`.
+#[derive(Clone)]
+pub struct ApiKeyService<S> {
+    inner: S,
+    key: Arc<str>,
+}
+
+impl<S> ApiKeyService<S> {
+    fn is_authorized(&self, request: &Request) -> bool {
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|key| constant_time_eq(key.as_bytes(), self.key.as_bytes()))
+    }
+}
+
+impl<S> Service<Request> for ApiKeyService<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if self.is_authorized(&request) {
+            Box::pin(self.inner.call(request))
+        } else {
+            let response =
+                ApiError::Unauthorized(Some("Missing or invalid API key".to_string())).into_response();
+            Box::pin(async move { Ok(response) })
+        }
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    async fn status(authorization: Option<&str>) -> StatusCode {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(ApiKeyLayer::new("secret"));
+        let mut request = Request::builder().uri("/");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_api_key() {
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer wrong")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("secret")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer secret")).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,135 @@
+//! A synchronous wrapper around `SmqlClient` for callers without an async
+//! runtime, such as scripts and simple glue code. Enabled by the `blocking`
+//! feature.
+
+use crate::client::{ClientError, SmqlClient};
+use crate::{
+    AddOptions, DeleteOutcome, Message, MessageBody, QueueStats, RetryOutcome, DEFAULT_QUEUE,
+};
+use std::future::Future;
+use tokio::runtime::{Builder, Runtime};
+
+/// Runs `SmqlClient` calls to completion on a runtime of its own.
+///
+/// Calls act on the default queue unless the `_in` variant is used. Must not
+/// be used from within an async context, where blocking would stall the
+/// caller's runtime; use `SmqlClient` there instead.
+pub struct Client {
+    runtime: Runtime,
+    client: SmqlClient,
+}
+
+impl Client {
+    /// Creates a client for the server at `url`, such as `http://10.0.0.5:1337`.
+    pub fn new(url: &str) -> Result<Self, ClientError> {
+        Self::with_client(SmqlClient::new(url)?)
+    }
+
+    /// Wraps an already configured async client, e.g. one with an API key.
+    pub fn with_client(client: SmqlClient) -> Result<Self, ClientError> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        Ok(Self { runtime, client })
+    }
+
+    /// Returns the wrapped async client.
+    pub fn client(&self) -> &SmqlClient {
+        &self.client
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    pub fn add(&self, body: impl Into<MessageBody>) -> Result<Message, ClientError> {
+        self.add_in(DEFAULT_QUEUE, body, AddOptions::default())
+    }
+
+    pub fn add_in(
+        &self,
+        queue: &str,
+        body: impl Into<MessageBody>,
+        options: AddOptions,
+    ) -> Result<Message, ClientError> {
+        self.block_on(self.client.add(queue, body, options))
+    }
+
+    /// Takes up to `count` messages, waiting up to `wait_secs` for one to arrive.
+    pub fn get(&self, count: Option<usize>, wait_secs: u64) -> Result<Vec<Message>, ClientError> {
+        self.get_in(DEFAULT_QUEUE, count, wait_secs)
+    }
+
+    pub fn get_in(
+        &self,
+        queue: &str,
+        count: Option<usize>,
+        wait_secs: u64,
+    ) -> Result<Vec<Message>, ClientError> {
+        self.block_on(self.client.get(queue, count, wait_secs))
+    }
+
+    pub fn peek(&self, count: Option<usize>) -> Result<Vec<Message>, ClientError> {
+        self.peek_in(DEFAULT_QUEUE, count)
+    }
+
+    pub fn peek_in(&self, queue: &str, count: Option<usize>) -> Result<Vec<Message>, ClientError> {
+        self.block_on(self.client.peek(queue, count))
+    }
+
+    pub fn delete(&self, ids: Vec<String>) -> Result<DeleteOutcome, ClientError> {
+        self.delete_in(DEFAULT_QUEUE, ids)
+    }
+
+    pub fn delete_in(&self, queue: &str, ids: Vec<String>) -> Result<DeleteOutcome, ClientError> {
+        self.block_on(self.client.delete(queue, ids))
+    }
+
+    pub fn retry(&self, ids: Vec<String>) -> Result<RetryOutcome, ClientError> {
+        self.retry_in(DEFAULT_QUEUE, ids)
+    }
+
+    pub fn retry_in(&self, queue: &str, ids: Vec<String>) -> Result<RetryOutcome, ClientError> {
+        self.block_on(self.client.retry(queue, ids))
+    }
+
+    pub fn purge(&self, token: Option<&str>) -> Result<usize, ClientError> {
+        self.purge_in(DEFAULT_QUEUE, token)
+    }
+
+    pub fn purge_in(&self, queue: &str, token: Option<&str>) -> Result<usize, ClientError> {
+        self.block_on(self.client.purge(queue, token))
+    }
+
+    pub fn stats(&self) -> Result<QueueStats, ClientError> {
+        self.stats_in(DEFAULT_QUEUE)
+    }
+
+    pub fn stats_in(&self, queue: &str) -> Result<QueueStats, ClientError> {
+        self.block_on(self.client.stats(queue))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_api;
+    use crate::storage::MemoryStorage;
+    use crate::MessageService;
+    use std::sync::Arc;
+
+    #[test]
+    fn calls_a_server_without_an_async_runtime() {
+        // The server gets a runtime of its own, as it would in another process.
+        let server = Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        let listener = server.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = create_api(MessageService::new(Arc::new(MemoryStorage::new())));
+        server.spawn(async move { axum::serve(listener, app).await });
+
+        let client = Client::new(&url).unwrap();
+        let added = client.add("hello").unwrap();
+        let taken = client.get(None, 0).unwrap();
+        assert_eq!(taken[0].id, added.id);
+        assert_eq!(client.delete(vec![added.id.to_string()]).unwrap().count, 1);
+        assert_eq!(client.stats().unwrap().ready_count, 0);
+    }
+}
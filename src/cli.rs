@@ -0,0 +1,131 @@
+//! One-shot commands that talk to a running server, for quick ops tasks
+//! without curl. Run without a command, the binary serves as usual.
+
+use serde_json::json;
+use smql::{client, config};
+
+const USAGE: &str = "\
+usage: smql [command] [options]
+
+runs the server when no command is given.
+
+commands:
+  add <body>     add a text message
+  peek           show the next ready messages without taking them
+  stats          show a summary of the queue
+  help           show this message
+
+options:
+  --queue <name>   act on a named queue instead of the default one
+  --count <n>      how many messages peek shows (default 1)
+  --url <url>      server to talk to, e.g. http://10.0.0.5:1337
+                   (default SMQL_URL, or this host on SMQL_PORT)";
+
+enum Command {
+    Add { body: String },
+    Peek { count: Option<usize> },
+    Stats,
+    Help,
+}
+
+/// A parsed command line naming a command.
+pub struct Invocation {
+    command: Command,
+    queue: Option<String>,
+    url: Option<String>,
+}
+
+/// Parses the arguments after the program name. Options may come before or
+/// after the command. Returns `None` when there are no arguments at all,
+/// meaning the server should run.
+pub fn parse(args: Vec<String>) -> Result<Option<Invocation>, String> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    let (mut queue, mut url, mut count) = (None, None, None);
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().ok_or_else(|| format!("{flag} needs a value"));
+        match arg.as_str() {
+            "--queue" => queue = Some(value("--queue")?),
+            "--url" => url = Some(value("--url")?),
+            "--count" => {
+                let given = value("--count")?;
+                count = Some(given.parse().map_err(|_| format!("invalid --count: {given}"))?);
+            }
+            "--help" | "-h" => positional.insert(0, "help".to_string()),
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {flag}")),
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.is_empty() {
+        return Err("no command given".to_string());
+    }
+    let name = positional.remove(0);
+    let command = match (name.as_str(), positional.as_slice()) {
+        ("add", [body]) => Command::Add { body: body.clone() },
+        ("add", _) => return Err("add takes exactly one body".to_string()),
+        ("peek", []) => Command::Peek { count },
+        ("stats", []) => Command::Stats,
+        ("help", _) => Command::Help,
+        ("peek" | "stats", _) => return Err(format!("{name} takes no arguments")),
+        _ => return Err(format!("unknown command: {name}")),
+    };
+    Ok(Some(Invocation { command, queue, url }))
+}
+
+/// Prints the usage after a command line error.
+pub fn usage_error(message: &str) {
+    eprintln!("smql: {message}\n\n{USAGE}");
+}
+
+/// Runs the command against the server, printing the response. Returns the
+/// process exit code.
+pub async fn run(invocation: Invocation) -> i32 {
+    let path = |route: &str| match &invocation.queue {
+        Some(queue) => format!("/queues/{queue}/{route}"),
+        None => format!("/{route}"),
+    };
+    let (method, path, body) = match &invocation.command {
+        Command::Add { body } => ("POST", path("add"), Some(json!({ "body": body }))),
+        Command::Peek { count } => ("POST", path("peek"), Some(json!({ "count": count }))),
+        Command::Stats => ("GET", path("stats"), None),
+        Command::Help => {
+            println!("{USAGE}");
+            return 0;
+        }
+    };
+
+    let address = match server_address(invocation.url.as_deref()) {
+        Ok(address) => address,
+        Err(e) => {
+            usage_error(&e);
+            return 2;
+        }
+    };
+    match client::request(&address, method, &path, body, config().api_key.as_deref()).await {
+        Ok((status, body)) if (200..300).contains(&status) => {
+            println!("{}", body.trim_end());
+            0
+        }
+        Ok((status, body)) => {
+            eprintln!("smql: server answered {status}: {}", body.trim_end());
+            1
+        }
+        Err(e) => {
+            eprintln!("smql: can't reach the server at {address}: {e}");
+            1
+        }
+    }
+}
+
+/// Returns the `host:port` to connect to.
+fn server_address(url: Option<&str>) -> Result<String, String> {
+    match url.map(str::to_string).or_else(|| std::env::var("SMQL_URL").ok()) {
+        Some(url) => client::server_address(&url),
+        None => Ok(format!("127.0.0.1:{}", config().port)),
+    }
+}
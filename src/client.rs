@@ -0,0 +1,64 @@
+//! A minimal HTTP client for talking to another SMQL server, used by the
+//! command line and by `/drain_to`. Only plain HTTP is spoken. The `client`
+//! feature adds `SmqlClient`, a typed client built on it.
+
+use serde_json::Value;
+use std::fmt::Write as _;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[cfg(feature = "client")]
+mod typed;
+
+#[cfg(feature = "client")]
+pub use typed::{ClientError, SmqlClient};
+
+/// Returns the `host:port` a server URL such as `http://10.0.0.5:1337` points
+/// at. The scheme may be left out.
+pub fn server_address(url: &str) -> Result<String, String> {
+    if url.starts_with("https://") {
+        return Err(format!("https is not supported, use a plain http url: {url}"));
+    }
+    let address = url.strip_prefix("http://").unwrap_or(url).trim_end_matches('/');
+    if address.is_empty() || address.contains('/') {
+        return Err(format!("invalid server url: {url}"));
+    }
+    Ok(address.to_string())
+}
+
+/// Makes one HTTP/1.0 request, so the response is never chunked and ends when
+/// the server closes the connection. Returns the status and the body.
+pub async fn request(
+    address: &str,
+    method: &str,
+    path: &str,
+    body: Option<Value>,
+    api_key: Option<&str>,
+) -> io::Result<(u16, String)> {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let mut head = format!("{method} {path} HTTP/1.0\r\nHost: {address}\r\n");
+    if !body.is_empty() {
+        let _ = write!(head, "Content-Type: application/json\r\nContent-Length: {}\r\n", body.len());
+    }
+    if let Some(api_key) = api_key {
+        let _ = write!(head, "Authorization: Bearer {api_key}\r\n");
+    }
+    head.push_str("\r\n");
+
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let response = String::from_utf8_lossy(&response);
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response");
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, body.to_string()))
+}
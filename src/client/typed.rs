@@ -0,0 +1,232 @@
+//! A typed client for the HTTP API, for Rust services that talk to a remote
+//! SMQL server instead of embedding `MessageService`. Enabled by the `client`
+//! feature.
+
+use super::{request, server_address};
+use crate::api::{
+    AddMessageRequest, CountResponse, DeleteMessagesRequest, GetMessagesRequest, PurgeRequest,
+    RetryMessagesRequest,
+};
+use crate::{AddOptions, DeleteOutcome, Message, MessageBody, QueueStats, RetryOutcome};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::io;
+
+/// Why a call to the server failed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server URL given to `SmqlClient::new` can't be used.
+    InvalidUrl(String),
+    /// The server couldn't be reached, or the connection failed part way.
+    Io(io::Error),
+    /// The server answered with an error status. `message` is the body it sent.
+    Status { status: u16, message: String },
+    /// The server answered with a body that isn't the expected JSON.
+    Decode(serde_json::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(reason) => write!(f, "{reason}"),
+            ClientError::Io(e) => write!(f, "Can't reach the server: {e}"),
+            ClientError::Status { status, message } => {
+                write!(f, "Server answered {status}: {message}")
+            }
+            ClientError::Decode(e) => write!(f, "Unexpected response from the server: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(e) => Some(e),
+            ClientError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        ClientError::Decode(e)
+    }
+}
+
+/// Calls the `/queues/{name}/...` routes of a server, one request per call.
+#[derive(Debug, Clone)]
+pub struct SmqlClient {
+    address: String,
+    api_key: Option<String>,
+}
+
+impl SmqlClient {
+    /// Creates a client for the server at `url`, such as `http://10.0.0.5:1337`.
+    pub fn new(url: &str) -> Result<Self, ClientError> {
+        let address = server_address(url).map_err(ClientError::InvalidUrl)?;
+        Ok(Self { address, api_key: None })
+    }
+
+    /// Sends `api_key` as a bearer token, for servers with `SMQL_API_KEY` set.
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub async fn add(
+        &self,
+        queue: &str,
+        body: impl Into<MessageBody>,
+        options: AddOptions,
+    ) -> Result<Message, ClientError> {
+        let request = AddMessageRequest {
+            body: body.into(),
+            id: options.id,
+            content_type: options.content_type,
+            delay_secs: options.delay_secs,
+            priority: Some(options.priority),
+            ttl_secs: options.ttl_secs,
+            dedup_id: options.dedup_id,
+            attributes: options.attributes,
+            signature: options.signature,
+            max_retries: options.max_retries,
+            group_id: options.group_id,
+        };
+        self.call("POST", queue, "add", Some(request)).await
+    }
+
+    /// Takes up to `count` messages, waiting up to `wait_secs` for one to arrive.
+    pub async fn get(
+        &self,
+        queue: &str,
+        count: Option<usize>,
+        wait_secs: u64,
+    ) -> Result<Vec<Message>, ClientError> {
+        let request = GetMessagesRequest { wait_secs: Some(wait_secs), ..page_request(count) };
+        self.call("POST", queue, "get", Some(request)).await
+    }
+
+    /// Acknowledges processing messages, removing them from the queue.
+    pub async fn delete(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+    ) -> Result<DeleteOutcome, ClientError> {
+        let request = DeleteMessagesRequest { ids, group: None, lease_token: None };
+        self.call("POST", queue, "delete", Some(request)).await
+    }
+
+    /// Returns processing messages to the queue after the retry backoff.
+    pub async fn retry(&self, queue: &str, ids: Vec<String>) -> Result<RetryOutcome, ClientError> {
+        let request = RetryMessagesRequest { ids, front: false, delay_secs: None, group: None };
+        self.call("POST", queue, "retry", Some(request)).await
+    }
+
+    /// Returns up to `count` ready messages in delivery order without taking them.
+    pub async fn peek(
+        &self,
+        queue: &str,
+        count: Option<usize>,
+    ) -> Result<Vec<Message>, ClientError> {
+        self.call("POST", queue, "peek", Some(page_request(count))).await
+    }
+
+    /// Removes every message in the queue, returning how many were removed.
+    /// `token` must match the server's `SMQL_PURGE_TOKEN` when it has one.
+    pub async fn purge(&self, queue: &str, token: Option<&str>) -> Result<usize, ClientError> {
+        let request = PurgeRequest { state: None, token: token.map(str::to_string) };
+        let response: CountResponse = self.call("POST", queue, "purge", Some(request)).await?;
+        Ok(response.count)
+    }
+
+    pub async fn stats(&self, queue: &str) -> Result<QueueStats, ClientError> {
+        self.call("GET", queue, "stats", None::<()>).await
+    }
+
+    /// Sends `body` to the queue's `route` and decodes the answer as `T`.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        queue: &str,
+        route: &str,
+        body: Option<impl Serialize>,
+    ) -> Result<T, ClientError> {
+        let path = format!("/queues/{queue}/{route}");
+        let body = body.map(serde_json::to_value).transpose()?;
+        let (status, body) =
+            request(&self.address, method, &path, body, self.api_key.as_deref()).await?;
+        if !(200..300).contains(&status) {
+            return Err(ClientError::Status { status, message: body.trim_end().to_string() });
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
+}
+
+/// A get or peek of up to `count` messages, with every other setting left to the server.
+fn page_request(count: Option<usize>) -> GetMessagesRequest {
+    GetMessagesRequest {
+        count,
+        wait_secs: None,
+        min_count: None,
+        max_count: None,
+        wait_ms: None,
+        group: None,
+        offset: 0,
+        auto_ack: false,
+        lease_secs: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::create_api;
+    use crate::storage::MemoryStorage;
+    use crate::MessageService;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// Serves a fresh in-memory queue on a local port, returning its URL.
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = create_api(MessageService::new(Arc::new(MemoryStorage::new())));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{address}")
+    }
+
+    #[tokio::test]
+    async fn round_trips_messages_through_the_server() {
+        let client = SmqlClient::new(&serve().await).unwrap();
+        let added = client.add("jobs", "hello", AddOptions::default()).await.unwrap();
+        client.add("jobs", "again", AddOptions::default()).await.unwrap();
+
+        let peeked = client.peek("jobs", Some(10)).await.unwrap();
+        assert_eq!(peeked.len(), 2);
+        assert_eq!(peeked[0].id, added.id);
+
+        let taken = client.get("jobs", Some(2), 0).await.unwrap();
+        let ids: Vec<String> = taken.iter().map(|message| message.id.to_string()).collect();
+        assert_eq!(client.retry("jobs", vec![ids[1].clone()]).await.unwrap().count, 1);
+        let deleted = client.delete("jobs", vec![ids[0].clone()]).await.unwrap();
+        assert_eq!(deleted.acked, [ids[0].clone()]);
+
+        assert_eq!(client.stats("jobs").await.unwrap().ready_count, 1);
+        assert_eq!(client.purge("jobs", None).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn error_statuses_carry_the_server_message() {
+        let client = SmqlClient::new(&serve().await).unwrap();
+        let error = client.add("bad*name", "hello", AddOptions::default()).await.unwrap_err();
+        assert!(matches!(error, ClientError::Status { status: 400, .. }), "{error}");
+    }
+}
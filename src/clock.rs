@@ -0,0 +1,143 @@
+//! The source of the current time for storage and the service, so lock
+//! timeouts, TTLs and delayed delivery can be driven by a `MockClock` instead
+//! of waiting on the wall clock.
+
+use std::ops::Deref;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Tells the current unix time.
+pub trait Clock: Send + Sync {
+    /// Returns the current unix time in milliseconds.
+    fn now_millis(&self) -> i64;
+
+    /// Returns the current unix time in seconds.
+    fn now_secs(&self) -> i64 {
+        self.now_millis().div_euclid(1000)
+    }
+}
+
+/// The wall clock. Used unless another clock is given.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// Lock timeouts, TTLs and delays are checked against it, so advancing it past
+/// a deadline has the same effect as waiting. Long-polling `get` still waits
+/// in real time.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    millis: AtomicI64,
+}
+
+impl MockClock {
+    /// Creates a clock stopped at `millis` unix milliseconds.
+    pub fn new(millis: i64) -> Self {
+        Self { millis: AtomicI64::new(millis) }
+    }
+
+    /// Creates a clock stopped at the current wall clock time.
+    pub fn now() -> Self {
+        Self::new(SystemClock.now_millis())
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        let by = i64::try_from(by.as_millis()).unwrap_or(i64::MAX);
+        self.millis.fetch_add(by, Ordering::SeqCst);
+    }
+
+    /// Moves the clock to `millis` unix milliseconds, forward or back.
+    pub fn set(&self, millis: i64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> i64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
+
+/// A clock shared between storages and the service, the `SystemClock` by default.
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self(clock)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self(Arc::new(SystemClock))
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::{config, AddOptions, MessageService};
+
+    fn service() -> (Arc<MockClock>, MessageService) {
+        let clock = Arc::new(MockClock::now());
+        let store = Arc::new(MemoryStorage::with_clock(clock.clone()));
+        (clock.clone(), MessageService::with_clock(store, clock))
+    }
+
+    #[tokio::test]
+    async fn delayed_messages_are_delivered_once_the_delay_passes() {
+        let (clock, service) = service();
+        let options = AddOptions { delay_secs: Some(60), ..Default::default() };
+        service.add("jobs", "later".into(), options).await.unwrap();
+        assert!(service.get("jobs", None, 0).await.unwrap().is_empty());
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(service.get("jobs", None, 0).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn messages_expire_once_their_ttl_passes() {
+        let (clock, service) = service();
+        let options = AddOptions { ttl_secs: Some(60), ..Default::default() };
+        service.add("jobs", "short lived".into(), options).await.unwrap();
+
+        clock.advance(Duration::from_secs(61));
+        assert!(service.get("jobs", None, 0).await.unwrap().is_empty());
+        assert_eq!(service.stats("jobs").await.unwrap().total_expired, 1);
+    }
+
+    #[tokio::test]
+    async fn lapsed_locks_return_messages_to_the_queue() {
+        let (clock, service) = service();
+        let added = service.add("jobs", "work".into(), AddOptions::default()).await.unwrap();
+        assert_eq!(service.get("jobs", None, 0).await.unwrap().len(), 1);
+        assert!(service.get("jobs", None, 0).await.unwrap().is_empty());
+
+        clock.advance(Duration::from_secs(config().visibility_timeout_secs + 1));
+        let redelivered = service.get("jobs", None, 0).await.unwrap();
+        assert_eq!(redelivered.len(), 1);
+        assert_eq!(redelivered[0].id, added.id);
+    }
+}
@@ -0,0 +1,66 @@
+//! Deflate compression of large message bodies, used by `MemoryStorage` to
+//! keep big backlogs small in memory.
+
+use crate::MessageBody;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Serialize, Serializer};
+use std::io::{Read, Write};
+
+/// A deflated body together with what is needed to restore it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressedBody {
+    /// Whether the original body was text rather than binary.
+    text: bool,
+    /// The size of the original body in bytes.
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl CompressedBody {
+    /// Compresses a body, returning `None` if compression wouldn't save space.
+    pub(crate) fn compress(body: &MessageBody) -> Option<CompressedBody> {
+        let (text, bytes) = match body {
+            MessageBody::Text(text) => (true, text.as_bytes()),
+            MessageBody::Binary { base64 } => (false, base64.as_slice()),
+            MessageBody::Compressed(_) => return None,
+        };
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).ok()?;
+        let data = encoder.finish().ok()?;
+        (data.len() < bytes.len()).then_some(CompressedBody { text, len: bytes.len(), data })
+    }
+
+    /// Restores the original body.
+    pub(crate) fn decompress(&self) -> MessageBody {
+        let mut bytes = Vec::with_capacity(self.len);
+        DeflateDecoder::new(self.data.as_slice())
+            .read_to_end(&mut bytes)
+            .expect("bodies are only compressed in memory, so can't be corrupt");
+        if self.text {
+            String::from_utf8(bytes)
+                .expect("text bodies are compressed from valid UTF-8")
+                .into()
+        } else {
+            bytes.into()
+        }
+    }
+
+    /// The size of the original body in bytes.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The size of the compressed body in bytes.
+    pub(crate) fn compressed_len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Serializes a compressed body as the original, so compression never shows
+/// in responses or the write-ahead log.
+pub(crate) fn serialize<S: Serializer>(body: &CompressedBody, serializer: S) -> Result<S::Ok, S::Error> {
+    body.decompress().serialize(serializer)
+}
@@ -0,0 +1,266 @@
+//! Minimal HMAC-SHA256 (RFC 2104 over FIPS 180-4 SHA-256), used to sign
+//! message bodies when `hmac_key` is set.
+
+use crate::auth::constant_time_eq;
+use crate::MessageBody;
+
+const BLOCK_LEN: usize = 64;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+struct Sha256 {
+    state: [u32; 8],
+    buffer: Vec<u8>,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Self { state: INITIAL_STATE, buffer: Vec::with_capacity(BLOCK_LEN), len: 0 }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.buffer.is_empty() {
+            let take = data.len().min(BLOCK_LEN - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() < BLOCK_LEN {
+                return;
+            }
+            let block = std::mem::take(&mut self.buffer);
+            self.compress(&block);
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_LEN);
+        for block in blocks.by_ref() {
+            self.compress(block);
+        }
+        self.buffer.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bit_len = self.len.wrapping_mul(8);
+        let mut padding = vec![0x80];
+        let padded = (self.buffer.len() + 1 + 8).div_ceil(BLOCK_LEN) * BLOCK_LEN;
+        padding.resize(padded - self.buffer.len() - 8, 0);
+        padding.extend_from_slice(&bit_len.to_be_bytes());
+        // Feed the padding without counting it towards the message length.
+        let len = self.len;
+        self.update(&padding);
+        self.len = len;
+
+        let mut digest = [0; 32];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        block[..32].copy_from_slice(&hasher.finish());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(&block.map(|byte| byte ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(&block.map(|byte| byte ^ 0x5c));
+    outer.update(&inner.finish());
+    outer.finish()
+}
+
+/// Returns the lowercase hex HMAC-SHA256 of a body's bytes: UTF-8 for text,
+/// the raw bytes for binary.
+pub(crate) fn sign(key: &[u8], body: &MessageBody) -> String {
+    let digest = match body {
+        MessageBody::Text(text) => hmac_sha256(key, text.as_bytes()),
+        MessageBody::Binary { base64 } => hmac_sha256(key, base64),
+        MessageBody::Compressed(_) => return sign(key, &body.clone().decompressed()),
+    };
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Checks a hex signature against the body, taking the same time whichever
+/// byte differs.
+pub(crate) fn verify(key: &[u8], body: &MessageBody, signature: &str) -> bool {
+    let signature = signature.to_ascii_lowercase();
+    constant_time_eq(sign(key, body).as_bytes(), signature.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::{set_test_config, AddOptions, Config, Error, MessageService};
+    use std::sync::Arc;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hex(&hasher.finish())
+    }
+
+    #[test]
+    fn sha256_matches_fips_180_2() {
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_is_the_same_however_the_input_is_split() {
+        let data = [b'x'; 200];
+        let mut hasher = Sha256::new();
+        for chunk in data.chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hex(&hasher.finish()), sha256(&data));
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let long_key = [0xaa; 131];
+        let cases: [(&[u8], &[u8], &str); 6] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &[
+                    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+                    0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19,
+                ],
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+            (
+                &long_key,
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                &long_key,
+                b"This is a test using a larger than block-size key and a larger than \
+                  block-size data. The key needs to be hashed before being used by the \
+                  HMAC algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (i, (key, data, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(hex(&hmac_sha256(key, data)), expected, "test case {}", i + 1);
+        }
+    }
+
+    #[test]
+    fn signatures_cover_the_body_bytes() {
+        let text = MessageBody::Text("Hi There".into());
+        let binary = MessageBody::Binary { base64: b"Hi There".to_vec() };
+        let signature = sign(&[0x0b; 20], &text);
+        assert_eq!(signature, "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        assert_eq!(sign(&[0x0b; 20], &binary), signature);
+        assert!(verify(&[0x0b; 20], &text, &signature.to_ascii_uppercase()));
+        assert!(!verify(&[0x0b; 20], &MessageBody::Text("Hi there".into()), &signature));
+        assert!(!verify(b"other key", &text, &signature));
+    }
+
+    #[tokio::test]
+    async fn service_signs_and_checks_bodies() {
+        set_test_config(Config { hmac_key: Some("Jefe".into()), ..Config::default() });
+        let service = MessageService::new(Arc::new(MemoryStorage::new()));
+        let body = "what do ya want for nothing?";
+        let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+        let added = service.add("jobs", body.into(), AddOptions::default()).await.unwrap();
+        assert_eq!(added.signature.as_deref(), Some(expected));
+        let taken = service.get("jobs", None, 0).await.unwrap();
+        assert_eq!(taken[0].signature.as_deref(), Some(expected));
+
+        let signature = Some(expected.to_string());
+        let tampered = AddOptions { signature: signature.clone(), ..Default::default() };
+        let result = service.add("jobs", "what do ya want for something?".into(), tampered).await;
+        assert!(matches!(result, Err(Error::InvalidSignature)), "{result:?}");
+
+        let signed = AddOptions { signature, ..Default::default() };
+        let added = service.add("jobs", body.into(), signed).await.unwrap();
+        assert_eq!(added.signature.as_deref(), Some(expected));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::storage::StorageError;
+use crate::{Error, Message, MessageService};
+use tokio::runtime::Handle;
+
+/// A message taken from a queue by `MessageService::get_leased`.
+///
+/// The message must be settled with `ack` or `nack`. A lease that is dropped
+/// without being settled is nacked by a background task, so the message is
+/// redelivered instead of waiting for its visibility timeout. If no Tokio
+/// runtime is available at drop time, the lock expiry still returns it.
+pub struct MessageLease {
+    service: MessageService,
+    queue: String,
+    message: Message,
+    settled: bool,
+}
+
+impl MessageLease {
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    /// Acknowledges the message, removing it from the queue.
+    ///
+    /// Returns false if the message was no longer processing, e.g. because its
+    /// lock had already expired.
+    pub async fn ack(mut self) -> Result<bool, Error> {
+        self.settled = true;
+        let id = self.message.id.to_string();
+        match self.service.ack(&self.queue, vec![id]).await {
+            Ok(outcome) => Ok(outcome.count > 0),
+            // Raised instead of an empty outcome when `strict_delete` is set.
+            Err(Error::Store(StorageError::NotFound(_))) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the message to the queue for redelivery.
+    ///
+    /// Returns false if the message was no longer processing.
+    pub async fn nack(mut self) -> Result<bool, Error> {
+        self.settled = true;
+        let id = self.message.id.to_string();
+        Ok(self.service.nack(&self.queue, vec![id]).await?.count > 0)
+    }
+}
+
+impl Drop for MessageLease {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+
+        let Ok(handle) = Handle::try_current() else {
+            return;
+        };
+        let service = self.service.clone();
+        let queue = std::mem::take(&mut self.queue);
+        let id = self.message.id.to_string();
+        handle.spawn(async move {
+            if let Err(e) = service.nack(&queue, vec![id.clone()]).await {
+                tracing::warn!("Failed to return dropped lease {id} to {queue}: {e}");
+            }
+        });
+    }
+}
+
+impl MessageService {
+    /// Takes up to `count` messages like `get`, wrapping each in a lease that
+    /// returns it to the queue unless it is acknowledged.
+    pub async fn get_leased(
+        &self,
+        queue: &str,
+        count: Option<usize>,
+        wait_secs: u64,
+    ) -> Result<Vec<MessageLease>, Error> {
+        let messages = self.get(queue, count, wait_secs).await?;
+        Ok(messages
+            .into_iter()
+            .map(|message| MessageLease {
+                service: self.clone(),
+                queue: queue.to_string(),
+                message,
+                settled: false,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::storage::MemoryStorage;
+    use crate::{config, set_test_config, AddOptions, Config};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn service() -> (Arc<MockClock>, MessageService) {
+        let clock = Arc::new(MockClock::now());
+        let store = Arc::new(MemoryStorage::with_clock(clock.clone()));
+        (clock.clone(), MessageService::with_clock(store, clock))
+    }
+
+    #[tokio::test]
+    async fn dropped_leases_are_redelivered() {
+        let (_, service) = service();
+        let added = service.add("jobs", "work".into(), AddOptions::default()).await.unwrap();
+        let leases = service.get_leased("jobs", None, 0).await.unwrap();
+        assert_eq!(leases[0].message().id, added.id);
+        assert_eq!(leases[0].queue(), "jobs");
+        drop(leases);
+
+        // The nack runs on a spawned task, which the wait gives a turn.
+        let again = service.get("jobs", None, 1).await.unwrap();
+        assert_eq!(again[0].id, added.id);
+        assert_eq!(again[0].retry_count, 1);
+    }
+
+    #[tokio::test]
+    async fn settled_leases_are_not_returned() {
+        let (_, service) = service();
+        for body in ["acked", "nacked"] {
+            service.add("jobs", body.into(), AddOptions::default()).await.unwrap();
+        }
+        let mut leases = service.get_leased("jobs", Some(2), 0).await.unwrap();
+        let nacked = leases.pop().unwrap();
+        let nacked_id = nacked.message().id;
+        assert!(leases.pop().unwrap().ack().await.unwrap());
+        assert!(nacked.nack().await.unwrap());
+        tokio::task::yield_now().await;
+
+        let stats = service.stats("jobs").await.unwrap();
+        assert_eq!((stats.ready_count, stats.processing_count), (1, 0));
+        let again = service.get("jobs", Some(10), 0).await.unwrap();
+        assert_eq!(again.len(), 1);
+        assert_eq!((again[0].id, again[0].retry_count), (nacked_id, 1));
+    }
+
+    /// Takes two leases, then lets their locks lapse.
+    async fn lapsed_leases() -> (MessageService, MessageLease, MessageLease) {
+        let (clock, service) = service();
+        for body in ["a", "b"] {
+            service.add("jobs", body.into(), AddOptions::default()).await.unwrap();
+        }
+        let mut leases = service.get_leased("jobs", Some(2), 0).await.unwrap();
+        clock.advance(Duration::from_secs(config().visibility_timeout_secs + 1));
+        let (second, first) = (leases.pop().unwrap(), leases.pop().unwrap());
+        (service, first, second)
+    }
+
+    #[tokio::test]
+    async fn settling_a_lapsed_lease_returns_false() {
+        let (service, first, second) = lapsed_leases().await;
+        assert!(!first.ack().await.unwrap());
+        assert!(!second.nack().await.unwrap());
+        let stats = service.stats("jobs").await.unwrap();
+        assert_eq!((stats.ready_count, stats.processing_count), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn lapsed_acks_return_false_with_strict_delete() {
+        set_test_config(Config { strict_delete: true, ..Config::default() });
+        let (service, first, _) = lapsed_leases().await;
+        let id = first.message().id.to_string();
+        let strict = service.ack("jobs", vec![id]).await;
+        assert!(matches!(strict, Err(Error::Store(StorageError::NotFound(_)))), "{strict:?}");
+        assert!(!first.ack().await.unwrap());
+    }
+}
@@ -1,37 +1,406 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use clock::{Clock, SharedClock, SystemClock};
+use regex::Regex;
+use schema::Schema;
+use upload::Uploads;
+pub use compress::CompressedBody;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, OnceLock};
-use tracing::Level;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{field, Level, Span};
 use uuid::Uuid;
 
 pub mod api;
+pub mod auth;
+mod base64;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod client;
+pub mod clock;
+mod compress;
+mod hmac;
+pub mod lease;
+pub mod rate_limit;
+mod regex;
+mod schema;
+mod sha1;
+pub mod snapshot;
 pub mod storage;
+mod upload;
+mod wal;
+mod websocket;
 
 // CONFIG
 const DEFAULT_PORT: u16 = 1337;
+const DEFAULT_BIND_ADDR: &str = "::";
 const DEFAULT_MAX_MESSAGE_SIZE: usize = 65536; // 64KB
+const DEFAULT_MAX_BATCH_BYTES: usize = 2 * 1024 * 1024; // 2MB
 const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_VISIBILITY_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_RETRIES: i32 = 5;
+const DEFAULT_RETRY_BACKOFF_MAX_SECS: u64 = 300;
+const DEFAULT_MAX_RETRY_DELAY_SECS: u64 = 12 * 60 * 60;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_SLOW_CONSUMER_SCAN_SECS: u64 = 60;
+const DEFAULT_WAL_COMPACT_EVERY: usize = 10_000;
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_SQLITE_PATH: &str = "smql.db";
+const DEFAULT_DEDUP_WINDOW_SECS: u64 = 300;
+const DEFAULT_UPLOAD_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_GET_COUNT: usize = 1;
+const DEFAULT_MAX_GET_COUNT: usize = 1000;
+const DEFAULT_MAX_BATCH_IDS: usize = 1000;
+const MAX_QUEUE_NAME_LEN: usize = 64;
+pub(crate) const MAX_WAIT_SECS: u64 = 20;
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+const MAX_ATTEMPT_HISTORY: usize = 10;
+const MAX_MESSAGE_RETRIES: i32 = 1000;
+const MAX_SLOW_MESSAGE_IDS: usize = 100;
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+/// Messages `/drain_to` sends per request unless told otherwise.
+const DEFAULT_DRAIN_BATCH: usize = 100;
+/// Longest wait for the target of `/drain_to` to take a batch.
+const DRAIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Shortest time between warnings about oversized bodies on one queue.
+const REJECTION_WARN_INTERVAL: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Clone)]
+/// The queue used by the unscoped routes (`/add`, `/get`, ...).
+pub const DEFAULT_QUEUE: &str = "default";
+
+tokio::task_local! {
+    /// The id of the API request being served, set by the request id middleware.
+    pub static REQUEST_ID: String;
+}
+
+/// Returns the id of the API request being served, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Joins a queue name and a consumer group name in the name of the queue that
+/// holds the group's copies of the messages.
+pub const GROUP_SEPARATOR: char = ':';
+
+/// Returns the name of the queue holding `group`'s copies of messages added to
+/// `queue`, e.g. `orders:billing`.
+pub fn group_queue(queue: &str, group: &str) -> String {
+    format!("{queue}{GROUP_SEPARATOR}{group}")
+}
+
+/// Server settings. Read from the JSON file named by `SMQL_CONFIG`, if any,
+/// with `SMQL_*` environment variables overriding individual fields.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    pub bind_addr: String,
     pub port: u16,
+    /// Most bytes a body and its attributes may take, counted as UTF-8 for text.
     pub max_message_size: usize,
+    /// Most bytes the bodies of one batch add may take together.
+    pub max_batch_bytes: usize,
+    /// Most characters a text body may have, on top of `max_message_size`.
+    pub body_char_limit: Option<usize>,
+    /// Bodies larger than this many bytes are compressed while stored.
+    pub compress_threshold: Option<usize>,
     pub log_level: String,
+    pub log_format: LogFormat,
+    pub visibility_timeout_secs: u64,
+    /// Messages processing for longer than this are reported as slow; 0 turns
+    /// detection off.
+    pub slow_consumer_secs: u64,
+    /// How often the processing messages of every queue are scanned for slow ones.
+    pub slow_consumer_scan_secs: u64,
+    pub max_retries: i32,
+    /// Delay before a retried message is redelivered, doubled on each further
+    /// retry. 0 redelivers at once.
+    pub retry_backoff_base_secs: u64,
+    /// Longest delay the retry backoff may reach.
+    pub retry_backoff_max_secs: u64,
+    /// Longest `delay_secs` a consumer may ask for when retrying or nacking.
+    pub max_retry_delay_secs: u64,
+    pub wal_path: Option<String>,
+    pub wal_compact_every: usize,
+    /// Where messages are stored.
+    pub storage: StorageBackend,
+    /// Database file for the `sqlite` storage backend.
+    pub sqlite_path: String,
+    /// File every queue is saved to periodically and restored from on startup.
+    pub snapshot_path: Option<String>,
+    /// Seconds between snapshots.
+    pub snapshot_interval_secs: u64,
+    /// Shards each queue is spread over, 0 for one per CPU. More than one
+    /// gives up strict ordering for throughput.
+    pub shards: usize,
+    /// Which tokio runtime the server runs on.
+    pub runtime: Runtime,
+    /// Worker threads for the multi-threaded runtime, or one per CPU if unset.
+    pub worker_threads: Option<usize>,
+    /// Longest wait for open requests to finish on shutdown before they are cut off.
+    pub shutdown_timeout_secs: u64,
+    pub api_key: Option<String>,
+    /// Key for signing message bodies with HMAC-SHA256.
+    pub hmac_key: Option<String>,
+    /// Token `/purge` requests must carry; purges need none when unset.
+    pub purge_token: Option<String>,
+    /// Answer cross-origin requests; off leaves CORS to a proxy in front.
+    pub cors: bool,
+    /// Origins allowed to make cross-origin requests; `*` or unset allows any.
+    pub cors_origins: Option<Vec<String>>,
+    /// Methods allowed in cross-origin requests; `*` or unset allows any.
+    pub cors_methods: Option<Vec<String>>,
+    /// Headers allowed in cross-origin requests; `*` or unset allows any.
+    pub cors_headers: Option<Vec<String>>,
+    /// Let browsers send cookies and auth headers cross-origin. Requires explicit
+    /// origins, methods and headers.
+    pub cors_allow_credentials: bool,
+    /// Requests per second allowed across all add routes.
+    pub rate_limit: Option<f64>,
+    /// Requests per second allowed to the add routes of each queue.
+    pub queue_rate_limit: Option<f64>,
+    pub dedup_window_secs: u64,
+    /// Chunked uploads that go this long without a chunk are abandoned.
+    pub upload_timeout_secs: u64,
+    /// Expired messages kept per queue for `/expired`; 0 drops them unseen.
+    pub expired_archive_size: usize,
+    pub max_queue_depth: Option<usize>,
+    /// Most messages a queue may have processing at once.
+    pub max_in_flight: Option<usize>,
+    /// Most messages a queue keeps processing; past it, the longest processing
+    /// ones are requeued to make room.
+    pub processing_capacity: Option<usize>,
+    pub default_get_count: usize,
+    pub max_get_count: usize,
+    /// Most ids a single delete, retry, ack, nack, extend or requeue may name.
+    pub max_batch_ids: usize,
+    /// Treat text bodies that are only whitespace as empty.
+    pub reject_blank_bodies: bool,
+    /// Pattern every body must match somewhere to be added.
+    pub body_regex: Option<String>,
+    /// JSON Schema every body must be valid JSON against to be added.
+    pub body_schema: Option<serde_json::Value>,
+    /// Fail deletes that name a message which is not processing.
+    pub strict_delete: bool,
+    /// How ids are generated for messages added without one.
+    pub id_generator: IdGenerator,
+    /// Whether the oldest or newest message of a priority is delivered first.
+    pub order: Order,
+    pub schedule: Schedule,
+    /// Priority bands for `Schedule::Weighted`, keyed by the lowest priority in
+    /// each band. When empty, every priority is its own band with weight
+    /// `priority + 1`.
+    pub priority_weights: BTreeMap<u8, u32>,
+    /// Relative share of `get_any` deliveries per queue. Unlisted queues weigh 1.
+    pub queue_weights: BTreeMap<String, u32>,
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+/// How message ids are generated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdGenerator {
+    /// Time-ordered random UUIDs.
+    #[default]
+    UuidV7,
+    /// Fully random UUIDs, revealing nothing about when a message was added.
+    UuidV4,
+    /// UUIDs holding the server start time in milliseconds in the high 64 bits
+    /// and a counter in the low 64, so ids sort in the order they were made.
+    Sequential,
+}
+
+impl IdGenerator {
+    pub fn generate(&self) -> Uuid {
+        match self {
+            IdGenerator::UuidV7 => Uuid::now_v7(),
+            IdGenerator::UuidV4 => Uuid::new_v4(),
+            IdGenerator::Sequential => {
+                static STARTED_AT: OnceLock<u64> = OnceLock::new();
+                static COUNTER: AtomicU64 = AtomicU64::new(1);
+                let started_at = *STARTED_AT.get_or_init(|| SystemClock.now_millis() as u64);
+                Uuid::from_u64_pair(started_at, COUNTER.fetch_add(1, Ordering::Relaxed))
+            }
+        }
+    }
+}
+
+/// The tokio runtime flavor the server runs on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Runtime {
+    /// A pool of worker threads, `worker_threads` of them if set.
+    #[default]
+    MultiThread,
+    /// Everything on the main thread, for a single CPU.
+    CurrentThread,
+}
+
+/// The storage backend messages are kept in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// In memory, optionally behind `wal_path` or `snapshot_path`.
+    #[default]
+    Memory,
+    /// In the SQLite database at `sqlite_path`. Needs the `sqlite` feature.
+    Sqlite,
+}
+
+/// Which message of a priority is delivered first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    /// Oldest first, like a queue.
+    #[default]
+    Fifo,
+    /// Newest first, like a stack.
+    Lifo,
+}
+
+/// How `get` chooses between ready messages of different priorities.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    /// Always deliver the highest priority first.
+    #[default]
+    Strict,
+    /// Share deliveries between priority bands in proportion to their weights,
+    /// so low priorities are not starved.
+    Weighted,
+}
+
+/// The limits and behaviour of the server that clients may need to adapt to.
+/// Never includes secrets such as the API key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub max_message_size: usize,
+    pub max_batch_bytes: usize,
+    pub body_char_limit: Option<usize>,
+    pub reject_blank_bodies: bool,
+    pub body_regex: Option<String>,
+    pub body_schema: Option<serde_json::Value>,
+    pub max_queue_name_length: usize,
+    pub default_get_count: usize,
+    pub max_get_count: usize,
+    pub max_wait_secs: u64,
+    pub max_batch_ids: usize,
+    pub visibility_timeout_secs: u64,
+    pub max_retries: i32,
+    /// Highest `max_retries` a message may set for itself.
+    pub max_message_retries: i32,
+    pub retry_backoff_base_secs: u64,
+    pub retry_backoff_max_secs: u64,
+    pub max_retry_delay_secs: u64,
+    pub dedup_window_secs: u64,
+    pub upload_timeout_secs: u64,
+    pub expired_archive_size: usize,
+    pub max_queue_depth: Option<usize>,
+    pub max_in_flight: Option<usize>,
+    pub strict_delete: bool,
+    pub rate_limit: Option<f64>,
+    pub queue_rate_limit: Option<f64>,
+    /// Whether requests must carry the API key.
+    pub auth_required: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            bind_addr: DEFAULT_BIND_ADDR.to_string(),
             port: DEFAULT_PORT,
             max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_batch_bytes: DEFAULT_MAX_BATCH_BYTES,
+            body_char_limit: None,
+            compress_threshold: None,
             log_level: DEFAULT_LOG_LEVEL.to_string(),
+            log_format: LogFormat::default(),
+            visibility_timeout_secs: DEFAULT_VISIBILITY_TIMEOUT_SECS,
+            slow_consumer_secs: 0,
+            slow_consumer_scan_secs: DEFAULT_SLOW_CONSUMER_SCAN_SECS,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff_base_secs: 0,
+            retry_backoff_max_secs: DEFAULT_RETRY_BACKOFF_MAX_SECS,
+            max_retry_delay_secs: DEFAULT_MAX_RETRY_DELAY_SECS,
+            wal_path: None,
+            storage: StorageBackend::default(),
+            sqlite_path: DEFAULT_SQLITE_PATH.to_string(),
+            wal_compact_every: DEFAULT_WAL_COMPACT_EVERY,
+            snapshot_path: None,
+            snapshot_interval_secs: DEFAULT_SNAPSHOT_INTERVAL_SECS,
+            shards: 1,
+            runtime: Runtime::default(),
+            worker_threads: None,
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            api_key: None,
+            hmac_key: None,
+            purge_token: None,
+            cors: true,
+            cors_origins: None,
+            cors_methods: None,
+            cors_headers: None,
+            cors_allow_credentials: false,
+            rate_limit: None,
+            queue_rate_limit: None,
+            dedup_window_secs: DEFAULT_DEDUP_WINDOW_SECS,
+            upload_timeout_secs: DEFAULT_UPLOAD_TIMEOUT_SECS,
+            expired_archive_size: 0,
+            max_queue_depth: None,
+            max_in_flight: None,
+            processing_capacity: None,
+            default_get_count: DEFAULT_GET_COUNT,
+            max_get_count: DEFAULT_MAX_GET_COUNT,
+            max_batch_ids: DEFAULT_MAX_BATCH_IDS,
+            reject_blank_bodies: false,
+            body_regex: None,
+            body_schema: None,
+            strict_delete: false,
+            id_generator: IdGenerator::default(),
+            order: Order::default(),
+            schedule: Schedule::default(),
+            priority_weights: BTreeMap::new(),
+            queue_weights: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
     pub fn from_env() -> Self {
-        let mut config = Config::default();
+        Self::with_env(Config::default())
+    }
+
+    /// Reads settings from a JSON file, then applies environment overrides.
+    /// Fields missing from the file keep their defaults.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let config = serde_json::from_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self::with_env(config))
+    }
+
+    fn with_env(mut config: Config) -> Self {
+        if let Ok(bind_addr) = env::var("SMQL_BIND_ADDR") {
+            config.bind_addr = bind_addr;
+        }
 
         if let Ok(port_str) = env::var("SMQL_PORT") {
             config.port = port_str.parse().unwrap_or(config.port);
@@ -41,27 +410,493 @@ impl Config {
             config.max_message_size = Self::parse_size(&size_str).unwrap_or(config.max_message_size);
         }
 
+        if let Ok(size_str) = env::var("SMQL_MAX_BATCH_BYTES") {
+            config.max_batch_bytes = Self::parse_size(&size_str).unwrap_or(config.max_batch_bytes);
+        }
+
+        if let Ok(limit_str) = env::var("SMQL_BODY_CHAR_LIMIT") {
+            config.body_char_limit = limit_str.parse::<usize>().ok().filter(|&limit| limit > 0);
+        }
+
+        if let Ok(threshold_str) = env::var("SMQL_COMPRESS_THRESHOLD") {
+            config.compress_threshold = Self::parse_size(&threshold_str);
+        }
+
         if let Ok(log_level) = env::var("SMQL_LOG_LEVEL") {
             config.log_level = log_level;
         }
 
+        if let Ok(format_str) = env::var("SMQL_LOG_FORMAT") {
+            match format_str.to_lowercase().as_str() {
+                "text" => config.log_format = LogFormat::Text,
+                "json" => config.log_format = LogFormat::Json,
+                _ => {}
+            }
+        }
+
+        if let Ok(timeout_str) = env::var("SMQL_VISIBILITY_TIMEOUT") {
+            config.visibility_timeout_secs = timeout_str
+                .parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .unwrap_or(config.visibility_timeout_secs);
+        }
+
+        if let Ok(slow_str) = env::var("SMQL_SLOW_CONSUMER") {
+            config.slow_consumer_secs = slow_str.parse::<u64>().unwrap_or(config.slow_consumer_secs);
+        }
+
+        if let Ok(scan_str) = env::var("SMQL_SLOW_CONSUMER_SCAN") {
+            config.slow_consumer_scan_secs = scan_str
+                .parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .unwrap_or(config.slow_consumer_scan_secs);
+        }
+
+        if let Ok(retries_str) = env::var("SMQL_MAX_RETRIES") {
+            config.max_retries = retries_str
+                .parse::<i32>()
+                .ok()
+                .filter(|&retries| retries >= 0)
+                .unwrap_or(config.max_retries);
+        }
+
+        if let Ok(base_str) = env::var("SMQL_RETRY_BACKOFF_BASE") {
+            config.retry_backoff_base_secs = base_str.parse().unwrap_or(config.retry_backoff_base_secs);
+        }
+
+        if let Ok(max_str) = env::var("SMQL_RETRY_BACKOFF_MAX") {
+            config.retry_backoff_max_secs = max_str
+                .parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .unwrap_or(config.retry_backoff_max_secs);
+        }
+
+        if let Ok(max_str) = env::var("SMQL_MAX_RETRY_DELAY") {
+            config.max_retry_delay_secs = max_str.parse().unwrap_or(config.max_retry_delay_secs);
+        }
+
+        if let Ok(timeout_str) = env::var("SMQL_SHUTDOWN_TIMEOUT") {
+            config.shutdown_timeout_secs = timeout_str
+                .parse::<u64>()
+                .unwrap_or(config.shutdown_timeout_secs);
+        }
+
+        if let Ok(wal_path) = env::var("SMQL_WAL_PATH") {
+            config.wal_path = Some(wal_path).filter(|path| !path.is_empty());
+        }
+
+        if let Ok(compact_str) = env::var("SMQL_WAL_COMPACT_EVERY") {
+            config.wal_compact_every = compact_str
+                .parse::<usize>()
+                .ok()
+                .filter(|&records| records > 0)
+                .unwrap_or(config.wal_compact_every);
+        }
+
+        if let Ok(storage_str) = env::var("SMQL_STORAGE") {
+            match storage_str.to_lowercase().as_str() {
+                "memory" => config.storage = StorageBackend::Memory,
+                "sqlite" => config.storage = StorageBackend::Sqlite,
+                _ => {}
+            }
+        }
+
+        if let Ok(sqlite_path) = env::var("SMQL_SQLITE_PATH") {
+            if !sqlite_path.is_empty() {
+                config.sqlite_path = sqlite_path;
+            }
+        }
+
+        if let Ok(snapshot_path) = env::var("SMQL_SNAPSHOT_PATH") {
+            config.snapshot_path = Some(snapshot_path).filter(|path| !path.is_empty());
+        }
+
+        if let Ok(interval_str) = env::var("SMQL_SNAPSHOT_INTERVAL") {
+            config.snapshot_interval_secs = interval_str
+                .parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .unwrap_or(config.snapshot_interval_secs);
+        }
+
+        if let Ok(shards_str) = env::var("SMQL_SHARDS") {
+            config.shards = shards_str.parse().unwrap_or(config.shards);
+        }
+
+        if let Ok(runtime_str) = env::var("SMQL_RUNTIME") {
+            match runtime_str.to_lowercase().as_str() {
+                "multi_thread" => config.runtime = Runtime::MultiThread,
+                "current_thread" => config.runtime = Runtime::CurrentThread,
+                _ => {}
+            }
+        }
+
+        if let Ok(threads_str) = env::var("SMQL_WORKER_THREADS") {
+            config.worker_threads = threads_str
+                .parse::<usize>()
+                .ok()
+                .filter(|&threads| threads > 0)
+                .or(config.worker_threads);
+        }
+
+        if let Ok(api_key) = env::var("SMQL_API_KEY") {
+            config.api_key = Some(api_key).filter(|key| !key.is_empty());
+        }
+
+        if let Ok(hmac_key) = env::var("SMQL_HMAC_KEY") {
+            config.hmac_key = Some(hmac_key).filter(|key| !key.is_empty());
+        }
+
+        if let Ok(purge_token) = env::var("SMQL_PURGE_TOKEN") {
+            config.purge_token = Some(purge_token).filter(|token| !token.is_empty());
+        }
+
+        if let Ok(cors_str) = env::var("SMQL_CORS") {
+            config.cors = match cors_str.to_ascii_lowercase().as_str() {
+                "off" => false,
+                "on" => true,
+                other => other.parse().unwrap_or(config.cors),
+            };
+        }
+
+        if let Ok(origins_str) = env::var("SMQL_CORS_ORIGINS") {
+            config.cors_origins = Self::parse_list(&origins_str);
+        }
+
+        if let Ok(methods_str) = env::var("SMQL_CORS_METHODS") {
+            config.cors_methods = Self::parse_list(&methods_str);
+        }
+
+        if let Ok(headers_str) = env::var("SMQL_CORS_HEADERS") {
+            config.cors_headers = Self::parse_list(&headers_str);
+        }
+
+        if let Ok(credentials_str) = env::var("SMQL_CORS_CREDENTIALS") {
+            config.cors_allow_credentials =
+                credentials_str.parse().unwrap_or(config.cors_allow_credentials);
+        }
+
+        if let Ok(rate_str) = env::var("SMQL_RATE_LIMIT") {
+            config.rate_limit = Self::parse_rate(&rate_str);
+        }
+
+        if let Ok(rate_str) = env::var("SMQL_QUEUE_RATE_LIMIT") {
+            config.queue_rate_limit = Self::parse_rate(&rate_str);
+        }
+
+        if let Ok(window_str) = env::var("SMQL_DEDUP_WINDOW") {
+            config.dedup_window_secs = window_str.parse().unwrap_or(config.dedup_window_secs);
+        }
+
+        if let Ok(timeout_str) = env::var("SMQL_UPLOAD_TIMEOUT") {
+            config.upload_timeout_secs = timeout_str
+                .parse::<u64>()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .unwrap_or(config.upload_timeout_secs);
+        }
+
+        if let Ok(archive_str) = env::var("SMQL_EXPIRED_ARCHIVE") {
+            config.expired_archive_size =
+                archive_str.parse().unwrap_or(config.expired_archive_size);
+        }
+
+        if let Ok(depth_str) = env::var("SMQL_MAX_QUEUE_DEPTH") {
+            config.max_queue_depth = depth_str.parse::<usize>().ok().filter(|&depth| depth > 0);
+        }
+
+        if let Ok(in_flight_str) = env::var("SMQL_MAX_IN_FLIGHT") {
+            config.max_in_flight = in_flight_str.parse::<usize>().ok().filter(|&max| max > 0);
+        }
+
+        if let Ok(capacity_str) = env::var("SMQL_PROCESSING_CAPACITY") {
+            config.processing_capacity =
+                capacity_str.parse::<usize>().ok().filter(|&capacity| capacity > 0);
+        }
+
+        if let Ok(count_str) = env::var("SMQL_DEFAULT_GET_COUNT") {
+            config.default_get_count = count_str
+                .parse::<usize>()
+                .ok()
+                .filter(|&count| count > 0)
+                .unwrap_or(config.default_get_count);
+        }
+
+        if let Ok(count_str) = env::var("SMQL_MAX_GET_COUNT") {
+            config.max_get_count = count_str
+                .parse::<usize>()
+                .ok()
+                .filter(|&count| count > 0)
+                .unwrap_or(config.max_get_count);
+        }
+
+        if let Ok(ids_str) = env::var("SMQL_MAX_BATCH_IDS") {
+            config.max_batch_ids = ids_str
+                .parse::<usize>()
+                .ok()
+                .filter(|&count| count > 0)
+                .unwrap_or(config.max_batch_ids);
+        }
+
+        if let Ok(blank_str) = env::var("SMQL_REJECT_BLANK_BODIES") {
+            config.reject_blank_bodies = blank_str.parse().unwrap_or(config.reject_blank_bodies);
+        }
+
+        if let Ok(regex) = env::var("SMQL_BODY_REGEX") {
+            config.body_regex = Some(regex).filter(|regex| !regex.is_empty());
+        }
+
+        if let Ok(schema_str) = env::var("SMQL_BODY_SCHEMA") {
+            // Kept as a string when it isn't JSON, for `validate` to report.
+            config.body_schema = Some(schema_str).filter(|schema| !schema.is_empty()).map(|schema| {
+                serde_json::from_str(&schema).unwrap_or(serde_json::Value::String(schema))
+            });
+        }
+
+        if let Ok(strict_str) = env::var("SMQL_STRICT_DELETE") {
+            config.strict_delete = strict_str.parse().unwrap_or(config.strict_delete);
+        }
+
+        if let Ok(generator_str) = env::var("SMQL_ID_GENERATOR") {
+            match generator_str.to_lowercase().as_str() {
+                "uuidv7" => config.id_generator = IdGenerator::UuidV7,
+                "uuidv4" => config.id_generator = IdGenerator::UuidV4,
+                "sequential" => config.id_generator = IdGenerator::Sequential,
+                _ => {}
+            }
+        }
+
+        if let Ok(order_str) = env::var("SMQL_ORDER") {
+            match order_str.to_lowercase().as_str() {
+                "fifo" => config.order = Order::Fifo,
+                "lifo" => config.order = Order::Lifo,
+                _ => {}
+            }
+        }
+
+        if let Ok(schedule_str) = env::var("SMQL_SCHEDULE") {
+            match schedule_str.to_lowercase().as_str() {
+                "strict" => config.schedule = Schedule::Strict,
+                "weighted" => config.schedule = Schedule::Weighted,
+                _ => {}
+            }
+        }
+
+        if let Ok(weights_str) = env::var("SMQL_PRIORITY_WEIGHTS") {
+            config.priority_weights =
+                Self::parse_weights(&weights_str).unwrap_or(config.priority_weights);
+        }
+
+        if let Ok(weights_str) = env::var("SMQL_QUEUE_WEIGHTS") {
+            config.queue_weights = Self::parse_weights(&weights_str).unwrap_or(config.queue_weights);
+        }
+
         config
     }
 
+    /// Parses a positive size in bytes, optionally with a `K`, `M` or `G`
+    /// suffix (either case) for KB, MB or GB. Sizes too large to represent are
+    /// rejected rather than wrapped.
     fn parse_size(value: &str) -> Option<usize> {
-        if value.is_empty() {
-            return None;
+        let (number, unit) = match value.char_indices().last()? {
+            (i, 'K' | 'k') => (&value[..i], 1024),
+            (i, 'M' | 'm') => (&value[..i], 1024 * 1024),
+            (i, 'G' | 'g') => (&value[..i], 1024 * 1024 * 1024),
+            _ => (value, 1),
+        };
+        number
+            .parse::<usize>()
+            .ok()
+            .filter(|&count| count > 0)
+            .and_then(|count| count.checked_mul(unit))
+    }
+
+    /// Splits a comma separated list, ignoring blank entries. An empty list is `None`.
+    fn parse_list(value: &str) -> Option<Vec<String>> {
+        let items: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect();
+        Some(items).filter(|items| !items.is_empty())
+    }
+
+    /// Checks settings that can't be fixed by falling back to a default.
+    pub fn validate(&self) -> Result<(), String> {
+        let invalid = |name: &str, item: &str| format!("invalid entry in {name}: {item:?}");
+        for origin in self.cors_origins.iter().flatten().filter(|item| *item != "*") {
+            HeaderValue::from_str(origin).map_err(|_| invalid("cors_origins", origin))?;
+        }
+        for method in self.cors_methods.iter().flatten().filter(|item| *item != "*") {
+            Method::from_str(&method.to_uppercase()).map_err(|_| invalid("cors_methods", method))?;
+        }
+        for header in self.cors_headers.iter().flatten().filter(|item| *item != "*") {
+            HeaderName::from_str(header).map_err(|_| invalid("cors_headers", header))?;
         }
 
-        if let Some(kb_str) = value.strip_suffix(['K', 'k']) {
-            kb_str
-                .parse::<usize>()
-                .ok()
-                .filter(|&kb| kb > 0)
-                .map(|kb| kb * 1024)
-        } else {
-            value.parse::<usize>().ok().filter(|&bytes| bytes > 0)
+        if let Some(pattern) = &self.body_regex {
+            Regex::new(pattern).map_err(|e| format!("invalid body_regex: {e}"))?;
+        }
+        match &self.body_schema {
+            Some(serde_json::Value::String(_)) => {
+                return Err("body_schema must be a JSON Schema object, not a string".to_string());
+            }
+            Some(schema) => {
+                Schema::compile(schema).map_err(|e| format!("invalid body_schema: {e}"))?;
+            }
+            None => {}
+        }
+
+        if self.shard_count() > 1 && self.wal_path.is_some() {
+            return Err("wal_path can't be used with more than one shard".to_string());
+        }
+        if self.wal_path.is_some() && self.snapshot_path.is_some() {
+            return Err("wal_path and snapshot_path can't be used together".to_string());
+        }
+        if self.storage == StorageBackend::Sqlite {
+            if !cfg!(feature = "sqlite") {
+                return Err("the sqlite storage needs a build with the sqlite feature".to_string());
+            }
+            if self.wal_path.is_some() || self.snapshot_path.is_some() {
+                let message = "wal_path and snapshot_path can't be used with sqlite storage";
+                return Err(message.to_string());
+            }
+            if self.shard_count() > 1 {
+                return Err("sqlite storage can't be used with more than one shard".to_string());
+            }
+        }
+        if self.snapshot_interval_secs == 0 {
+            return Err("snapshot_interval_secs must be at least 1".to_string());
+        }
+        match (self.runtime, self.worker_threads) {
+            (_, Some(0)) => return Err("worker_threads must be at least 1".to_string()),
+            (Runtime::CurrentThread, Some(_)) => {
+                return Err("worker_threads needs the multi_thread runtime".to_string());
+            }
+            _ => {}
+        }
+
+        if self.cors && self.cors_allow_credentials {
+            let lists = [
+                ("cors_origins", &self.cors_origins),
+                ("cors_methods", &self.cors_methods),
+                ("cors_headers", &self.cors_headers),
+            ];
+            for (name, list) in lists {
+                let explicit = list.as_ref().is_some_and(|items| !items.iter().any(|item| item == "*"));
+                if !explicit {
+                    return Err(format!(
+                        "cors_allow_credentials requires an explicit {name} list without `*`"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a positive requests-per-second rate; anything else disables the limit.
+    fn parse_rate(value: &str) -> Option<f64> {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|&rate| rate.is_finite() && rate > 0.0)
+    }
+
+    /// Returns the address to listen on. `bind_addr` may be given with or
+    /// without brackets, e.g. `127.0.0.1`, `::1` or `[::]`.
+    pub fn socket_addr(&self) -> Result<SocketAddr, AddrParseError> {
+        let ip = self.bind_addr.trim_start_matches('[').trim_end_matches(']');
+        let ip: IpAddr = ip.parse()?;
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    /// The address used when `bind_addr` is invalid.
+    pub fn default_socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(DEFAULT_BIND_ADDR.parse().unwrap(), self.port)
+    }
+
+    /// Returns how many shards to spread each queue over.
+    pub fn shard_count(&self) -> usize {
+        match self.shards {
+            0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            shards => shards,
+        }
+    }
+
+    /// Returns the settings clients may see.
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            max_message_size: self.max_message_size,
+            max_batch_bytes: self.max_batch_bytes,
+            body_char_limit: self.body_char_limit,
+            reject_blank_bodies: self.reject_blank_bodies,
+            body_regex: self.body_regex.clone(),
+            body_schema: self.body_schema.clone(),
+            max_queue_name_length: MAX_QUEUE_NAME_LEN,
+            default_get_count: self.default_get_count,
+            max_get_count: self.max_get_count,
+            max_wait_secs: MAX_WAIT_SECS,
+            max_batch_ids: self.max_batch_ids,
+            visibility_timeout_secs: self.visibility_timeout_secs,
+            max_retries: self.max_retries,
+            max_message_retries: MAX_MESSAGE_RETRIES,
+            retry_backoff_base_secs: self.retry_backoff_base_secs,
+            retry_backoff_max_secs: self.retry_backoff_max_secs,
+            max_retry_delay_secs: self.max_retry_delay_secs,
+            dedup_window_secs: self.dedup_window_secs,
+            upload_timeout_secs: self.upload_timeout_secs,
+            expired_archive_size: self.expired_archive_size,
+            max_queue_depth: self.max_queue_depth,
+            max_in_flight: self.max_in_flight,
+            strict_delete: self.strict_delete,
+            rate_limit: self.rate_limit,
+            queue_rate_limit: self.queue_rate_limit,
+            auth_required: self.api_key.is_some(),
+        }
+    }
+
+    /// Parses `key=weight` pairs separated by commas, e.g. `10=8,0=1`.
+    fn parse_weights<K: FromStr + Ord>(value: &str) -> Option<BTreeMap<K, u32>> {
+        value
+            .split(',')
+            .map(|pair| {
+                let (key, weight) = pair.split_once('=')?;
+                let weight = weight.trim().parse::<u32>().ok().filter(|&weight| weight > 0)?;
+                Some((key.trim().parse::<K>().ok()?, weight))
+            })
+            .collect()
+    }
+
+    /// Returns how long to hold back a message being retried after
+    /// `retry_count` earlier retries: `base * 2^retry_count`, capped at the max.
+    pub(crate) fn retry_backoff_secs(&self, retry_count: i32) -> u64 {
+        let exponent = retry_count.clamp(0, 63) as u32;
+        self.retry_backoff_base_secs
+            .saturating_mul(1 << exponent)
+            .min(self.retry_backoff_max_secs)
+    }
+
+    /// Returns the `get_any` weight of a queue.
+    pub(crate) fn queue_weight(&self, queue: &str) -> u32 {
+        self.queue_weights.get(queue).copied().unwrap_or(1).max(1)
+    }
+
+    /// Returns the band a priority falls into and that band's weight.
+    /// Priorities below the lowest configured band share a band of weight 1.
+    pub(crate) fn priority_band(&self, priority: u8) -> (u8, u32) {
+        if self.priority_weights.is_empty() {
+            return (priority, u32::from(priority) + 1);
         }
+
+        self.priority_weights
+            .range(..=priority)
+            .next_back()
+            .map(|(&band, &weight)| (band, weight))
+            .unwrap_or((0, 1))
     }
 
     pub fn tracing_level(&self) -> Level {
@@ -76,126 +911,2005 @@ impl Config {
     }
 }
 
+/// Records the outcome of an operation on its span. Ids are only recorded at
+/// trace level, since a batch can carry many of them.
+fn record_outcome(count: usize, ids: impl FnOnce() -> Vec<String>) {
+    let span = Span::current();
+    span.record("count", count);
+    if tracing::enabled!(Level::TRACE) {
+        span.record("ids", ids().join(","));
+    }
+}
+
+/// Returns a message taken for `drain_to` as it was before it was taken.
+fn undelivered(mut message: Message) -> Message {
+    message.state = MessageState::Ready;
+    message.lock_until = None;
+    message.lease_token = None;
+    message.attempts.pop();
+    message
+}
+
+fn message_ids(messages: &[Message]) -> Vec<String> {
+    messages.iter().map(|message| message.id.to_string()).collect()
+}
+
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
 /// Returns a reference to the global `Config` instance.
+///
+/// # Panics
+///
+/// Panics if `SMQL_CONFIG` names a file that can't be read or parsed, or if
+/// the settings fail `Config::validate`.
 pub fn config() -> &'static Config {
-    CONFIG.get_or_init(Config::from_env)
+    #[cfg(test)]
+    if let Some(config) = TEST_CONFIG.with(std::cell::Cell::get) {
+        return config;
+    }
+    CONFIG.get_or_init(|| {
+        let config = match env::var("SMQL_CONFIG") {
+            Ok(path) if !path.is_empty() => Config::from_file(Path::new(&path))
+                .unwrap_or_else(|e| panic!("Failed to load config file {path}: {e}")),
+            _ => Config::from_env(),
+        };
+        if let Err(e) = config.validate() {
+            panic!("Invalid configuration: {e}");
+        }
+        config
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_CONFIG: std::cell::Cell<Option<&'static Config>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Makes `config` return `config` on the current thread, for tests of
+/// settings other than the defaults.
+#[cfg(test)]
+pub(crate) fn set_test_config(config: Config) {
+    TEST_CONFIG.with(|test_config| test_config.set(Some(Box::leak(Box::new(config)))));
 }
 
 // TYPES
 /// Represents the state of a message in the queue.
+///
+/// Serialized as `Ready`, `Processing`, ... by default, or as `ready`,
+/// `processing`, ... with the `lowercase-states` feature. Either spelling is
+/// accepted when deserializing, so write-ahead logs and exports stay readable
+/// when the feature is switched.
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "lowercase-states", serde(rename_all = "lowercase"))]
 pub enum MessageState {
     /// The message is ready to be processed.
+    #[serde(alias = "Ready", alias = "ready")]
     Ready,
     /// The message is currently being processed.
+    #[serde(alias = "Processing", alias = "processing")]
     Processing,
     /// The message has been processed and is done.
+    #[serde(alias = "Done", alias = "done")]
     Done,
+    /// The message exceeded the maximum number of retries and was dead-lettered.
+    #[serde(alias = "Dead", alias = "dead")]
+    Dead,
 }
 
-/// Represents a message in the queue.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Message {
-    pub id: Uuid,
-    pub body: String,
-    pub state: MessageState,
-    pub lock_until: Option<i64>,
-    pub retry_count: i32,
+/// The payload of a message.
+///
+/// Text bodies serialize as a plain JSON string so existing clients are
+/// unaffected; binary bodies serialize as `{"base64": "..."}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MessageBody {
+    Text(String),
+    Binary {
+        #[serde(with = "base64")]
+        base64: Vec<u8>,
+    },
+    /// A large body compressed while stored. Messages handed out by the
+    /// storage never carry one, and it serializes as the original body.
+    #[serde(skip_deserializing, serialize_with = "compress::serialize")]
+    Compressed(CompressedBody),
 }
 
-impl Message {
-    pub fn new(body: String) -> Message {
-        Message {
-            id: Uuid::now_v7(),
-            body,
-            state: MessageState::Ready,
-            lock_until: None,
-            retry_count: 0,
+impl MessageBody {
+    /// Returns the size of the body in bytes, UTF-8 encoded for text.
+    pub fn len(&self) -> usize {
+        match self {
+            MessageBody::Text(text) => text.len(),
+            MessageBody::Binary { base64 } => base64.len(),
+            MessageBody::Compressed(compressed) => compressed.len(),
         }
     }
-}
-
-// SERVICES
-/// The `MessageService` provides the business logic for interacting with the message queue.
-#[derive(Clone)]
-pub struct MessageService {
-    store: Arc<dyn storage::Storage>,
-}
-
-/// Represents the possible errors that can occur in the `MessageService`.
-#[derive(Debug)]
-pub enum Error {
-    /// The message body is larger than the configured maximum size.
-    BodyTooLarge,
-    /// No message IDs were provided for an operation that requires them.
-    NoIds,
-    /// An invalid message ID was provided.
-    InvalidId(String),
-    /// An error occurred in the storage layer.
-    Store(String),
-}
 
-impl From<String> for Error {
-    fn from(s: String) -> Self {
-        Error::Store(s)
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
-}
 
-impl MessageService {
-    /// Creates a new `MessageService` with the given storage implementation.
-    pub fn new(store: Arc<dyn storage::Storage>) -> MessageService {
-        Self { store }
+    /// Returns true if the body is empty or, for text, only whitespace.
+    pub fn is_blank(&self) -> bool {
+        match self {
+            MessageBody::Text(text) => text.trim().is_empty(),
+            MessageBody::Binary { base64 } => base64.is_empty(),
+            MessageBody::Compressed(compressed) => compressed.decompress().is_blank(),
+        }
     }
-}
 
-impl MessageService {
-    pub async fn add(&self, body: String) -> Result<Message, Error> {
-        if body.len() > config().max_message_size {
-            return Err(Error::BodyTooLarge);
+    /// Returns true if a text body contains `needle`. Binary bodies never do.
+    pub fn contains_text(&self, needle: &str) -> bool {
+        match self {
+            MessageBody::Text(text) => text.contains(needle),
+            MessageBody::Binary { .. } => false,
+            MessageBody::Compressed(compressed) => compressed.decompress().contains_text(needle),
         }
-
-        let msg = Message::new(body);
-        self.store.add(msg.clone()).await?;
-        Ok(msg)
     }
 
-    pub async fn get(&self, count: usize) -> Result<Vec<Message>, Error> {
-        Ok(self.store.get(count).await?)
+    /// Returns the body with any storage compression undone.
+    pub fn decompressed(self) -> MessageBody {
+        match self {
+            MessageBody::Compressed(compressed) => compressed.decompress(),
+            body => body,
+        }
     }
 
-    pub async fn delete(&self, ids: Vec<String>) -> Result<(), Error> {
+    /// Parses the body as JSON, binary bodies as UTF-8 text.
+    pub fn to_json(&self) -> serde_json::Result<serde_json::Value> {
+        match self {
+            MessageBody::Text(text) => serde_json::from_str(text),
+            MessageBody::Binary { base64 } => serde_json::from_slice(base64),
+            MessageBody::Compressed(compressed) => compressed.decompress().to_json(),
+        }
+    }
+}
+
+/// Returns true for JSON media types such as `application/json` and
+/// `application/problem+json`, ignoring parameters like `charset`.
+pub fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence == "application/json" || (essence.contains('/') && essence.ends_with("+json"))
+}
+
+impl From<String> for MessageBody {
+    fn from(text: String) -> Self {
+        MessageBody::Text(text)
+    }
+}
+
+impl From<&str> for MessageBody {
+    fn from(text: &str) -> Self {
+        MessageBody::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for MessageBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        MessageBody::Binary { base64: bytes }
+    }
+}
+
+/// Represents a message in the queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub id: Uuid,
+    pub body: MessageBody,
+    /// When the message was created, in unix milliseconds.
+    #[serde(default)]
+    pub created_at: i64,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub state: MessageState,
+    /// While processing, when the lock runs out and the message is redelivered,
+    /// in unix seconds.
+    pub lock_until: Option<i64>,
+    /// While processing under a lease, the token that acks and extends must name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease_token: Option<String>,
+    pub retry_count: i32,
+    pub deliver_at: Option<i64>,
+    pub priority: u8,
+    pub expires_at: Option<i64>,
+    pub dedup_id: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// The most recent deliveries of the message, oldest first, at most
+    /// `MAX_ATTEMPT_HISTORY` of them.
+    #[serde(default)]
+    pub attempts: Vec<DeliveryAttempt>,
+    /// The `X-Request-Id` of the API request that added the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Hex HMAC-SHA256 of the body, made with `hmac_key` or by the producer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Retries allowed before dead-lettering, instead of the configured `max_retries`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<i32>,
+    /// Messages of the same group are delivered one at a time, in order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    /// The body parsed as JSON, for messages with a JSON `content_type`. Parsed
+    /// once when the body is added or amended and stored next to it, since
+    /// `signature` and `body_regex` are over the exact text of `body`, which
+    /// re-encoding the parsed value would not give back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub structured_body: Option<serde_json::Value>,
+}
+
+/// A message dropped because its TTL passed, as kept in the expired archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpiredMessage {
+    #[serde(flatten)]
+    pub message: Message,
+    /// When the message was dropped, in unix milliseconds. Its `expires_at` is
+    /// when it became due to be.
+    pub expired_at: i64,
+}
+
+/// One delivery of a message to a consumer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    /// When the message was delivered, in unix milliseconds.
+    pub delivered_at: i64,
+    /// When the consumer retried or nacked it, in unix milliseconds. Unset
+    /// while processing, and when the lock ran out or the message was requeued.
+    pub retried_at: Option<i64>,
+}
+
+impl Message {
+    /// Creates a message with an id from the configured `IdGenerator`.
+    pub fn new(body: impl Into<MessageBody>) -> Message {
+        Self::with_id(config().id_generator.generate(), body)
+    }
+
+    pub fn with_id(id: Uuid, body: impl Into<MessageBody>) -> Message {
+        Message {
+            id,
+            body: body.into(),
+            created_at: SystemClock.now_millis(),
+            content_type: None,
+            state: MessageState::Ready,
+            lock_until: None,
+            lease_token: None,
+            retry_count: 0,
+            deliver_at: None,
+            priority: 0,
+            expires_at: None,
+            dedup_id: None,
+            attributes: HashMap::new(),
+            attempts: Vec::new(),
+            request_id: None,
+            signature: None,
+            max_retries: None,
+            group_id: None,
+            structured_body: None,
+        }
+    }
+
+    /// Returns the message with `structured_body` parsed again from its body,
+    /// for messages read back from a log or an import, whose `structured_body`
+    /// may be missing or not match the body.
+    pub fn with_structured_body(mut self) -> Message {
+        self.structured_body = None;
+        if self.content_type.as_deref().is_some_and(is_json_content_type) {
+            self.structured_body = self.body.to_json().ok();
+        }
+        self
+    }
+
+    /// Returns how many retries the message may have before it is
+    /// dead-lettered: its own `max_retries`, or the configured one.
+    pub fn retry_limit(&self) -> i32 {
+        self.max_retries.unwrap_or(config().max_retries)
+    }
+
+    /// Records a delivery, forgetting the oldest once the history is full.
+    pub(crate) fn record_delivery(&mut self, now_ms: i64) {
+        if self.attempts.len() >= MAX_ATTEMPT_HISTORY {
+            self.attempts.remove(0);
+        }
+        self.attempts.push(DeliveryAttempt { delivered_at: now_ms, retried_at: None });
+    }
+
+    /// Marks the latest delivery as retried.
+    pub(crate) fn record_retry(&mut self, now_ms: i64) {
+        if let Some(attempt) = self.attempts.last_mut() {
+            attempt.retried_at.get_or_insert(now_ms);
+        }
+    }
+
+    /// Returns how long ago the message was created, in milliseconds.
+    pub fn age_ms(&self) -> u64 {
+        self.age_ms_at(SystemClock.now_millis())
+    }
+
+    /// Returns how long ago the message was last delivered, in milliseconds,
+    /// if it is processing.
+    pub fn in_flight_ms(&self) -> Option<u64> {
+        self.in_flight_ms_at(SystemClock.now_millis())
+    }
+
+    /// Like `age_ms`, as of `now_ms` unix milliseconds.
+    pub(crate) fn age_ms_at(&self, now_ms: i64) -> u64 {
+        (now_ms - self.created_at).max(0) as u64
+    }
+
+    /// Like `in_flight_ms`, as of `now_ms` unix milliseconds.
+    pub(crate) fn in_flight_ms_at(&self, now_ms: i64) -> Option<u64> {
+        if self.state != MessageState::Processing {
+            return None;
+        }
+        let delivered_at = self.attempts.last()?.delivered_at;
+        Some((now_ms - delivered_at).max(0) as u64)
+    }
+}
+
+/// A point-in-time summary of a queue.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueStats {
+    /// Messages waiting to be delivered, including delayed ones.
+    pub ready_count: usize,
+    /// Messages delivered to a consumer and not yet acknowledged.
+    pub processing_count: usize,
+    /// Messages moved to the dead-letter queue.
+    pub dead_letter_count: usize,
+    /// Age of the oldest ready message, if any.
+    pub oldest_message_age_secs: Option<u64>,
+    /// Age of the oldest ready message in milliseconds, if any.
+    pub oldest_message_age_ms: Option<u64>,
+    /// `created_at` of the oldest ready message in unix milliseconds, if any.
+    pub oldest_ready_at: Option<i64>,
+    /// `created_at` of the newest ready message in unix milliseconds, if any.
+    pub newest_ready_at: Option<i64>,
+    /// Messages added to the queue since startup.
+    pub total_added: u64,
+    /// Messages dropped because their TTL passed, since startup.
+    pub total_expired: u64,
+    /// Gets that delivered fewer messages than asked for because the queue was
+    /// at `max_in_flight`, since startup.
+    pub total_throttled: u64,
+    /// Processing messages requeued to stay within `processing_capacity`,
+    /// since startup.
+    pub total_evicted: u64,
+    /// Bodies refused for being over `max_message_size`, since startup.
+    pub total_rejected: u64,
+    /// Whether the server is draining and refusing new messages.
+    pub draining: bool,
+    /// Compressed size over original size of the bodies compressed since
+    /// startup, if any were.
+    pub compression_ratio: Option<f64>,
+    /// Ids of messages processing for longer than `slow_consumer_secs`, longest
+    /// first and at most 100.
+    pub slow_message_ids: Vec<String>,
+}
+
+/// How far `MessageService::drain_to` got.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DrainReport {
+    /// Messages the target took and that were deleted here.
+    pub drained: usize,
+    /// Batches sent to the target.
+    pub batches: usize,
+    /// Messages still ready here, e.g. delayed ones not yet due.
+    pub remaining: usize,
+}
+
+/// The result of deleting or acknowledging messages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeleteOutcome {
+    /// How many messages were removed.
+    pub count: usize,
+    /// Requested ids that were removed, in the order they were given.
+    pub acked: Vec<String>,
+    /// Requested ids that were not processing, e.g. because their lock expired
+    /// and they were returned to the queue.
+    pub not_found: Vec<String>,
+    /// Requested ids that are not UUIDs, so can't name any message.
+    pub invalid: Vec<String>,
+}
+
+/// The result of retrying or nacking messages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetryOutcome {
+    /// How many messages were requeued or dead-lettered.
+    pub count: usize,
+    /// Requested ids that were already ready, e.g. because they were retried
+    /// twice or their lock expired first. A consumer naming these holds a
+    /// stale lease.
+    pub already_ready: Vec<String>,
+    /// Requested ids that were neither processing nor ready, e.g. because they
+    /// were deleted or dead-lettered.
+    pub not_found: Vec<String>,
+}
+
+/// A message together with the queue it was taken from, as returned by
+/// `MessageService::get_any`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub queue: String,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+/// Every message of one queue, as exported by `MessageService::export`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueExport {
+    pub queue: String,
+    pub messages: Vec<Message>,
+}
+
+/// The kind of change a `QueueEvent` describes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueEventKind {
+    Added,
+    Delivered,
+    Deleted,
+    Retried,
+    Purged,
+}
+
+impl QueueEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueueEventKind::Added => "added",
+            QueueEventKind::Delivered => "delivered",
+            QueueEventKind::Deleted => "deleted",
+            QueueEventKind::Retried => "retried",
+            QueueEventKind::Purged => "purged",
+        }
+    }
+}
+
+/// A change to a queue, published to subscribers of `MessageService::subscribe`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEvent {
+    #[serde(rename = "event")]
+    pub kind: QueueEventKind,
+    pub queue: String,
+    /// The affected message; absent for queue-wide events such as purge.
+    pub id: Option<String>,
+    /// The `X-Request-Id` of the API request that caused the change.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Optional settings for a message being added to a queue.
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    /// Seconds to wait before the message becomes deliverable.
+    pub delay_secs: Option<u64>,
+    /// Higher priority messages are delivered first.
+    pub priority: u8,
+    /// Seconds after which the message is dropped if it hasn't been processed.
+    pub ttl_secs: Option<u64>,
+    /// Adds with the same id within the dedup window return the first message.
+    pub dedup_id: Option<String>,
+    /// Key/value metadata delivered alongside the body.
+    pub attributes: HashMap<String, String>,
+    /// The media type of the body, e.g. `application/octet-stream`.
+    pub content_type: Option<String>,
+    /// A UUID to use instead of a generated id. Adding it to a queue that
+    /// already holds a message with this id fails with a conflict.
+    pub id: Option<String>,
+    /// A hex HMAC-SHA256 of the body from the producer. Checked against
+    /// `hmac_key` when one is set, otherwise stored as given.
+    pub signature: Option<String>,
+    /// Retries allowed before dead-lettering, overriding `max_retries`.
+    pub max_retries: Option<i32>,
+    /// While a message of this group is processing, no other message of the
+    /// group is delivered, so each group is consumed in order.
+    pub group_id: Option<String>,
+}
+
+/// A page of ready messages, from `MessageService::peek_page`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    /// How many messages are ready in the whole queue.
+    pub total: usize,
+}
+
+/// Which messages `MessageService::search` returns. Every criterion given
+/// must match; an empty filter matches every message.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchFilter {
+    /// Text the body must contain. Binary bodies never match.
+    pub body_contains: Option<String>,
+    /// Attributes the message must carry, with exactly these values.
+    pub attributes: HashMap<String, String>,
+}
+
+impl SearchFilter {
+    pub fn matches(&self, message: &Message) -> bool {
+        self.attributes
+            .iter()
+            .all(|(key, value)| message.attributes.get(key) == Some(value))
+            && self
+                .body_contains
+                .as_deref()
+                .is_none_or(|needle| message.body.contains_text(needle))
+    }
+}
+
+// SERVICES
+/// The `MessageService` provides the business logic for interacting with the message queue.
+#[derive(Clone)]
+pub struct MessageService {
+    store: Arc<dyn storage::Storage>,
+    events: broadcast::Sender<QueueEvent>,
+    /// While set, adds are refused so queues can be emptied before a restart.
+    draining: Arc<AtomicBool>,
+    /// Smooth weighted round-robin credit per queue, for `get_any`.
+    queue_credit: Arc<std::sync::Mutex<HashMap<String, i64>>>,
+    /// Stamps new messages and their delays and TTLs.
+    clock: SharedClock,
+    /// Bodies being sent in chunks, not yet added.
+    uploads: Arc<Uploads>,
+    /// Oversized bodies refused per queue.
+    rejected: Arc<std::sync::Mutex<HashMap<String, Rejections>>>,
+}
+
+/// Oversized bodies refused on one queue, and when that was last logged.
+#[derive(Default)]
+struct Rejections {
+    count: u64,
+    /// Unix milliseconds of the last warning.
+    warned_at: Option<i64>,
+    /// Rejections since the last warning that were not logged.
+    suppressed: u64,
+}
+
+/// Represents the possible errors that can occur in the `MessageService`.
+#[derive(Debug)]
+pub enum Error {
+    /// The message body and attributes take more bytes than `max_message_size`.
+    BodyTooLarge { size: usize, limit: usize },
+    /// The bodies of a batch take more bytes together than `max_batch_bytes`.
+    BatchTooLarge { size: usize, limit: usize },
+    /// The text body has more characters than `body_char_limit`.
+    BodyTooLong { chars: usize, limit: usize },
+    /// The message body is empty.
+    EmptyBody,
+    /// The queue has reached its configured maximum depth.
+    QueueFull,
+    /// The service is draining and not accepting new messages.
+    Draining,
+    /// No message IDs were provided for an operation that requires them.
+    NoIds,
+    /// Message IDs that are not valid UUIDs were provided.
+    InvalidIds(Vec<String>),
+    /// More message IDs were provided than `max_batch_ids` allows.
+    TooManyIds { count: usize, limit: usize },
+    /// An invalid queue name was provided.
+    InvalidQueueName(String),
+    /// The signature given with a body does not match it under `hmac_key`.
+    InvalidSignature,
+    /// A message's own `max_retries` is negative or above the allowed limit.
+    InvalidMaxRetries { max_retries: i32, limit: i32 },
+    /// A retry or nack asked for a longer delay than `max_retry_delay_secs`.
+    InvalidDelay { delay_secs: u64, limit: u64 },
+    /// The body does not match `body_regex` or `body_schema`.
+    ValidationFailed(String),
+    /// No upload with this id is in progress on the queue, or it was abandoned.
+    UploadNotFound(String),
+    /// The URL of another server is malformed or not plain HTTP.
+    InvalidUrl(String),
+    /// Draining to another server stopped because it couldn't be reached or
+    /// refused a batch. Nothing was lost.
+    DrainStopped { drained: usize, reason: String },
+    /// An error occurred in the storage layer.
+    Store(storage::StorageError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BodyTooLarge { size, limit } => write!(
+                f,
+                "Message body size is too large: {size} bytes, limit is {limit} bytes"
+            ),
+            Error::BatchTooLarge { size, limit } => write!(
+                f,
+                "Batch size is too large: {size} bytes, limit is {limit} bytes"
+            ),
+            Error::BodyTooLong { chars, limit } => write!(
+                f,
+                "Message body is too long: {chars} characters, limit is {limit} characters"
+            ),
+            Error::EmptyBody => write!(f, "Message body is empty"),
+            Error::QueueFull => write!(f, "Queue is full"),
+            Error::Draining => write!(f, "Not accepting messages while draining"),
+            Error::NoIds => write!(f, "No message IDs provided"),
+            Error::InvalidIds(ids) if ids.len() == 1 => write!(f, "Invalid message ID: {}", ids[0]),
+            Error::InvalidIds(ids) => write!(f, "Invalid message IDs: {}", ids.join(", ")),
+            Error::TooManyIds { count, limit } => {
+                write!(f, "Too many message IDs: {count}, limit is {limit}")
+            }
+            Error::InvalidQueueName(name) => write!(f, "Invalid queue name: {name}"),
+            Error::InvalidSignature => write!(f, "Message signature does not match the body"),
+            Error::InvalidMaxRetries { max_retries, limit } => {
+                write!(f, "Invalid max_retries: {max_retries}, must be between 0 and {limit}")
+            }
+            Error::InvalidDelay { delay_secs, limit } => {
+                write!(f, "Invalid delay_secs: {delay_secs}, limit is {limit}")
+            }
+            Error::ValidationFailed(reason) => {
+                write!(f, "Message body failed validation: {reason}")
+            }
+            Error::UploadNotFound(id) => write!(f, "Upload not found: {id}"),
+            Error::InvalidUrl(reason) => write!(f, "{reason}"),
+            Error::DrainStopped { drained, reason } => {
+                write!(f, "Stopped draining after {drained} messages: {reason}")
+            }
+            Error::Store(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<storage::StorageError> for Error {
+    fn from(e: storage::StorageError) -> Self {
+        Error::Store(e)
+    }
+}
+
+impl MessageService {
+    /// Creates a new `MessageService` with the given storage implementation.
+    pub fn new(store: Arc<dyn storage::Storage>) -> MessageService {
+        Self::with_clock(store, Arc::new(SystemClock))
+    }
+
+    /// Creates a new `MessageService` that tells the time with `clock`. Give
+    /// the storage the same clock, e.g. through `MemoryStorage::with_clock`,
+    /// so message timestamps and deadlines agree.
+    pub fn with_clock(store: Arc<dyn storage::Storage>, clock: Arc<dyn Clock>) -> MessageService {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            store,
+            events,
+            draining: Arc::default(),
+            queue_credit: Arc::default(),
+            clock: SharedClock::new(clock),
+            uploads: Arc::default(),
+            rejected: Arc::default(),
+        }
+    }
+}
+
+impl MessageService {
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty, ids = field::Empty))]
+    pub async fn add(
+        &self,
+        queue: &str,
+        body: MessageBody,
+        options: AddOptions,
+    ) -> Result<Message, Error> {
+        Self::validate_queue(queue)?;
+        self.check_accepting()?;
+        let msg = self
+            .build_message(body, &options)
+            .inspect_err(|e| self.note_rejected(queue, e))?;
+        let id = msg.id;
+        let stored = self.store.add(queue, msg).await?.ok_or(Error::QueueFull)?;
+        // A deduplicated add returns an earlier message and adds nothing.
+        if stored.id == id {
+            self.publish(QueueEventKind::Added, queue, [stored.id.to_string()]);
+        }
+        record_outcome(1, || vec![stored.id.to_string()]);
+        Ok(stored)
+    }
+
+    /// Adds several messages at once.
+    ///
+    /// Each body is validated on its own, so an invalid body only fails its own
+    /// entry in the returned list; the valid ones are still added. Bodies taking
+    /// more than `max_batch_bytes` together fail the whole batch.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty, ids = field::Empty))]
+    pub async fn add_batch(
+        &self,
+        queue: &str,
+        bodies: Vec<String>,
+    ) -> Result<Vec<Result<Message, Error>>, Error> {
+        Self::validate_queue(queue)?;
+        self.check_accepting()?;
+        let size: usize = bodies.iter().map(String::len).sum();
+        let limit = config().max_batch_bytes;
+        if size > limit {
+            tracing::warn!(size, limit, "Rejected oversized batch");
+            return Err(Error::BatchTooLarge { size, limit });
+        }
+        let options = AddOptions::default();
+        let results: Vec<Result<Message, Error>> = bodies
+            .into_iter()
+            .map(|body| {
+                self.build_message(body.into(), &options)
+                    .inspect_err(|e| self.note_rejected(queue, e))
+            })
+            .collect();
+
+        let messages: Vec<Message> = results.iter().flatten().cloned().collect();
+        let mut accepted = 0;
+        if !messages.is_empty() {
+            accepted = self.store.add_batch(queue, messages).await?;
+        }
+
+        // The store accepts messages in order until the queue is full.
+        let results: Vec<Result<Message, Error>> = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(message) if accepted > 0 => {
+                    accepted -= 1;
+                    Ok(message)
+                }
+                Ok(_) => Err(Error::QueueFull),
+                Err(e) => Err(e),
+            })
+            .collect();
+
+        let added: Vec<String> = results.iter().flatten().map(|message| message.id.to_string()).collect();
+        record_outcome(added.len(), || added.clone());
+        self.publish(QueueEventKind::Added, queue, added);
+        Ok(results)
+    }
+
+    /// Takes up to `count` messages, long-polling for up to `wait_secs`
+    /// (capped at 20) when none are available.
+    pub async fn get(
+        &self,
+        queue: &str,
+        count: Option<usize>,
+        wait_secs: u64,
+    ) -> Result<Vec<Message>, Error> {
+        self.get_batch(queue, count, None, Duration::from_secs(wait_secs), false, None).await
+    }
+
+    /// Takes up to `max_count` messages once at least `min_count` (default 1)
+    /// are available, waiting up to `wait` (capped at 20 seconds) for them.
+    /// When the wait runs out, returns whatever is available.
+    ///
+    /// With `auto_ack`, the messages are removed as they are taken instead of
+    /// locked until acked. This trades reliability for throughput: a consumer
+    /// that crashes or fails on a message loses it for good, since there is
+    /// nothing left to retry, redeliver or dead-letter. Use it only where
+    /// at-most-once delivery is acceptable.
+    ///
+    /// Otherwise, with `lease_secs`, the messages are locked for that long
+    /// instead of `visibility_timeout_secs`, under a new random `lease_token`
+    /// that `delete_leased` and `extend_lock` must then name.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty, ids = field::Empty))]
+    pub async fn get_batch(
+        &self,
+        queue: &str,
+        max_count: Option<usize>,
+        min_count: Option<usize>,
+        wait: Duration,
+        auto_ack: bool,
+        lease_secs: Option<u64>,
+    ) -> Result<Vec<Message>, Error> {
+        Self::validate_queue(queue)?;
+        let count = Self::read_count(max_count);
+        let wait = wait.min(Duration::from_secs(MAX_WAIT_SECS));
+        let min_count = min_count.unwrap_or(1);
+        let lease = lease_secs.filter(|_| !auto_ack).map(|secs| storage::Lease {
+            token: Uuid::new_v4().to_string(),
+            secs: secs.max(1),
+        });
+        let messages =
+            self.store.get(queue, count, min_count, wait, auto_ack, lease.as_ref()).await?;
+        let delivered = message_ids(&messages);
+        record_outcome(delivered.len(), || delivered.clone());
+        self.publish(QueueEventKind::Delivered, queue, delivered.clone());
+        if auto_ack {
+            self.publish(QueueEventKind::Deleted, queue, delivered);
+        }
+        Ok(messages)
+    }
+
+    /// Takes up to `count` messages from several queues, sharing deliveries
+    /// between them by smooth weighted round-robin using `queue_weights`. `*`
+    /// stands for every existing queue. Does not wait for messages to arrive.
+    pub async fn get_any(
+        &self,
+        queues: &[String],
+        count: Option<usize>,
+    ) -> Result<Vec<QueuedMessage>, Error> {
+        let mut active: Vec<String> = Vec::new();
+        for queue in queues {
+            if queue == "*" {
+                active.extend(self.store.list_queues().await?);
+            } else {
+                Self::validate_queue(queue)?;
+                active.push(queue.clone());
+            }
+        }
+        active.sort();
+        active.dedup();
+
+        let count = Self::read_count(count);
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            let Some(queue) = self.next_weighted_queue(&active) else {
+                break;
+            };
+            match self.store.get(&queue, 1, 1, Duration::ZERO, false, None).await?.pop() {
+                Some(message) => {
+                    self.publish(QueueEventKind::Delivered, &queue, [message.id.to_string()]);
+                    messages.push(QueuedMessage { queue, message });
+                }
+                None => {
+                    active.retain(|active| *active != queue);
+                    self.queue_credit
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .remove(&queue);
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Picks the queue with the most credit after crediting each its weight,
+    /// then charges it the total, so picks follow the weights over time.
+    fn next_weighted_queue(&self, queues: &[String]) -> Option<String> {
+        let config = config();
+        let mut credit = self.queue_credit.lock().unwrap_or_else(|e| e.into_inner());
+        let mut total = 0;
+        let mut best: Option<(&String, i64)> = None;
+        for queue in queues {
+            let weight = i64::from(config.queue_weight(queue));
+            total += weight;
+            let queue_credit = credit.entry(queue.clone()).or_default();
+            *queue_credit += weight;
+            if best.is_none_or(|(_, best_credit)| *queue_credit > best_credit) {
+                best = Some((queue, *queue_credit));
+            }
+        }
+
+        let (queue, _) = best?;
+        *credit.entry(queue.clone()).or_default() -= total;
+        Some(queue.clone())
+    }
+
+    /// Removes processing messages, reporting which of the ids were removed,
+    /// which were not processing and which are not valid ids at all.
+    ///
+    /// With `strict_delete` set, any id that was not removed fails the whole
+    /// delete instead. Messages taken under a lease are not removed; see
+    /// `delete_leased`.
+    pub async fn delete(&self, queue: &str, ids: Vec<String>) -> Result<DeleteOutcome, Error> {
+        self.delete_leased(queue, ids, None).await
+    }
+
+    /// Like `delete`, for messages taken under the lease `lease_token`. Ids
+    /// processing under another lease, or none, are reported as not found.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty, ids = field::Empty))]
+    pub async fn delete_leased(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        lease_token: Option<&str>,
+    ) -> Result<DeleteOutcome, Error> {
+        Self::validate_queue(queue)?;
+        if config().strict_delete {
+            Self::validate_ids(&ids)?;
+        } else {
+            Self::validate_id_count(&ids)?;
+        }
+        let (valid, invalid): (Vec<String>, Vec<String>) =
+            ids.into_iter().partition(|id| Uuid::parse_str(id).is_ok());
+        if !invalid.is_empty() {
+            tracing::warn!(ids = %invalid.join(","), "Skipped invalid message ids");
+        }
+
+        let deleted = if valid.is_empty() {
+            Vec::new()
+        } else {
+            self.store.delete(queue, valid.clone(), lease_token).await?
+        };
+        let deleted_set: HashSet<&String> = deleted.iter().collect();
+        let (acked, not_found) = valid.into_iter().partition(|id| deleted_set.contains(id));
+        let count = deleted.len();
+        record_outcome(count, || deleted.clone());
+        self.publish(QueueEventKind::Deleted, queue, deleted);
+        Ok(DeleteOutcome { count, acked, not_found, invalid })
+    }
+
+    /// Removes every message in the queue, returning how many were removed.
+    pub async fn purge(&self, queue: &str) -> Result<usize, Error> {
+        self.purge_state(queue, None).await
+    }
+
+    /// Removes the messages in `state`, or every message when it is `None`,
+    /// returning how many were removed.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty))]
+    pub async fn purge_state(
+        &self,
+        queue: &str,
+        state: Option<MessageState>,
+    ) -> Result<usize, Error> {
+        Self::validate_queue(queue)?;
+        let purged = match state {
+            Some(state) => self.store.purge_state(queue, state).await?,
+            None => self.store.purge(queue).await?,
+        };
+        Span::current().record("count", purged);
+        tracing::debug!(operation = "purged", queue, count = purged, "Queue purged");
+        self.publish_event(QueueEvent {
+            kind: QueueEventKind::Purged,
+            queue: queue.to_string(),
+            id: None,
+            request_id: current_request_id(),
+        });
+        Ok(purged)
+    }
+
+    /// Returns processing messages to the queue, returning how many were
+    /// requeued or dead-lettered.
+    ///
+    /// With `front`, the messages are redelivered before other ready messages of
+    /// the same priority, in the order their ids were given.
+    pub async fn retry(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        front: bool,
+    ) -> Result<RetryOutcome, Error> {
+        self.retry_after(queue, ids, front, None).await
+    }
+
+    /// Like `retry`, but with `delay_secs` the messages are held back for that
+    /// long instead of for the retry backoff, up to `max_retry_delay_secs`.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty, ids = field::Empty))]
+    pub async fn retry_after(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        front: bool,
+        delay_secs: Option<u64>,
+    ) -> Result<RetryOutcome, Error> {
+        Self::validate_queue(queue)?;
         Self::validate_ids(&ids)?;
-        Ok(self.store.delete(ids).await?)
+        let limit = config().max_retry_delay_secs;
+        if let Some(delay_secs) = delay_secs.filter(|&delay| delay > limit) {
+            return Err(Error::InvalidDelay { delay_secs, limit });
+        }
+        let storage::Retried { ids: retried, already_ready } =
+            self.store.retry(queue, ids.clone(), front, delay_secs).await?;
+        let known: HashSet<&String> = retried.iter().chain(&already_ready).collect();
+        let not_found = ids.into_iter().filter(|id| !known.contains(id)).collect();
+        let count = retried.len();
+        record_outcome(count, || retried.clone());
+        self.publish(QueueEventKind::Retried, queue, retried);
+        Ok(RetryOutcome { count, already_ready, not_found })
+    }
+
+    /// Returns every processing message in the queue to ready, e.g. after a
+    /// consumer died holding messages. Unlike `retry`, this does not count as a
+    /// delivery attempt. Returns how many messages were requeued.
+    pub async fn requeue_all(&self, queue: &str) -> Result<usize, Error> {
+        Self::validate_queue(queue)?;
+        let requeued = self.store.requeue_all(queue).await?;
+        let count = requeued.len();
+        self.publish(QueueEventKind::Retried, queue, requeued);
+        Ok(count)
+    }
+
+    /// Keeps processing messages locked for `extra_secs` longer, so a long job
+    /// isn't redelivered mid-way. Fails without extending anything if any of
+    /// the messages is not processing, or not under `lease_token` (no lease
+    /// when `None`). Returns how many locks were extended.
+    pub async fn extend_lock(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        extra_secs: u64,
+        lease_token: Option<&str>,
+    ) -> Result<usize, Error> {
+        Self::validate_queue(queue)?;
+        Self::validate_ids(&ids)?;
+        Ok(self.store.extend_lock(queue, ids, extra_secs, lease_token).await?)
+    }
+
+    /// Acknowledges successfully processed messages, removing them from the queue.
+    ///
+    /// Reports which of the ids were not processing, like `delete`.
+    pub async fn ack(&self, queue: &str, ids: Vec<String>) -> Result<DeleteOutcome, Error> {
+        self.delete(queue, ids).await
+    }
+
+    /// Negatively acknowledges messages, returning them to the queue for redelivery.
+    ///
+    /// Returns the number of messages requeued or dead-lettered.
+    pub async fn nack(&self, queue: &str, ids: Vec<String>) -> Result<RetryOutcome, Error> {
+        self.retry(queue, ids, false).await
+    }
+
+    /// Subscribes to the stream of queue events. Subscribers that fall behind
+    /// miss events rather than slowing down the queue.
+    pub fn subscribe(&self) -> broadcast::Receiver<QueueEvent> {
+        self.events.subscribe()
+    }
+
+    /// Logs each affected message and publishes an event for it.
+    fn publish(&self, kind: QueueEventKind, queue: &str, ids: impl IntoIterator<Item = String>) {
+        let subscribed = self.events.receiver_count() > 0;
+        for id in ids {
+            tracing::debug!(operation = kind.as_str(), queue, message_id = %id, "Message {}", kind.as_str());
+            if subscribed {
+                self.publish_event(QueueEvent {
+                    kind,
+                    queue: queue.to_string(),
+                    id: Some(id),
+                    request_id: current_request_id(),
+                });
+            }
+        }
+    }
+
+    fn publish_event(&self, event: QueueEvent) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    /// Returns up to `count` ready messages from the head of the queue.
+    pub async fn peek(&self, queue: &str, count: Option<usize>) -> Result<Vec<Message>, Error> {
+        Ok(self.peek_page(queue, 0, count).await?.messages)
+    }
+
+    /// Returns up to `count` ready messages in delivery order without taking
+    /// them, skipping the first `offset`, along with how many are ready in all.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty, ids = field::Empty))]
+    pub async fn peek_page(
+        &self,
+        queue: &str,
+        offset: usize,
+        count: Option<usize>,
+    ) -> Result<MessagePage, Error> {
+        Self::validate_queue(queue)?;
+        let page = self.store.peek(queue, offset, Self::read_count(count)).await?;
+        record_outcome(page.messages.len(), || message_ids(&page.messages));
+        Ok(page)
+    }
+
+    /// Finds ready and processing messages matching `filter` without taking
+    /// them, skipping the first `offset` matches and returning at most `limit`
+    /// (default 100, capped at `max_get_count`). Scans the whole queue, so it
+    /// is meant for debugging and admin use rather than the hot path.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, count = field::Empty, ids = field::Empty))]
+    pub async fn search(
+        &self,
+        queue: &str,
+        filter: &SearchFilter,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<Message>, Error> {
+        Self::validate_queue(queue)?;
+        let limit = limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(config().max_get_count);
+        let messages = self.store.search(queue, filter, offset, limit).await?;
+        record_outcome(messages.len(), || message_ids(&messages));
+        Ok(messages)
     }
 
-    pub async fn purge(&self) -> Result<(), Error> {
-        Ok(self.store.purge().await?)
+    /// Looks up a message by id regardless of its position or state.
+    pub async fn get_by_id(&self, queue: &str, id: String) -> Result<Option<Message>, Error> {
+        Self::validate_queue(queue)?;
+        Self::validate_ids(std::slice::from_ref(&id))?;
+        Ok(self.store.get_by_id(queue, &id).await?)
     }
 
-    pub async fn retry(&self, ids: Vec<String>) -> Result<(), Error> {
+    /// Replaces the body of a ready or processing message, keeping its id,
+    /// retry count and delivery history, e.g. to fix a poison message before
+    /// retrying it. The new body and its signature are checked like those of
+    /// an add. Returns `None` if no such message is ready or processing.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, ids = %id))]
+    pub async fn amend(
+        &self,
+        queue: &str,
+        id: String,
+        body: MessageBody,
+        signature: Option<String>,
+    ) -> Result<Option<Message>, Error> {
+        Self::validate_queue(queue)?;
+        Self::validate_ids(std::slice::from_ref(&id))?;
+        let Some(message) = self.store.get_by_id(queue, &id).await? else {
+            return Ok(None);
+        };
+        Self::check_body(&body, &message.attributes).inspect_err(|e| self.note_rejected(queue, e))?;
+        let structured_body = Self::parse_json(&body, message.content_type.as_deref())?;
+        let signature = Self::signature(&body, signature.as_deref())?;
+        let amended = self.store.amend(queue, &id, body, signature, structured_body).await?;
+        if amended.is_some() {
+            tracing::debug!(operation = "amended", queue, message_id = %id, "Message amended");
+        }
+        Ok(amended)
+    }
+
+    pub async fn dead_letters(
+        &self,
+        queue: &str,
+        count: Option<usize>,
+    ) -> Result<Vec<Message>, Error> {
+        Self::validate_queue(queue)?;
+        Ok(self.store.dead_letters(queue, Self::read_count(count)).await?)
+    }
+
+    /// Returns the most recently expired messages of the queue, newest first.
+    /// Empty unless `expired_archive_size` is set.
+    pub async fn expired(
+        &self,
+        queue: &str,
+        count: Option<usize>,
+    ) -> Result<Vec<ExpiredMessage>, Error> {
+        Self::validate_queue(queue)?;
+        Ok(self.store.expired(queue, Self::read_count(count)).await?)
+    }
+
+    pub async fn requeue_dead_letters(&self, queue: &str, ids: Vec<String>) -> Result<(), Error> {
+        Self::validate_queue(queue)?;
         Self::validate_ids(&ids)?;
-        Ok(self.store.retry(ids).await?)
+        Ok(self.store.requeue_dead_letters(queue, ids).await?)
+    }
+
+    pub async fn stats(&self, queue: &str) -> Result<QueueStats, Error> {
+        Self::validate_queue(queue)?;
+        let mut stats = self.store.stats(queue).await?;
+        stats.draining = self.is_draining();
+        let rejected = self.rejected.lock().unwrap_or_else(|e| e.into_inner());
+        stats.total_rejected = rejected.get(queue).map_or(0, |rejections| rejections.count);
+        Ok(stats)
+    }
+
+    /// Counts a body refused for being over `max_message_size` against
+    /// `queue`. The warning is logged at most once per
+    /// `REJECTION_WARN_INTERVAL` for each queue, so a producer whose every
+    /// message is too large can't flood the log.
+    fn note_rejected(&self, queue: &str, e: &Error) {
+        let Error::BodyTooLarge { size, limit } = *e else {
+            return;
+        };
+        let now = self.clock.now_millis();
+        let mut rejected = self.rejected.lock().unwrap_or_else(|e| e.into_inner());
+        let rejections = rejected.entry(queue.to_string()).or_default();
+        rejections.count += 1;
+        let interval_ms = REJECTION_WARN_INTERVAL.as_millis() as i64;
+        if rejections.warned_at.is_some_and(|warned_at| now - warned_at < interval_ms) {
+            rejections.suppressed += 1;
+            return;
+        }
+        tracing::warn!(
+            queue,
+            size,
+            limit,
+            suppressed = rejections.suppressed,
+            total = rejections.count,
+            "Rejected message body over the size limit"
+        );
+        rejections.warned_at = Some(now);
+        rejections.suppressed = 0;
+    }
+
+    /// Stops accepting new messages until `resume`. Everything else, including
+    /// get, ack and retry, keeps working so consumers can empty the queues.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Accepts new messages again after `drain`.
+    pub fn resume(&self) {
+        self.draining.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    fn check_accepting(&self) -> Result<(), Error> {
+        if self.is_draining() {
+            return Err(Error::Draining);
+        }
+        Ok(())
+    }
+
+    /// Starts a body to be sent in chunks with `append_upload` and added as a
+    /// message with `options` by `complete_upload`. Returns the upload id.
+    pub fn begin_upload(&self, queue: &str, options: AddOptions) -> Result<String, Error> {
+        Self::validate_queue(queue)?;
+        self.check_accepting()?;
+        Ok(self.uploads.begin(queue, options, self.upload_deadline()))
+    }
+
+    /// Appends a chunk to an upload, returning the size of the body so far. A
+    /// chunk that would take the body past `max_message_size` is refused and
+    /// leaves the upload as it was.
+    pub fn append_upload(
+        &self,
+        queue: &str,
+        upload_id: &str,
+        chunk: MessageBody,
+    ) -> Result<usize, Error> {
+        Self::validate_queue(queue)?;
+        let limit = config().max_message_size;
+        let now = self.clock.now_millis();
+        match self.uploads.append(queue, upload_id, chunk, limit, now, self.upload_deadline()) {
+            Some(Ok(size)) => Ok(size),
+            Some(Err(size)) => {
+                let e = Error::BodyTooLarge { size, limit };
+                self.note_rejected(queue, &e);
+                Err(e)
+            }
+            None => Err(Error::UploadNotFound(upload_id.to_string())),
+        }
+    }
+
+    /// Adds the uploaded body as a message, like `add`. If the add fails, the
+    /// upload is kept so it can be completed again.
+    pub async fn complete_upload(&self, queue: &str, upload_id: &str) -> Result<Message, Error> {
+        Self::validate_queue(queue)?;
+        let upload = self
+            .uploads
+            .take(queue, upload_id, self.clock.now_millis())
+            .ok_or_else(|| Error::UploadNotFound(upload_id.to_string()))?;
+        let added = self.add(queue, upload.body(), upload.options.clone()).await;
+        if added.is_err() {
+            self.uploads.restore(queue, upload_id, upload);
+        }
+        added
+    }
+
+    /// Returns when an upload touched now is abandoned, in unix milliseconds.
+    fn upload_deadline(&self) -> i64 {
+        let timeout_ms = config().upload_timeout_secs.saturating_mul(1000);
+        self.clock.now_millis().saturating_add(i64::try_from(timeout_ms).unwrap_or(i64::MAX))
+    }
+
+    pub async fn list_queues(&self) -> Result<Vec<String>, Error> {
+        Ok(self.store.list_queues().await?)
+    }
+
+    /// Registers a consumer group on `queue`, returning the queue's groups.
+    ///
+    /// Every message added to the queue afterwards is copied to each of its
+    /// groups, which consume their copies independently through the
+    /// `queue:group` queue. Messages already in the queue are not copied.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, group = %group))]
+    pub async fn register_group(&self, queue: &str, group: &str) -> Result<Vec<String>, Error> {
+        Self::validate_group(queue, group)?;
+        if self.store.register_group(queue, group).await? {
+            tracing::debug!(operation = "group_registered", queue, group, "Consumer group registered");
+        }
+        Ok(self.store.groups(queue).await?)
+    }
+
+    /// Removes a consumer group from `queue` together with its copies of the
+    /// messages, returning how many copies were dropped.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, group = %group))]
+    pub async fn unregister_group(&self, queue: &str, group: &str) -> Result<usize, Error> {
+        Self::validate_group(queue, group)?;
+        let dropped = self.store.unregister_group(queue, group).await?;
+        tracing::debug!(
+            operation = "group_unregistered",
+            queue,
+            group,
+            count = dropped,
+            "Consumer group removed"
+        );
+        Ok(dropped)
+    }
+
+    pub async fn groups(&self, queue: &str) -> Result<Vec<String>, Error> {
+        Self::validate_queue(queue)?;
+        Ok(self.store.groups(queue).await?)
+    }
+
+    /// Returns the ids of the messages of every queue that have been
+    /// processing for longer than `slow_consumer_secs`, by queue. Queues without
+    /// any are left out.
+    pub async fn slow_messages(&self) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let mut slow = Vec::new();
+        for queue in self.store.list_queues().await? {
+            let ids = self.store.stats(&queue).await?.slow_message_ids;
+            if !ids.is_empty() {
+                slow.push((queue, ids));
+            }
+        }
+        Ok(slow)
+    }
+
+    /// Drops ready messages whose TTL has passed from every queue, returning
+    /// how many were dropped. Abandoned uploads are dropped too.
+    pub async fn remove_expired(&self) -> Result<usize, Error> {
+        let abandoned = self.uploads.remove_expired(self.clock.now_millis());
+        if abandoned > 0 {
+            tracing::debug!(count = abandoned, "Dropped abandoned uploads");
+        }
+        let mut expired = 0;
+        for queue in self.store.list_queues().await? {
+            expired += self.store.remove_expired(&queue).await?;
+        }
+        Ok(expired)
+    }
+
+    /// Returns every message of every queue, whatever its state.
+    pub async fn export(&self) -> Result<Vec<QueueExport>, Error> {
+        Ok(self.store.export().await?)
+    }
+
+    /// Stores previously exported messages, keeping their ids and states.
+    ///
+    /// With `replace`, every existing queue is emptied first; otherwise messages
+    /// whose id is already in their queue are skipped. Returns how many
+    /// messages were imported.
+    pub async fn import(&self, queues: Vec<QueueExport>, replace: bool) -> Result<usize, Error> {
+        for export in &queues {
+            Self::validate_queue(&export.queue)?;
+        }
+        Ok(self.store.import(queues, replace).await?)
+    }
+
+    /// Moves the ready messages of `queue` to the same queue on the SMQL server
+    /// at `url`, `batch` at a time (100 by default), until none are left. Each batch is taken
+    /// under a lease, sent to the target's `/import` and deleted here once the
+    /// target has taken it. Ids are kept, so a batch sent again after an
+    /// interruption is skipped by the target instead of duplicated.
+    ///
+    /// If the target can't be reached or refuses a batch, draining stops with
+    /// `DrainStopped`. The batch in hand returns to the queue when its lease
+    /// runs out, without counting as a retry, and calling again resumes.
+    #[tracing::instrument(level = "debug", skip_all, fields(queue = %queue, url = %url))]
+    pub async fn drain_to(
+        &self,
+        queue: &str,
+        url: &str,
+        batch: Option<usize>,
+        api_key: Option<&str>,
+    ) -> Result<DrainReport, Error> {
+        Self::validate_queue(queue)?;
+        let address = client::server_address(url).map_err(Error::InvalidUrl)?;
+        // The lease outlasts the request, so a slow target can't see a batch
+        // that has already been handed back here.
+        let lease_secs = config().visibility_timeout_secs + DRAIN_REQUEST_TIMEOUT.as_secs();
+        let batch = batch.or(Some(DEFAULT_DRAIN_BATCH));
+        let mut report = DrainReport::default();
+        loop {
+            let messages =
+                self.get_batch(queue, batch, None, Duration::ZERO, false, Some(lease_secs)).await?;
+            let Some(token) = messages.first().and_then(|message| message.lease_token.clone())
+            else {
+                break;
+            };
+            let ids = message_ids(&messages);
+            let export = QueueExport {
+                queue: queue.to_string(),
+                messages: messages.into_iter().map(undelivered).collect(),
+            };
+            let body = serde_json::to_value([export])
+                .map_err(|e| storage::StorageError::Backend(Box::new(e)))?;
+            let path = "/import?mode=append";
+            let sent = client::request(&address, "POST", path, Some(body), api_key);
+            let reason = match tokio::time::timeout(DRAIN_REQUEST_TIMEOUT, sent).await {
+                Ok(Ok((status, _))) if (200..300).contains(&status) => None,
+                Ok(Ok((status, body))) => {
+                    Some(format!("target answered {status}: {}", body.trim_end()))
+                }
+                Ok(Err(e)) => Some(format!("can't reach the target at {address}: {e}")),
+                Err(_) => Some(format!(
+                    "target at {address} did not answer within {} seconds",
+                    DRAIN_REQUEST_TIMEOUT.as_secs()
+                )),
+            };
+            if let Some(reason) = reason {
+                tracing::warn!(queue, drained = report.drained, "Stopped draining: {reason}");
+                return Err(Error::DrainStopped { drained: report.drained, reason });
+            }
+
+            report.drained += self.delete_leased(queue, ids, Some(&token)).await?.count;
+            report.batches += 1;
+            tracing::info!(
+                queue,
+                batches = report.batches,
+                drained = report.drained,
+                "Drained a batch to {address}"
+            );
+        }
+        report.remaining = self.store.stats(queue).await?.ready_count;
+        Ok(report)
+    }
+
+    /// Checks that the storage backend is reachable.
+    pub async fn health_check(&self) -> Result<(), Error> {
+        Ok(self.store.health_check().await?)
+    }
+
+    /// Flushes the storage before exit, returning how many messages were still
+    /// being processed across all queues.
+    pub async fn shutdown(&self) -> Result<usize, Error> {
+        let mut in_flight = 0;
+        for queue in self.store.list_queues().await? {
+            in_flight += self.store.stats(&queue).await?.processing_count;
+        }
+
+        self.store.shutdown().await?;
+        Ok(in_flight)
+    }
+
+    /// Rejects an empty body, or one that with `attributes` is over the size or
+    /// character limits.
+    fn check_body(body: &MessageBody, attributes: &HashMap<String, String>) -> Result<(), Error> {
+        let empty = if config().reject_blank_bodies { body.is_blank() } else { body.is_empty() };
+        if empty {
+            return Err(Error::EmptyBody);
+        }
+
+        // Attributes count towards the size limit so they can't be used to
+        // smuggle unbounded data alongside a small body.
+        let attributes_size: usize = attributes
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        let size = body.len() + attributes_size;
+        let limit = config().max_message_size;
+        if size > limit {
+            return Err(Error::BodyTooLarge { size, limit });
+        }
+
+        if let (MessageBody::Text(text), Some(limit)) = (body, config().body_char_limit) {
+            let chars = text.chars().count();
+            if chars > limit {
+                tracing::warn!(chars, limit, "Rejected message body over the character limit");
+                return Err(Error::BodyTooLong { chars, limit });
+            }
+        }
+
+        Self::check_format(body).inspect_err(|e| tracing::warn!("Rejected message: {e}"))
+    }
+
+    /// Parses the body when `content_type` says it is JSON, rejecting it if it
+    /// doesn't parse. Other bodies give `None`.
+    fn parse_json(
+        body: &MessageBody,
+        content_type: Option<&str>,
+    ) -> Result<Option<serde_json::Value>, Error> {
+        if !content_type.is_some_and(is_json_content_type) {
+            return Ok(None);
+        }
+        body.to_json()
+            .map(Some)
+            .map_err(|e| Error::ValidationFailed(format!("body is not valid JSON: {e}")))
+            .inspect_err(|e| tracing::warn!("Rejected message: {e}"))
+    }
+
+    /// Checks a body against `body_regex` and `body_schema`, when set. Binary
+    /// bodies are checked as UTF-8 text.
+    fn check_format(body: &MessageBody) -> Result<(), Error> {
+        // Both compiled when the config was validated, so they can't fail here.
+        let compile = || {
+            let config = config();
+            let regex = config.body_regex.as_deref().and_then(|regex| Regex::new(regex).ok());
+            let schema = config.body_schema.as_ref().and_then(|value| Schema::compile(value).ok());
+            (regex, schema)
+        };
+        // Tests change the config per thread, so compile it afresh for each.
+        #[cfg(test)]
+        let (regex, schema) = &compile();
+        #[cfg(not(test))]
+        let (regex, schema) = {
+            static RULES: OnceLock<(Option<Regex>, Option<Schema>)> = OnceLock::new();
+            RULES.get_or_init(compile)
+        };
+        if regex.is_none() && schema.is_none() {
+            return Ok(());
+        }
+
+        let text = match body {
+            MessageBody::Text(text) => text.as_str(),
+            MessageBody::Binary { base64 } => std::str::from_utf8(base64)
+                .map_err(|_| Error::ValidationFailed("body is not UTF-8 text".to_string()))?,
+            MessageBody::Compressed(_) => return Self::check_format(&body.clone().decompressed()),
+        };
+        if regex.as_ref().is_some_and(|regex| !regex.is_match(text)) {
+            return Err(Error::ValidationFailed("body does not match body_regex".to_string()));
+        }
+        if let Some(schema) = schema {
+            let value: serde_json::Value = serde_json::from_str(text)
+                .map_err(|e| Error::ValidationFailed(format!("body is not valid JSON: {e}")))?;
+            schema.check(&value).map_err(Error::ValidationFailed)?;
+        }
+        Ok(())
+    }
+
+    fn build_message(&self, body: MessageBody, options: &AddOptions) -> Result<Message, Error> {
+        Self::check_body(&body, &options.attributes)?;
+        let structured_body = Self::parse_json(&body, options.content_type.as_deref())?;
+
+        let mut msg = match &options.id {
+            Some(id) => {
+                let id = Uuid::parse_str(id).map_err(|_| {
+                    tracing::warn!(ids = %id, "Rejected invalid message id");
+                    Error::InvalidIds(vec![id.clone()])
+                })?;
+                Message::with_id(id, body)
+            }
+            None => Message::new(body),
+        };
+        msg.created_at = self.clock.now_millis();
+        msg.priority = options.priority;
+        let now = self.clock.now_secs();
+        if let Some(delay) = options.delay_secs.filter(|&delay| delay > 0) {
+            let delay = i64::try_from(delay).unwrap_or(i64::MAX);
+            msg.deliver_at = Some(now.saturating_add(delay));
+        }
+        if let Some(ttl) = options.ttl_secs {
+            let ttl = i64::try_from(ttl).unwrap_or(i64::MAX);
+            msg.expires_at = Some(now.saturating_add(ttl));
+        }
+        if let Some(max_retries) = options.max_retries {
+            if !(0..=MAX_MESSAGE_RETRIES).contains(&max_retries) {
+                return Err(Error::InvalidMaxRetries { max_retries, limit: MAX_MESSAGE_RETRIES });
+            }
+            msg.max_retries = Some(max_retries);
+        }
+        msg.dedup_id = options.dedup_id.clone();
+        msg.group_id = options.group_id.clone().filter(|group_id| !group_id.is_empty());
+        msg.attributes = options.attributes.clone();
+        msg.content_type = options.content_type.clone();
+        msg.structured_body = structured_body;
+        msg.request_id = current_request_id();
+        msg.signature = Self::signature(&msg.body, options.signature.as_deref())?;
+        Ok(msg)
+    }
+
+    /// Signs a body with `hmac_key`, after checking the producer's signature
+    /// against it if one was given. Without a key, the producer's signature is
+    /// kept as it is.
+    fn signature(body: &MessageBody, given: Option<&str>) -> Result<Option<String>, Error> {
+        let Some(key) = &config().hmac_key else {
+            return Ok(given.map(str::to_string));
+        };
+        if given.is_some_and(|given| !hmac::verify(key.as_bytes(), body, given)) {
+            tracing::warn!("Rejected message with an invalid signature");
+            return Err(Error::InvalidSignature);
+        }
+        Ok(Some(hmac::sign(key.as_bytes(), body)))
+    }
+
+    /// Applies the configured default and cap to a requested read count.
+    /// Counts above `max_get_count` are clamped rather than rejected.
+    fn read_count(count: Option<usize>) -> usize {
+        let config = config();
+        count
+            .unwrap_or(config.default_get_count)
+            .min(config.max_get_count)
+    }
+
+    /// Accepts plain queue names and the `queue:group` names of consumer groups.
+    pub(crate) fn validate_queue(queue: &str) -> Result<(), Error> {
+        let valid = match queue.split_once(GROUP_SEPARATOR) {
+            Some((queue, group)) => Self::is_valid_name(queue) && Self::is_valid_name(group),
+            None => Self::is_valid_name(queue),
+        };
+
+        if !valid {
+            return Err(Error::InvalidQueueName(queue.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Rejects a group name, or a queue name to register a group on, that is not
+    /// a plain name.
+    fn validate_group(queue: &str, group: &str) -> Result<(), Error> {
+        if !Self::is_valid_name(queue) {
+            return Err(Error::InvalidQueueName(queue.to_string()));
+        }
+        if !Self::is_valid_name(group) {
+            return Err(Error::InvalidQueueName(group_queue(queue, group)));
+        }
+        Ok(())
     }
 
-    pub async fn peek(&self, count: usize) -> Result<Vec<Message>, Error> {
-        Ok(self.store.peek(count).await?)
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= MAX_QUEUE_NAME_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
     }
 
+    /// Rejects empty and oversized batches before parsing any id, then reports
+    /// every id that isn't a UUID at once.
     fn validate_ids(ids: &[String]) -> Result<(), Error> {
+        Self::validate_id_count(ids)?;
+
+        let invalid: Vec<String> = ids
+            .iter()
+            .filter(|id| Uuid::parse_str(id).is_err())
+            .cloned()
+            .collect();
+        if !invalid.is_empty() {
+            tracing::warn!(ids = %invalid.join(","), "Rejected invalid message ids");
+            return Err(Error::InvalidIds(invalid));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that some ids were given, but no more than `max_batch_ids`.
+    fn validate_id_count(ids: &[String]) -> Result<(), Error> {
         if ids.is_empty() {
             return Err(Error::NoIds);
         }
 
-        for id in ids {
-            if Uuid::parse_str(id).is_err() {
-                return Err(Error::InvalidId(id.clone()));
-            }
+        let limit = config().max_batch_ids;
+        if ids.len() > limit {
+            return Err(Error::TooManyIds { count: ids.len(), limit });
         }
-
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use clock::MockClock;
+    use storage::MemoryStorage;
+
+    fn service() -> MessageService {
+        MessageService::new(Arc::new(MemoryStorage::new()))
+    }
+
+    #[tokio::test]
+    async fn structured_body_is_stored_with_the_message() {
+        let service = service();
+        let content_type = Some("application/json".to_string());
+        let options = AddOptions { content_type, ..Default::default() };
+        let added = service.add("jobs", r#"{"order": 1}"#.into(), options).await.unwrap();
+        assert_eq!(added.structured_body, Some(json!({"order": 1})));
+
+        let id = added.id.to_string();
+        let found = service.search("jobs", &SearchFilter::default(), 0, None).await.unwrap();
+        assert_eq!(found[0].structured_body, Some(json!({"order": 1})));
+
+        let amended = service.amend("jobs", id.clone(), r#"{"order": 2}"#.into(), None).await;
+        assert_eq!(amended.unwrap().unwrap().structured_body, Some(json!({"order": 2})));
+        let stored = service.get_by_id("jobs", id).await.unwrap().unwrap();
+        assert_eq!(stored.structured_body, Some(json!({"order": 2})));
+        assert_eq!(stored.body, MessageBody::Text(r#"{"order": 2}"#.into()));
+    }
+
+    #[test]
+    fn parse_size_reads_suffixes() {
+        assert_eq!(Config::parse_size("512"), Some(512));
+        assert_eq!(Config::parse_size("4K"), Some(4 * 1024));
+        assert_eq!(Config::parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(Config::parse_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(Config::parse_size("4k"), Some(4 * 1024));
+        assert_eq!(Config::parse_size("2m"), Some(2 * 1024 * 1024));
+        assert_eq!(Config::parse_size("1g"), Some(1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_sizes() {
+        for value in ["", "0", "K", "1.5M", "-1", "99999999999G"] {
+            assert_eq!(Config::parse_size(value), None, "{value:?}");
+        }
+    }
+
+
+    /// Adds a message of each priority, in order, returning their ids.
+    async fn add_priorities(service: &MessageService, priorities: &[u8]) -> Vec<Uuid> {
+        let mut ids = Vec::new();
+        for &priority in priorities {
+            let options = AddOptions { priority, ..Default::default() };
+            ids.push(service.add("jobs", "work".into(), options).await.unwrap().id);
+        }
+        ids
+    }
+
+    /// Takes every ready message one at a time, returning their ids in delivery order.
+    async fn drain_ids(service: &MessageService) -> Vec<Uuid> {
+        let mut ids = Vec::new();
+        while let Some(message) = service.get("jobs", Some(1), 0).await.unwrap().pop() {
+            ids.push(message.id);
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn higher_priorities_are_delivered_first_in_fifo_order() {
+        let service = service();
+        let ids = add_priorities(&service, &[0, 5, 0, 9, 5]).await;
+        assert_eq!(drain_ids(&service).await, [ids[3], ids[1], ids[4], ids[0], ids[2]]);
+    }
+
+
+    #[tokio::test]
+    async fn weighted_schedule_still_serves_low_priorities() {
+        set_test_config(Config { schedule: Schedule::Weighted, ..Config::default() });
+        let service = service();
+        // Without configured weights, priority 2 weighs 3 and priority 0 weighs 1.
+        let ids = add_priorities(&service, &[2, 2, 2, 2, 0, 0, 0, 0]).await;
+
+        let mut delivered = Vec::new();
+        for _ in 0..4 {
+            let taken = service.get("jobs", Some(1), 0).await.unwrap();
+            delivered.extend(taken.into_iter().map(|message| message.id));
+        }
+        let low = delivered.iter().filter(|id| ids[4..].contains(id)).count();
+        assert_eq!(low, 1, "delivered {delivered:?}");
+    }
+
+
+    #[tokio::test]
+    async fn retries_back_off_exponentially_up_to_the_max() {
+        let config =
+            Config { retry_backoff_base_secs: 10, retry_backoff_max_secs: 60, ..Config::default() };
+        let delays: Vec<u64> = (0..5).map(|retries| config.retry_backoff_secs(retries)).collect();
+        assert_eq!(delays, [10, 20, 40, 60, 60]);
+        set_test_config(config);
+
+        let clock = Arc::new(MockClock::now());
+        let store = Arc::new(MemoryStorage::with_clock(clock.clone()));
+        let service = MessageService::with_clock(store, clock.clone());
+        let added = service.add("jobs", "work".into(), AddOptions::default()).await.unwrap();
+        for delay in [10, 20] {
+            service.get("jobs", None, 0).await.unwrap();
+            service.nack("jobs", vec![added.id.to_string()]).await.unwrap();
+            clock.advance(Duration::from_secs(delay - 1));
+            assert!(service.get("jobs", None, 0).await.unwrap().is_empty());
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(service.get("jobs", None, 0).await.unwrap()[0].retry_count, 2);
+    }
+
+
+    #[tokio::test]
+    async fn lifo_delivers_newest_first_within_a_priority() {
+        set_test_config(Config { order: Order::Lifo, ..Config::default() });
+        let service = service();
+        let ids = add_priorities(&service, &[0, 0, 5, 5]).await;
+        let expected = [ids[3], ids[2], ids[1], ids[0]];
+
+        let peeked = service.peek("jobs", Some(10)).await.unwrap();
+        let peeked: Vec<Uuid> = peeked.iter().map(|message| message.id).collect();
+        assert_eq!(peeked, expected);
+        assert_eq!(drain_ids(&service).await, expected);
+    }
 
-    
+
+    #[tokio::test]
+    async fn per_message_max_retries_overrides_the_default() {
+        let service = service();
+        let options = AddOptions { max_retries: Some(1), ..Default::default() };
+        let added = service.add("jobs", "fragile".into(), options).await.unwrap();
+        for _ in 0..2 {
+            assert_eq!(service.get("jobs", None, 0).await.unwrap().len(), 1);
+            service.nack("jobs", vec![added.id.to_string()]).await.unwrap();
+        }
+        assert!(service.get("jobs", None, 0).await.unwrap().is_empty());
+        assert_eq!(service.dead_letters("jobs", None).await.unwrap()[0].id, added.id);
+
+        for max_retries in [-1, MAX_MESSAGE_RETRIES + 1] {
+            let options = AddOptions { max_retries: Some(max_retries), ..Default::default() };
+            let rejected = service.add("jobs", "work".into(), options).await;
+            assert!(matches!(rejected, Err(Error::InvalidMaxRetries { .. })), "{max_retries}");
+        }
+    }
+
+
+    #[tokio::test]
+    async fn retry_to_front_goes_ahead_of_its_priority() {
+        for front in [false, true] {
+            let service = service();
+            let ids = add_priorities(&service, &[0, 0, 0]).await;
+            service.get("jobs", None, 0).await.unwrap();
+            service.retry("jobs", vec![ids[0].to_string()], front).await.unwrap();
+            // Still behind a higher priority added after the retry.
+            let urgent = add_priorities(&service, &[9]).await[0];
+
+            let expected = match front {
+                true => [urgent, ids[0], ids[1], ids[2]],
+                false => [urgent, ids[1], ids[2], ids[0]],
+            };
+            assert_eq!(drain_ids(&service).await, expected, "front: {front}");
+        }
+    }
+
+
+    #[tokio::test]
+    async fn size_counts_bytes_and_char_limit_counts_chars() {
+        let body_char_limit = Some(3);
+        set_test_config(Config { max_message_size: 8, body_char_limit, ..Config::default() });
+        let service = service();
+        let add = |body: &str| service.add("jobs", body.into(), AddOptions::default());
+
+        // Three two-byte characters: 6 bytes, within both limits.
+        assert!(add("ééé").await.is_ok());
+        assert!(matches!(add("ééééé").await, Err(Error::BodyTooLarge { size: 10, limit: 8 })));
+        assert!(matches!(add("éééé").await, Err(Error::BodyTooLong { chars: 4, limit: 3 })));
+        assert!(matches!(add("abcd").await, Err(Error::BodyTooLong { chars: 4, limit: 3 })));
+    }
+
+
+    #[tokio::test]
+    async fn empty_bodies_are_rejected() {
+        let service = service();
+        let add = |body: &str| service.add("jobs", body.into(), AddOptions::default());
+        assert!(matches!(add("").await, Err(Error::EmptyBody)));
+        assert!(add(" \n").await.is_ok());
+        assert!(add("x").await.is_ok());
+
+        set_test_config(Config { reject_blank_bodies: true, ..Config::default() });
+        assert!(matches!(add("").await, Err(Error::EmptyBody)));
+        assert!(matches!(add(" \n").await, Err(Error::EmptyBody)));
+        assert!(add("x").await.is_ok());
+    }
+
+
+    #[tokio::test]
+    async fn every_group_gets_its_own_copy() {
+        let service = service();
+        service.register_group("orders", "billing").await.unwrap();
+        service.register_group("orders", "shipping").await.unwrap();
+        let added = service.add("orders", "order 1".into(), AddOptions::default()).await.unwrap();
+
+        let billing = group_queue("orders", "billing");
+        let shipping = group_queue("orders", "shipping");
+        let taken = service.get(&billing, None, 0).await.unwrap();
+        assert_eq!(taken[0].id, added.id);
+        assert_eq!(service.ack(&billing, vec![added.id.to_string()]).await.unwrap().count, 1);
+
+        // Billing acking its copy leaves shipping's untouched.
+        assert_eq!(service.get(&shipping, None, 0).await.unwrap()[0].id, added.id);
+        assert!(service.get(&billing, None, 0).await.unwrap().is_empty());
+    }
+
+
+    #[tokio::test]
+    async fn leased_messages_need_their_token_and_requeue_when_the_lease_lapses() {
+        let clock = Arc::new(MockClock::now());
+        let store = Arc::new(MemoryStorage::with_clock(clock.clone()));
+        let service = MessageService::with_clock(store, clock.clone());
+        let added = service.add("jobs", "work".into(), AddOptions::default()).await.unwrap();
+        let ids = vec![added.id.to_string()];
+        let take = || service.get_batch("jobs", None, None, Duration::ZERO, false, Some(30));
+
+        let taken = take().await.unwrap();
+        let token = taken[0].lease_token.clone().expect("a lease token");
+        let stale = service.delete_leased("jobs", ids.clone(), Some("stale")).await.unwrap();
+        assert_eq!(stale.count, 0);
+        assert_eq!(service.extend_lock("jobs", ids.clone(), 30, Some(&token)).await.unwrap(), 1);
+
+        // Renewed once, so the lease now runs 60 seconds.
+        clock.advance(Duration::from_secs(59));
+        assert!(take().await.unwrap().is_empty());
+        clock.advance(Duration::from_secs(1));
+        let retaken = take().await.unwrap();
+        assert_ne!(retaken[0].lease_token.as_ref(), Some(&token));
+        let lapsed = service.delete_leased("jobs", ids.clone(), Some(&token)).await.unwrap();
+        assert_eq!(lapsed.count, 0);
+        let token = retaken[0].lease_token.as_deref();
+        assert_eq!(service.delete_leased("jobs", ids, token).await.unwrap().count, 1);
+    }
+
+
+    #[tokio::test]
+    async fn dead_letters_can_be_requeued() {
+        set_test_config(Config { max_retries: 1, ..Config::default() });
+        let service = service();
+        let added = service.add("jobs", "poison".into(), AddOptions::default()).await.unwrap();
+        let ids = vec![added.id.to_string()];
+        for _ in 0..2 {
+            service.get("jobs", None, 0).await.unwrap();
+            service.nack("jobs", ids.clone()).await.unwrap();
+        }
+        let stats = service.stats("jobs").await.unwrap();
+        assert_eq!((stats.ready_count, stats.dead_letter_count), (0, 1));
+        assert_eq!(service.dead_letters("jobs", None).await.unwrap()[0].retry_count, 2);
+
+        service.requeue_dead_letters("jobs", ids).await.unwrap();
+        assert!(service.dead_letters("jobs", None).await.unwrap().is_empty());
+        let requeued = service.get("jobs", None, 0).await.unwrap();
+        assert_eq!((requeued[0].id, requeued[0].retry_count), (added.id, 0));
+    }
+
+
+    #[tokio::test]
+    async fn expired_messages_are_archived_up_to_the_cap() {
+        set_test_config(Config { expired_archive_size: 2, ..Config::default() });
+        let clock = Arc::new(MockClock::now());
+        let store = Arc::new(MemoryStorage::with_clock(clock.clone()));
+        let service = MessageService::with_clock(store, clock.clone());
+        let mut ids = Vec::new();
+        for ttl_secs in [10, 20, 30] {
+            let options = AddOptions { ttl_secs: Some(ttl_secs), ..Default::default() };
+            ids.push(service.add("jobs", "short lived".into(), options).await.unwrap().id);
+        }
+
+        clock.advance(Duration::from_secs(31));
+        assert!(service.get("jobs", None, 0).await.unwrap().is_empty());
+        let expired = service.expired("jobs", Some(10)).await.unwrap();
+        let archived: Vec<Uuid> = expired.iter().map(|expired| expired.message.id).collect();
+        assert_eq!(archived, [ids[2], ids[1]]);
+        assert_eq!(expired[0].expired_at, clock.now_millis());
+        assert_eq!(service.stats("jobs").await.unwrap().total_expired, 3);
+    }
+
+
+    #[tokio::test]
+    async fn batches_have_a_total_limit_apart_from_the_message_limit() {
+        set_test_config(Config { max_message_size: 4, max_batch_bytes: 10, ..Config::default() });
+        let service = service();
+        let batch = |bodies: &[&str]| {
+            service.add_batch("jobs", bodies.iter().map(|body| body.to_string()).collect())
+        };
+
+        let results = batch(&["abcd", "efghi", "j"]).await.unwrap();
+        assert!(results[0].is_ok() && results[2].is_ok());
+        assert!(matches!(results[1], Err(Error::BodyTooLarge { size: 5, limit: 4 })));
+
+        let rejected = batch(&["abcd", "efgh", "ijk"]).await;
+        assert!(matches!(rejected, Err(Error::BatchTooLarge { size: 11, limit: 10 })));
+        assert_eq!(service.stats("jobs").await.unwrap().ready_count, 2);
+    }
+
+
+    #[tokio::test]
+    async fn adds_with_the_same_dedup_id_return_the_first_message() {
+        let clock = Arc::new(MockClock::now());
+        let store = Arc::new(MemoryStorage::with_clock(clock.clone()));
+        let service = MessageService::with_clock(store, clock.clone());
+        let add = |dedup_id: &str| {
+            let options = AddOptions { dedup_id: Some(dedup_id.to_string()), ..Default::default() };
+            service.add("jobs", "work".into(), options)
+        };
+        let first = add("order-1").await.unwrap();
+        assert_eq!(add("order-1").await.unwrap().id, first.id);
+        assert_ne!(add("order-2").await.unwrap().id, first.id);
+        assert_eq!(service.stats("jobs").await.unwrap().ready_count, 2);
+
+        let window = Duration::from_secs(config().dedup_window_secs);
+        clock.advance(window - Duration::from_millis(1));
+        assert_eq!(add("order-1").await.unwrap().id, first.id);
+        clock.advance(Duration::from_millis(1));
+        let second = add("order-1").await.unwrap();
+        assert_ne!(second.id, first.id);
+
+        // A zero window turns deduplication off.
+        set_test_config(Config { dedup_window_secs: 0, ..Config::default() });
+        assert_ne!(add("order-1").await.unwrap().id, second.id);
+        assert_eq!(service.stats("jobs").await.unwrap().ready_count, 4);
+    }
+
+    #[tokio::test]
+    async fn purged_messages_can_be_added_again() {
+        let service = service();
+        let add = |dedup_id: &str| {
+            let options = AddOptions { dedup_id: Some(dedup_id.to_string()), ..Default::default() };
+            service.add("jobs", "work".into(), options)
+        };
+        let first = add("order-1").await.unwrap();
+        service.purge("jobs").await.unwrap();
+        let second = add("order-1").await.unwrap();
+        assert_ne!(second.id, first.id);
+
+        let kept = add("order-2").await.unwrap();
+        service.get("jobs", None, 0).await.unwrap();
+        assert_eq!(service.purge_state("jobs", Some(MessageState::Ready)).await.unwrap(), 1);
+        assert_ne!(add("order-2").await.unwrap().id, kept.id);
+        // The processing message wasn't purged, so its dedup id still holds.
+        assert_eq!(add("order-1").await.unwrap().id, second.id);
+    }
 }
@@ -1,36 +1,237 @@
+mod cli;
+
+use std::future::IntoFuture;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use smql::api::create_api;
-use smql::{config, MessageService};
-use smql::storage::MemoryStorage;
-use tracing::info;
+use smql::{config, snapshot, LogFormat, MessageService, Runtime};
+use smql::storage::{MemoryStorage, ShardedStorage, Storage};
+#[cfg(feature = "sqlite")]
+use smql::{storage::SqliteStorage, StorageBackend};
+use tokio::signal;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
-    filter::LevelFilter, layer::Layer, layer::SubscriberExt, util::SubscriberInitExt,
+    filter::LevelFilter, fmt::format::FmtSpan, layer::Layer, layer::SubscriberExt,
+    util::SubscriberInitExt,
 };
 
-#[tokio::main]
-async fn main() {
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+fn main() {
+    let invocation = match cli::parse(std::env::args().skip(1).collect()) {
+        Ok(invocation) => invocation,
+        Err(e) => {
+            cli::usage_error(&e);
+            std::process::exit(2);
+        }
+    };
+
+    let runtime = build_runtime().unwrap_or_else(|e| panic!("Failed to start the runtime: {e}"));
+    match invocation {
+        Some(invocation) => std::process::exit(runtime.block_on(cli::run(invocation))),
+        None => runtime.block_on(serve()),
+    }
+}
+
+/// Builds the runtime chosen by `runtime` and `worker_threads`.
+fn build_runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    let cfg = config();
+    let mut builder = match cfg.runtime {
+        Runtime::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+        Runtime::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+    };
+    if let Some(threads) = cfg.worker_threads {
+        builder.worker_threads(threads);
+    }
+    builder.enable_all().build()
+}
+
+async fn serve() {
     let cfg = config();
 
+    let fmt_layer = match cfg.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed(),
+    };
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_filter(LevelFilter::from_level(cfg.tracing_level())),
-        )
+        .with(fmt_layer.with_filter(LevelFilter::from_level(cfg.tracing_level())))
         .init();
 
     info!(
-        "Starting SMQL with configuration: port={}, max_message_size={}, log_level={}",
-        cfg.port, cfg.max_message_size, cfg.log_level
+        bind_addr = %cfg.bind_addr,
+        port = cfg.port,
+        max_message_size = cfg.max_message_size,
+        log_level = %cfg.log_level,
+        visibility_timeout_secs = cfg.visibility_timeout_secs,
+        max_retries = cfg.max_retries,
+        runtime = ?cfg.runtime,
+        "Starting SMQL"
     );
 
-    let store = Arc::new(MemoryStorage::new());
+    let store: Arc<dyn Storage> = match &cfg.wal_path {
+        #[cfg(feature = "sqlite")]
+        _ if cfg.storage == StorageBackend::Sqlite => {
+            let path = &cfg.sqlite_path;
+            let store = SqliteStorage::open(Path::new(path))
+                .unwrap_or_else(|e| panic!("Failed to open SQLite database at {path}: {e}"));
+            info!("Recovered queues from SQLite database at {}", path);
+            Arc::new(store)
+        }
+        Some(path) => {
+            let store = MemoryStorage::with_wal(Path::new(path))
+                .unwrap_or_else(|e| panic!("Failed to open write-ahead log at {path}: {e}"));
+            info!("Recovered queues from write-ahead log at {}", path);
+            Arc::new(store)
+        }
+        None if cfg.shard_count() > 1 => {
+            info!(shards = cfg.shard_count(), "Spreading queues over shards");
+            Arc::new(ShardedStorage::new(cfg.shard_count()))
+        }
+        None => Arc::new(MemoryStorage::new()),
+    };
     let service = MessageService::new(store);
+    if let Some(path) = &cfg.snapshot_path {
+        let restored = snapshot::load(&service, Path::new(path))
+            .await
+            .unwrap_or_else(|e| panic!("Failed to restore snapshot from {path}: {e}"));
+        info!(count = restored, "Restored queues from snapshot at {}", path);
+        tokio::spawn(save_snapshots(service.clone(), path.clone()));
+    }
+    tokio::spawn(sweep_expired(service.clone()));
+    if cfg.slow_consumer_secs > 0 {
+        tokio::spawn(report_slow_consumers(service.clone()));
+    }
 
-    let app = create_api(service);
-    let bind_addr = format!("[::]:{}", cfg.port);
+    let app = create_api(service.clone());
+    let bind_addr = cfg.socket_addr().unwrap_or_else(|e| {
+        let fallback = cfg.default_socket_addr();
+        warn!("Invalid SMQL_BIND_ADDR {:?} ({}), binding to {} instead", cfg.bind_addr, e, fallback);
+        fallback
+    });
     let listener = tokio::net::TcpListener::bind(bind_addr).await.unwrap();
 
     info!("Listening on {}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app).await.unwrap();
+    // The drain timeout starts with the shutdown signal, not with the server.
+    let (signalled, on_signal) = oneshot::channel();
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            let _ = signalled.send(());
+        })
+        .into_future();
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => result.unwrap(),
+        _ = on_signal => {
+            let drain_timeout = Duration::from_secs(cfg.shutdown_timeout_secs);
+            match tokio::time::timeout(drain_timeout, server).await {
+                Ok(result) => {
+                    result.unwrap();
+                    info!("Drained connections cleanly");
+                }
+                Err(_) => warn!(
+                    timeout_secs = cfg.shutdown_timeout_secs,
+                    "Timed out draining connections, closing the rest"
+                ),
+            }
+        }
+    }
+
+    if let Some(path) = &cfg.snapshot_path {
+        match snapshot::save(&service, Path::new(path)).await {
+            Ok(count) => info!(count, "Saved snapshot to {}", path),
+            Err(e) => error!("Failed to save snapshot to {}: {}", path, e),
+        }
+    }
+
+    match service.shutdown().await {
+        Ok(in_flight) => info!(count = in_flight, "Shut down with messages still in flight"),
+        Err(e) => error!("Failed to shut down storage cleanly: {}", e),
+    }
+}
+
+/// Saves every queue to the snapshot at `path` every `snapshot_interval_secs`.
+async fn save_snapshots(service: MessageService, path: String) {
+    let period = Duration::from_secs(config().snapshot_interval_secs);
+    // The first snapshot is due one period after startup, not at once.
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        match snapshot::save(&service, Path::new(&path)).await {
+            Ok(count) => debug!(count, "Saved snapshot to {}", path),
+            Err(e) => error!("Failed to save snapshot to {}: {}", path, e),
+        }
+    }
+}
+
+/// Periodically drops messages whose TTL has passed, so they are collected
+/// even from queues nobody is reading.
+async fn sweep_expired(service: MessageService) {
+    let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match service.remove_expired().await {
+            Ok(0) => {}
+            Ok(expired) => debug!(operation = "expired", count = expired, "Dropped expired messages"),
+            Err(e) => error!("Failed to drop expired messages: {}", e),
+        }
+    }
+}
+
+/// Periodically warns about messages that have been processing for longer
+/// than `slow_consumer_secs`, which usually means a consumer is stuck.
+async fn report_slow_consumers(service: MessageService) {
+    let cfg = config();
+    let mut interval = tokio::time::interval(Duration::from_secs(cfg.slow_consumer_scan_secs));
+    loop {
+        interval.tick().await;
+        match service.slow_messages().await {
+            Ok(slow) => {
+                for (queue, ids) in slow {
+                    warn!(
+                        queue = %queue,
+                        count = ids.len(),
+                        ids = %ids.join(","),
+                        threshold_secs = cfg.slow_consumer_secs,
+                        "Messages processing longer than the slow consumer threshold"
+                    );
+                }
+            }
+            Err(e) => error!("Failed to scan for slow consumers: {}", e),
+        }
+    }
+}
+
+/// Resolves once the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.unwrap();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutdown signal received, draining connections");
 }
@@ -0,0 +1,208 @@
+use crate::DEFAULT_QUEUE;
+use axum::extract::Request;
+use axum::response::{IntoResponse, Response};
+use skyak_axum_core::errors::ApiError;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tower::{Layer, Service};
+
+/// Past this many tracked queues, buckets that have refilled are forgotten so
+/// requests to made-up queue names can't grow the map without bound.
+const MAX_TRACKED_QUEUES: usize = 10_000;
+
+/// A `tower::Layer` that answers 429 once requests exceed a rate limit.
+///
+/// Limits are in requests per second, with bursts of up to one second's worth
+/// of requests. The global limit is shared by every route the layer wraps; the
+/// per-queue limit applies separately to each queue named in the path, with
+/// unscoped routes counting against the default queue. Clones share their state.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+struct Limiter {
+    global: Option<Mutex<TokenBucket>>,
+    per_queue: Option<f64>,
+    queues: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimitLayer {
+    /// Creates a layer with the given global and per-queue limits. Limits that
+    /// are unset or not positive are not enforced.
+    pub fn new(global: Option<f64>, per_queue: Option<f64>) -> Self {
+        let global = global.filter(|&rate| rate > 0.0);
+        let per_queue = per_queue.filter(|&rate| rate > 0.0);
+        Self {
+            limiter: Arc::new(Limiter {
+                global: global.map(|rate| Mutex::new(TokenBucket::new(rate))),
+                per_queue,
+                queues: Mutex::default(),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+/// The service produced by `RateLimitLayer`.
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl Limiter {
+    /// Takes a token from the global bucket and from the bucket of the request's
+    /// queue, returning false if either is empty.
+    fn try_acquire(&self, request: &Request) -> bool {
+        let now = Instant::now();
+        if let Some(rate) = self.per_queue {
+            let queue = queue_name(request.uri().path());
+            let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+            if queues.len() >= MAX_TRACKED_QUEUES {
+                queues.retain(|_, bucket| !bucket.is_full(now));
+            }
+            let bucket = queues
+                .entry(queue.to_string())
+                .or_insert_with(|| TokenBucket::new(rate));
+            if !bucket.try_take(now) {
+                return false;
+            }
+        }
+
+        match &self.global {
+            Some(bucket) => bucket.lock().unwrap_or_else(|e| e.into_inner()).try_take(now),
+            None => true,
+        }
+    }
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if self.limiter.try_acquire(&request) {
+            Box::pin(self.inner.call(request))
+        } else {
+            let response =
+                ApiError::TooManyRequests(Some("Rate limit exceeded".to_string())).into_response();
+            Box::pin(async move { Ok(response) })
+        }
+    }
+}
+
+/// Returns the queue a request path is scoped to, e.g. `jobs` for `/queues/jobs/add`.
+fn queue_name(path: &str) -> &str {
+    path.strip_prefix("/queues/")
+        .and_then(|rest| rest.split('/').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or(DEFAULT_QUEUE)
+}
+
+/// Refills at `rate` tokens per second up to a capacity of `rate`, and at least one.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate.max(1.0),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.rate.max(1.0)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity());
+        self.refilled_at = now;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity()
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    async fn status(app: &Router, path: &str) -> StatusCode {
+        let request = Request::post(path).body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    fn app(layer: RateLimitLayer) -> Router {
+        Router::new()
+            .route("/add", post(|| async { "added" }))
+            .route("/queues/{name}/add", post(|| async { "added" }))
+            .layer(layer)
+    }
+
+    #[tokio::test]
+    async fn per_queue_limits_are_separate() {
+        let app = app(RateLimitLayer::new(None, Some(1.0)));
+        assert_eq!(status(&app, "/queues/jobs/add").await, StatusCode::OK);
+        assert_eq!(status(&app, "/queues/jobs/add").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(&app, "/queues/mail/add").await, StatusCode::OK);
+        // Unscoped routes count against the default queue.
+        assert_eq!(status(&app, "/add").await, StatusCode::OK);
+        let default_queue = format!("/queues/{DEFAULT_QUEUE}/add");
+        assert_eq!(status(&app, &default_queue).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn the_global_limit_is_shared_by_every_queue() {
+        let app = app(RateLimitLayer::new(Some(2.0), None));
+        assert_eq!(status(&app, "/queues/jobs/add").await, StatusCode::OK);
+        assert_eq!(status(&app, "/queues/mail/add").await, StatusCode::OK);
+        assert_eq!(status(&app, "/add").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,548 @@
+//! A small regular expression matcher for `body_regex` and schema `pattern`s.
+//!
+//! Supports literals, `.`, classes (`[a-z]`, `[^0-9]`), the escapes `\d \w \s`
+//! and their negations, anchors `^ $`, groups `( )` and `(?: )`, alternation
+//! `|` and the quantifiers `* + ? {n} {n,} {n,m}`. Patterns are compiled to an
+//! NFA that is simulated over the text, so matching takes time linear in the
+//! text whatever the pattern, and a hostile body can't make it backtrack.
+
+/// Most instructions a compiled pattern may take, so a pattern like
+/// `(a{1000}){1000}` is refused instead of exhausting memory.
+const MAX_PROGRAM_LEN: usize = 10_000;
+
+/// Largest count a `{n,m}` quantifier may give.
+const MAX_REPEAT: u32 = 1_000;
+
+/// A compiled pattern.
+#[derive(Debug, Clone)]
+pub(crate) struct Regex {
+    program: Vec<Inst>,
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    /// Consumes one character in the class.
+    Class(Class),
+    /// Continues at both targets.
+    Split(usize, usize),
+    Jump(usize),
+    /// Matches only at the start of the text.
+    Start,
+    /// Matches only at the end of the text.
+    End,
+    Match,
+}
+
+/// A set of characters, as inclusive ranges of code points.
+#[derive(Debug, Clone)]
+struct Class {
+    ranges: Vec<(u32, u32)>,
+    negated: bool,
+}
+
+impl Class {
+    fn single(c: char) -> Self {
+        Self { ranges: vec![(c as u32, c as u32)], negated: false }
+    }
+
+    /// `.`: anything but a line feed.
+    fn any() -> Self {
+        Self { ranges: vec![('\n' as u32, '\n' as u32)], negated: true }
+    }
+
+    /// Returns the character if the class is exactly one.
+    fn as_char(&self) -> Option<u32> {
+        match self.ranges.as_slice() {
+            [(from, to)] if from == to && !self.negated => Some(*from),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        let c = c as u32;
+        self.ranges.iter().any(|&(from, to)| from <= c && c <= to) != self.negated
+    }
+
+    /// Returns the ranges of the class with any negation applied.
+    fn resolved(&self) -> Vec<(u32, u32)> {
+        if !self.negated {
+            return self.ranges.clone();
+        }
+        let mut ranges = self.ranges.clone();
+        ranges.sort_unstable();
+        let mut complement = Vec::new();
+        let mut next = 0;
+        for (from, to) in ranges {
+            if from > next {
+                complement.push((next, from - 1));
+            }
+            next = next.max(to.saturating_add(1));
+        }
+        if next <= char::MAX as u32 {
+            complement.push((next, char::MAX as u32));
+        }
+        complement
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Empty,
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node: Box<Node>, min: u32, max: Option<u32> },
+}
+
+impl Regex {
+    /// Compiles `pattern`, or says what is wrong with it.
+    pub(crate) fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0 };
+        let node = parser.alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err("unmatched `)`".to_string());
+        }
+        let mut program = Vec::new();
+        compile(&node, &mut program)?;
+        program.push(Inst::Match);
+        Ok(Regex { program })
+    }
+
+    /// Returns true if the pattern matches anywhere in `text`. Use `^` and `$`
+    /// to match the whole text.
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let mut current = Vec::new();
+        let mut next = Vec::new();
+        let mut seen = vec![false; self.program.len()];
+        let mut next_seen = vec![false; self.program.len()];
+        for pos in 0..=chars.len() {
+            // Start a new attempt at every position.
+            self.add(&mut current, &mut seen, 0, pos == 0, pos == chars.len());
+            if current.iter().any(|&pc| matches!(self.program[pc], Inst::Match)) {
+                return true;
+            }
+            let Some(&c) = chars.get(pos) else {
+                break;
+            };
+
+            next_seen.fill(false);
+            for &pc in &current {
+                if let Inst::Class(class) = &self.program[pc] {
+                    if class.matches(c) {
+                        self.add(&mut next, &mut next_seen, pc + 1, false, pos + 1 == chars.len());
+                    }
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+            std::mem::swap(&mut seen, &mut next_seen);
+            next.clear();
+        }
+        false
+    }
+
+    /// Adds the threads reachable from `pc` without consuming a character.
+    fn add(
+        &self,
+        list: &mut Vec<usize>,
+        seen: &mut [bool],
+        pc: usize,
+        at_start: bool,
+        at_end: bool,
+    ) {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if std::mem::replace(&mut seen[pc], true) {
+                continue;
+            }
+            match &self.program[pc] {
+                Inst::Jump(to) => stack.push(*to),
+                Inst::Split(first, second) => {
+                    stack.push(*second);
+                    stack.push(*first);
+                }
+                Inst::Start if at_start => stack.push(pc + 1),
+                Inst::End if at_end => stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Class(_) | Inst::Match => list.push(pc),
+            }
+        }
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) -> Result<(), String> {
+    if program.len() > MAX_PROGRAM_LEN {
+        return Err("pattern is too large".to_string());
+    }
+    match node {
+        Node::Empty => {}
+        Node::Class(class) => program.push(Inst::Class(class.clone())),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program)?;
+            }
+        }
+        Node::Alternate(nodes) => {
+            // Split to each branch in turn, every branch jumping past the rest.
+            let mut jumps = Vec::new();
+            for (i, node) in nodes.iter().enumerate() {
+                let split = program.len();
+                if i + 1 < nodes.len() {
+                    program.push(Inst::Split(split + 1, 0));
+                }
+                compile(node, program)?;
+                if i + 1 < nodes.len() {
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    let after = program.len();
+                    program[split] = Inst::Split(split + 1, after);
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat { node, min, max } => {
+            for _ in 0..*min {
+                compile(node, program)?;
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program)?;
+                    program.push(Inst::Jump(split));
+                    let end = program.len();
+                    program[split] = Inst::Split(split + 1, end);
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        compile(node, program)?;
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+    if program.len() > MAX_PROGRAM_LEN {
+        return Err("pattern is too large".to_string());
+    }
+    Ok(())
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let matched = self.peek() == Some(c);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concatenation()?];
+        while self.eat('|') {
+            branches.push(self.concatenation()?);
+        }
+        Ok(if branches.len() == 1 { branches.remove(0) } else { Node::Alternate(branches) })
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.remove(0),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn quantified(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.counted()? {
+                    Some(bounds) => bounds,
+                    None => return Ok(node),
+                },
+                _ => return Ok(node),
+            };
+            // Step past the operator, or the closing brace of a counted one.
+            self.pos += 1;
+            if matches!(node, Node::Start | Node::End | Node::Empty) {
+                return Err("repetition of nothing".to_string());
+            }
+            // A lazy quantifier matches the same texts, which is all that counts here.
+            self.eat('?');
+            node = Node::Repeat { node: Box::new(node), min, max };
+        }
+    }
+
+    /// Parses `{n}`, `{n,}` or `{n,m}` up to, not including, the closing
+    /// brace. Returns `None`, consuming nothing, if the brace doesn't start
+    /// one, so it is taken literally.
+    fn counted(&mut self) -> Result<Option<(u32, Option<u32>)>, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let number = |parser: &mut Parser| {
+            let from = parser.pos;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.pos += 1;
+            }
+            let digits: String = parser.chars[from..parser.pos].iter().collect();
+            digits.parse::<u32>().ok()
+        };
+        let Some(min) = number(self) else {
+            self.pos = start;
+            return Ok(None);
+        };
+        let max = if self.eat(',') { number(self) } else { Some(min) };
+        if self.peek() != Some('}') {
+            self.pos = start;
+            return Ok(None);
+        }
+        if min > MAX_REPEAT || max.is_some_and(|max| max > MAX_REPEAT) {
+            return Err(format!("repetition count above {MAX_REPEAT}"));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(format!("invalid repetition {{{min},{}}}", max.unwrap_or_default()));
+        }
+        Ok(Some((min, max)))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let Some(c) = self.next() else {
+            return Ok(Node::Empty);
+        };
+        match c {
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return Err("unsupported group flag, only `(?:` is supported".to_string());
+                }
+                let node = self.alternation()?;
+                if !self.eat(')') {
+                    return Err("unclosed `(`".to_string());
+                }
+                Ok(node)
+            }
+            '[' => self.class().map(Node::Class),
+            '.' => Ok(Node::Class(Class::any())),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '*' | '+' | '?' => Err(format!("`{c}` must follow something to repeat")),
+            '\\' => self.escape().map(Node::Class),
+            c => Ok(Node::Class(Class::single(c))),
+        }
+    }
+
+    /// Parses the escape after a backslash.
+    fn escape(&mut self) -> Result<Class, String> {
+        let c = self.next().ok_or("trailing backslash")?;
+        let perl = |ranges: &[(char, char)], negated| Class {
+            ranges: ranges.iter().map(|&(from, to)| (from as u32, to as u32)).collect(),
+            negated,
+        };
+        const DIGIT: &[(char, char)] = &[('0', '9')];
+        const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+        const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+        Ok(match c {
+            'd' => perl(DIGIT, false),
+            'D' => perl(DIGIT, true),
+            'w' => perl(WORD, false),
+            'W' => perl(WORD, true),
+            's' => perl(SPACE, false),
+            'S' => perl(SPACE, true),
+            'n' => Class::single('\n'),
+            'r' => Class::single('\r'),
+            't' => Class::single('\t'),
+            c if c.is_ascii_alphanumeric() => return Err(format!("unsupported escape \\{c}")),
+            c => Class::single(c),
+        })
+    }
+
+    /// Parses a bracketed class after its `[`.
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next().ok_or("unclosed `[`")?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let from = if c == '\\' {
+                let escaped = self.escape()?;
+                match escaped.as_char() {
+                    Some(from) => from,
+                    None => {
+                        ranges.extend(escaped.resolved());
+                        continue;
+                    }
+                }
+            } else {
+                c as u32
+            };
+            let to = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                match self.next().ok_or("unclosed `[`")? {
+                    '\\' => self.escape()?.as_char().ok_or("invalid class range")?,
+                    c => c as u32,
+                }
+            } else {
+                from
+            };
+            if to < from {
+                return Err("invalid class range".to_string());
+            }
+            ranges.push((from, to));
+        }
+        Ok(Class { ranges, negated })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn anchors_match_only_at_the_ends() {
+        assert!(matches("^abc$", "abc"));
+        assert!(!matches("^abc$", "abcd"));
+        assert!(!matches("^abc$", "xabc"));
+        assert!(matches("^ab", "abc"));
+        assert!(!matches("^bc", "abc"));
+        assert!(matches("bc$", "abc"));
+        assert!(!matches("ab$", "abc"));
+        assert!(matches("^$", ""));
+        assert!(!matches("^$", "a"));
+    }
+
+    #[test]
+    fn unanchored_patterns_match_anywhere() {
+        assert!(matches("b+", "abbbc"));
+        assert!(matches("needle", "haystack with a needle in it"));
+        assert!(!matches("needle", "haystack"));
+        assert!(matches("", "anything"));
+        assert!(matches("x*", ""));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches("^[a-c]+$", "abcabc"));
+        assert!(!matches("^[a-c]+$", "abcd"));
+        assert!(matches("^[^0-9]+$", "abc"));
+        assert!(!matches("^[^0-9]+$", "ab1"));
+        assert!(matches("^[-a]+$", "-a-"));
+        assert!(matches("^[a-]+$", "a-a"));
+        assert!(matches("^[]a]+$", "]a"));
+        assert!(matches(r"^[\d_]+$", "12_3"));
+        assert!(matches(r"^[^\s]+$", "no-spaces"));
+        assert!(!matches(r"^[^\s]+$", "a space"));
+        assert!(matches(r"^\d{3}-\w+\s\S$", "123-abc_9 x"));
+        assert!(!matches(r"^\D$", "7"));
+        assert!(!matches(r"^\W$", "a"));
+        assert!(matches(r"^\.\*\[$", ".*["));
+        assert!(matches("^.$", "é"));
+        assert!(!matches("^.$", "\n"));
+        assert!(matches(r"^a\tb\nc$", "a\tb\nc"));
+    }
+
+    #[test]
+    fn alternation_and_groups() {
+        assert!(matches("^(cat|dog)s?$", "dogs"));
+        assert!(matches("^(cat|dog)s?$", "cat"));
+        assert!(!matches("^(cat|dog)s?$", "cow"));
+        assert!(matches("^(?:a|b|c)+$", "abcba"));
+        assert!(matches("^a|b$", "ax"));
+        assert!(matches("^a|b$", "xb"));
+        assert!(!matches("^a|b$", "xa"));
+        assert!(matches("^(a|)$", ""));
+    }
+
+    #[test]
+    fn repetition_bounds() {
+        let pattern = "^a{2,3}$";
+        assert!(!matches(pattern, "a"));
+        assert!(matches(pattern, "aa"));
+        assert!(matches(pattern, "aaa"));
+        assert!(!matches(pattern, "aaaa"));
+        assert!(matches("^a{2}$", "aa"));
+        assert!(!matches("^a{2}$", "aaa"));
+        assert!(matches("^a{2,}$", "aaaaa"));
+        assert!(!matches("^a{2,}$", "a"));
+        assert!(matches("^a{0}b$", "b"));
+        assert!(matches("^(ab){2}$", "abab"));
+        assert!(matches("^a+?b*?c??$", "aab"));
+        // A brace that doesn't start a count is taken literally.
+        assert!(matches("^a{,2}$", "a{,2}"));
+        assert!(matches("^a{x}$", "a{x}"));
+    }
+
+    #[test]
+    fn hostile_patterns_match_in_linear_time() {
+        let text = "a".repeat(5_000);
+        assert!(!matches("^(a+)+b$", &text));
+        assert!(!matches("^(a|a)*b$", &text));
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused() {
+        let errors = [
+            ("(abc", "unclosed `(`"),
+            ("abc)", "unmatched `)`"),
+            ("[abc", "unclosed `[`"),
+            ("[z-a]", "invalid class range"),
+            ("*a", "`*` must follow something to repeat"),
+            ("a|+", "`+` must follow something to repeat"),
+            ("^*", "repetition of nothing"),
+            ("a\\", "trailing backslash"),
+            (r"\b", r"unsupported escape \b"),
+            ("(?i)a", "unsupported group flag, only `(?:` is supported"),
+            ("a{3,2}", "invalid repetition {3,2}"),
+            ("a{1001}", "repetition count above 1000"),
+            ("(a{1000}){1000}", "pattern is too large"),
+        ];
+        for (pattern, expected) in errors {
+            assert_eq!(Regex::new(pattern).unwrap_err(), expected, "{pattern:?}");
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! A subset of JSON Schema for `body_schema`.
+//!
+//! Supports `type`, `enum`, `const`, `properties`, `required`,
+//! `additionalProperties`, `items`, `minItems`, `maxItems`, `minLength`,
+//! `maxLength`, `pattern`, `minimum`, `maximum`, `exclusiveMinimum`,
+//! `exclusiveMaximum`, `allOf`, `anyOf`, `oneOf` and `not`. Any other keyword
+//! is refused when the schema is compiled rather than silently ignored, so a
+//! schema never looks stricter than it is.
+
+use crate::regex::Regex;
+use serde_json::{Map, Value};
+
+/// Keywords that only describe a schema and are accepted without effect.
+const ANNOTATIONS: &[&str] =
+    &["$schema", "$id", "$comment", "title", "description", "default", "examples"];
+
+/// A compiled schema.
+#[derive(Debug, Clone)]
+pub(crate) enum Schema {
+    /// `true` accepts anything, `false` nothing.
+    Bool(bool),
+    Rules(Box<Rules>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rules {
+    types: Option<Vec<String>>,
+    allowed: Option<Vec<Value>>,
+    properties: Vec<(String, Schema)>,
+    required: Vec<String>,
+    additional_properties: Option<Schema>,
+    items: Option<Schema>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    pattern: Option<(String, Regex)>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    exclusive_minimum: Option<f64>,
+    exclusive_maximum: Option<f64>,
+    all_of: Vec<Schema>,
+    any_of: Vec<Schema>,
+    one_of: Vec<Schema>,
+    not: Option<Schema>,
+}
+
+const TYPES: &[&str] = &["null", "boolean", "object", "array", "number", "integer", "string"];
+
+impl Schema {
+    /// Compiles a schema, or says which keyword is wrong and where.
+    pub(crate) fn compile(schema: &Value) -> Result<Schema, String> {
+        Self::compile_at(schema, "")
+    }
+
+    fn compile_at(schema: &Value, path: &str) -> Result<Schema, String> {
+        let keywords = match schema {
+            Value::Bool(accept) => return Ok(Schema::Bool(*accept)),
+            Value::Object(keywords) => keywords,
+            _ => return Err(format!("{}: a schema must be an object or a boolean", at(path))),
+        };
+
+        let mut rules = Rules::default();
+        for (keyword, value) in keywords {
+            let here = format!("{path}/{keyword}");
+            let invalid = |expected: &str| format!("{}: must be {expected}", at(&here));
+            let count = |value: &Value| {
+                value.as_u64().map(|count| count as usize).ok_or_else(|| invalid("a whole number"))
+            };
+            let number = |value: &Value| value.as_f64().ok_or_else(|| invalid("a number"));
+            let schemas = |value: &Value| {
+                let list = value.as_array().filter(|list| !list.is_empty());
+                let list = list.ok_or_else(|| invalid("a non-empty array of schemas"))?;
+                list.iter()
+                    .enumerate()
+                    .map(|(i, schema)| Self::compile_at(schema, &format!("{here}/{i}")))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            match keyword.as_str() {
+                "type" => {
+                    let types = match value {
+                        Value::String(name) => vec![name.clone()],
+                        Value::Array(names) => names
+                            .iter()
+                            .map(|name| name.as_str().map(str::to_string))
+                            .collect::<Option<Vec<_>>>()
+                            .ok_or_else(|| invalid("a type name or an array of them"))?,
+                        _ => return Err(invalid("a type name or an array of them")),
+                    };
+                    let unknown = types.iter().find(|name| !TYPES.contains(&name.as_str()));
+                    if let Some(unknown) = unknown {
+                        return Err(format!("{}: unknown type {unknown:?}", at(&here)));
+                    }
+                    rules.types = Some(types);
+                }
+                "enum" => {
+                    let values = value.as_array().ok_or_else(|| invalid("an array"))?;
+                    rules.allowed = Some(values.clone());
+                }
+                "const" => rules.allowed = Some(vec![value.clone()]),
+                "properties" => {
+                    let properties = value.as_object().ok_or_else(|| invalid("an object"))?;
+                    for (name, schema) in properties {
+                        let schema = Self::compile_at(schema, &format!("{here}/{name}"))?;
+                        rules.properties.push((name.clone(), schema));
+                    }
+                }
+                "required" => {
+                    rules.required = value
+                        .as_array()
+                        .and_then(|names| {
+                            names.iter().map(|name| name.as_str().map(str::to_string)).collect()
+                        })
+                        .ok_or_else(|| invalid("an array of property names"))?;
+                }
+                "additionalProperties" => {
+                    rules.additional_properties = Some(Self::compile_at(value, &here)?);
+                }
+                "items" => rules.items = Some(Self::compile_at(value, &here)?),
+                "minItems" => rules.min_items = Some(count(value)?),
+                "maxItems" => rules.max_items = Some(count(value)?),
+                "minLength" => rules.min_length = Some(count(value)?),
+                "maxLength" => rules.max_length = Some(count(value)?),
+                "pattern" => {
+                    let pattern = value.as_str().ok_or_else(|| invalid("a string"))?;
+                    let regex = Regex::new(pattern)
+                        .map_err(|e| format!("{}: invalid pattern: {e}", at(&here)))?;
+                    rules.pattern = Some((pattern.to_string(), regex));
+                }
+                "minimum" => rules.minimum = Some(number(value)?),
+                "maximum" => rules.maximum = Some(number(value)?),
+                "exclusiveMinimum" => rules.exclusive_minimum = Some(number(value)?),
+                "exclusiveMaximum" => rules.exclusive_maximum = Some(number(value)?),
+                "allOf" => rules.all_of = schemas(value)?,
+                "anyOf" => rules.any_of = schemas(value)?,
+                "oneOf" => rules.one_of = schemas(value)?,
+                "not" => rules.not = Some(Self::compile_at(value, &here)?),
+                keyword if ANNOTATIONS.contains(&keyword) => {}
+                keyword => {
+                    return Err(format!("{}: unsupported keyword {keyword:?}", at(path)));
+                }
+            }
+        }
+        Ok(Schema::Rules(Box::new(rules)))
+    }
+
+    /// Checks a value against the schema, returning the first mismatch and
+    /// where in the value it is.
+    pub(crate) fn check(&self, value: &Value) -> Result<(), String> {
+        self.check_at(value, "")
+    }
+
+    fn check_at(&self, value: &Value, path: &str) -> Result<(), String> {
+        let rules = match self {
+            Schema::Bool(true) => return Ok(()),
+            Schema::Bool(false) => return Err(format!("{}: not allowed", at(path))),
+            Schema::Rules(rules) => rules,
+        };
+        let fail = |reason: String| Err(format!("{}: {reason}", at(path)));
+
+        if let Some(types) = &rules.types {
+            if !types.iter().any(|name| is_type(value, name)) {
+                return fail(format!("expected {}, got {}", types.join(" or "), type_name(value)));
+            }
+        }
+        if let Some(allowed) = &rules.allowed {
+            if !allowed.contains(value) {
+                return fail("not one of the allowed values".to_string());
+            }
+        }
+
+        match value {
+            Value::Object(object) => rules.check_object(object, path)?,
+            Value::Array(items) => {
+                if let Some(min) = rules.min_items.filter(|&min| items.len() < min) {
+                    return fail(format!("fewer than {min} items"));
+                }
+                if let Some(max) = rules.max_items.filter(|&max| items.len() > max) {
+                    return fail(format!("more than {max} items"));
+                }
+                if let Some(schema) = &rules.items {
+                    for (i, item) in items.iter().enumerate() {
+                        schema.check_at(item, &format!("{path}/{i}"))?;
+                    }
+                }
+            }
+            Value::String(text) => {
+                let chars = text.chars().count();
+                if let Some(min) = rules.min_length.filter(|&min| chars < min) {
+                    return fail(format!("shorter than {min} characters"));
+                }
+                if let Some(max) = rules.max_length.filter(|&max| chars > max) {
+                    return fail(format!("longer than {max} characters"));
+                }
+                if let Some((pattern, regex)) = &rules.pattern {
+                    if !regex.is_match(text) {
+                        return fail(format!("does not match {pattern:?}"));
+                    }
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                if let Some(min) = rules.minimum.filter(|&min| number < min) {
+                    return fail(format!("below the minimum of {min}"));
+                }
+                if let Some(max) = rules.maximum.filter(|&max| number > max) {
+                    return fail(format!("above the maximum of {max}"));
+                }
+                if let Some(min) = rules.exclusive_minimum.filter(|&min| number <= min) {
+                    return fail(format!("not above the exclusive minimum of {min}"));
+                }
+                if let Some(max) = rules.exclusive_maximum.filter(|&max| number >= max) {
+                    return fail(format!("not below the exclusive maximum of {max}"));
+                }
+            }
+            Value::Null | Value::Bool(_) => {}
+        }
+
+        for schema in &rules.all_of {
+            schema.check_at(value, path)?;
+        }
+        let matches = |schema: &&Schema| schema.check_at(value, path).is_ok();
+        if !rules.any_of.is_empty() && !rules.any_of.iter().any(|schema| matches(&schema)) {
+            return fail("matches none of anyOf".to_string());
+        }
+        if !rules.one_of.is_empty() {
+            let matching = rules.one_of.iter().filter(matches).count();
+            if matching != 1 {
+                return fail(format!("matches {matching} of oneOf, not exactly one"));
+            }
+        }
+        if rules.not.as_ref().is_some_and(|schema| schema.check_at(value, path).is_ok()) {
+            return fail("matches the schema under not".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Rules {
+    fn check_object(&self, object: &Map<String, Value>, path: &str) -> Result<(), String> {
+        if let Some(missing) = self.required.iter().find(|name| !object.contains_key(*name)) {
+            return Err(format!("{}: missing required property {missing:?}", at(path)));
+        }
+        for (name, value) in object {
+            let here = format!("{path}/{name}");
+            match self.properties.iter().find(|(property, _)| property == name) {
+                Some((_, schema)) => schema.check_at(value, &here)?,
+                None => {
+                    if let Some(schema) = &self.additional_properties {
+                        schema.check_at(value, &here)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names a place in the body by JSON pointer, `/` being the whole body.
+fn at(path: &str) -> String {
+    if path.is_empty() {
+        "/".to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        name => type_name(value) == name,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Compiles `schema` and checks `value` against it.
+    fn check(schema: Value, value: Value) -> Result<(), String> {
+        Schema::compile(&schema).unwrap().check(&value)
+    }
+
+    #[test]
+    fn type_keyword() {
+        assert!(check(json!({"type": "string"}), json!("a")).is_ok());
+        assert_eq!(
+            check(json!({"type": "string"}), json!(1)).unwrap_err(),
+            "/: expected string, got number"
+        );
+        assert!(check(json!({"type": ["null", "boolean"]}), json!(null)).is_ok());
+        assert!(check(json!({"type": "integer"}), json!(2.0)).is_ok());
+        assert!(check(json!({"type": "integer"}), json!(2.5)).is_err());
+        assert!(check(json!({"type": "number"}), json!(2)).is_ok());
+    }
+
+    #[test]
+    fn enum_and_const() {
+        let schema = json!({"enum": ["red", 1, null]});
+        assert!(check(schema.clone(), json!(1)).is_ok());
+        assert_eq!(check(schema, json!("blue")).unwrap_err(), "/: not one of the allowed values");
+        assert!(check(json!({"const": {"a": 1}}), json!({"a": 1})).is_ok());
+        assert!(check(json!({"const": {"a": 1}}), json!({"a": 2})).is_err());
+    }
+
+    #[test]
+    fn object_keywords() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"id": {"type": "integer"}},
+            "required": ["id"],
+            "additionalProperties": {"type": "string"},
+        });
+        assert!(check(schema.clone(), json!({"id": 1, "note": "x"})).is_ok());
+        assert_eq!(
+            check(schema.clone(), json!({"note": "x"})).unwrap_err(),
+            r#"/: missing required property "id""#
+        );
+        assert_eq!(
+            check(schema.clone(), json!({"id": "1"})).unwrap_err(),
+            "/id: expected integer, got string"
+        );
+        assert_eq!(
+            check(schema, json!({"id": 1, "note": 2})).unwrap_err(),
+            "/note: expected string, got number"
+        );
+        let closed = json!({"properties": {"id": {}}, "additionalProperties": false});
+        assert_eq!(check(closed, json!({"id": 1, "x": 0})).unwrap_err(), "/x: not allowed");
+    }
+
+    #[test]
+    fn array_keywords() {
+        let schema = json!({"items": {"type": "number"}, "minItems": 1, "maxItems": 2});
+        assert!(check(schema.clone(), json!([1, 2])).is_ok());
+        assert_eq!(check(schema.clone(), json!([])).unwrap_err(), "/: fewer than 1 items");
+        assert_eq!(check(schema.clone(), json!([1, 2, 3])).unwrap_err(), "/: more than 2 items");
+        assert_eq!(check(schema, json!([1, "2"])).unwrap_err(), "/1: expected number, got string");
+    }
+
+    #[test]
+    fn string_keywords() {
+        let schema = json!({"minLength": 2, "maxLength": 3, "pattern": "^[a-z]+$"});
+        assert!(check(schema.clone(), json!("abc")).is_ok());
+        // Lengths count characters, not bytes.
+        assert!(check(schema.clone(), json!("éé")).is_err());
+        assert!(check(json!({"maxLength": 2}), json!("éé")).is_ok());
+        assert_eq!(check(schema.clone(), json!("a")).unwrap_err(), "/: shorter than 2 characters");
+        assert_eq!(
+            check(schema.clone(), json!("abcd")).unwrap_err(),
+            "/: longer than 3 characters"
+        );
+        assert_eq!(check(schema, json!("AB")).unwrap_err(), r#"/: does not match "^[a-z]+$""#);
+        // Keywords for other types don't apply.
+        assert!(check(json!({"minLength": 2}), json!(1)).is_ok());
+    }
+
+    #[test]
+    fn number_keywords() {
+        let schema = json!({"minimum": 1, "maximum": 10});
+        assert!(check(schema.clone(), json!(1)).is_ok());
+        assert!(check(schema.clone(), json!(10)).is_ok());
+        assert_eq!(check(schema.clone(), json!(0)).unwrap_err(), "/: below the minimum of 1");
+        assert_eq!(check(schema, json!(11)).unwrap_err(), "/: above the maximum of 10");
+        let exclusive = json!({"exclusiveMinimum": 1, "exclusiveMaximum": 10});
+        assert!(check(exclusive.clone(), json!(5)).is_ok());
+        assert_eq!(
+            check(exclusive.clone(), json!(1)).unwrap_err(),
+            "/: not above the exclusive minimum of 1"
+        );
+        assert_eq!(
+            check(exclusive, json!(10)).unwrap_err(),
+            "/: not below the exclusive maximum of 10"
+        );
+    }
+
+    #[test]
+    fn combining_keywords() {
+        let all_of = json!({"allOf": [{"type": "integer"}, {"minimum": 5}]});
+        assert!(check(all_of.clone(), json!(5)).is_ok());
+        assert!(check(all_of, json!(4)).is_err());
+
+        let any_of = json!({"anyOf": [{"type": "string"}, {"type": "null"}]});
+        assert!(check(any_of.clone(), json!(null)).is_ok());
+        assert_eq!(check(any_of, json!(1)).unwrap_err(), "/: matches none of anyOf");
+
+        let one_of = json!({"oneOf": [{"type": "integer"}, {"minimum": 5}]});
+        assert!(check(one_of.clone(), json!(1)).is_ok());
+        assert_eq!(
+            check(one_of.clone(), json!(7)).unwrap_err(),
+            "/: matches 2 of oneOf, not exactly one"
+        );
+        assert_eq!(
+            check(one_of, json!(2.5)).unwrap_err(),
+            "/: matches 0 of oneOf, not exactly one"
+        );
+
+        let not = json!({"not": {"type": "null"}});
+        assert!(check(not.clone(), json!(0)).is_ok());
+        assert_eq!(check(not, json!(null)).unwrap_err(), "/: matches the schema under not");
+    }
+
+    #[test]
+    fn boolean_schemas() {
+        assert!(check(json!(true), json!({"anything": [1]})).is_ok());
+        assert_eq!(check(json!(false), json!(null)).unwrap_err(), "/: not allowed");
+    }
+
+    #[test]
+    fn errors_point_at_nested_values() {
+        let schema = json!({
+            "properties": {
+                "orders": {"items": {"properties": {"qty": {"minimum": 1}}}},
+            },
+        });
+        let value = json!({"orders": [{"qty": 1}, {"qty": 0}]});
+        assert_eq!(check(schema, value).unwrap_err(), "/orders/1/qty: below the minimum of 1");
+    }
+
+    #[test]
+    fn annotations_are_accepted() {
+        let schema = json!({"$schema": "x", "title": "Order", "description": "", "default": 1});
+        assert!(check(schema, json!(0)).is_ok());
+    }
+
+    #[test]
+    fn invalid_schemas_are_refused() {
+        let errors = [
+            (json!("string"), "/: a schema must be an object or a boolean"),
+            (json!({"format": "email"}), r#"/: unsupported keyword "format""#),
+            (
+                json!({"properties": {"a": {"$ref": "#"}}}),
+                r#"/properties/a: unsupported keyword "$ref""#,
+            ),
+            (json!({"type": "text"}), r#"/type: unknown type "text""#),
+            (json!({"type": 1}), "/type: must be a type name or an array of them"),
+            (json!({"minLength": -1}), "/minLength: must be a whole number"),
+            (json!({"maximum": "10"}), "/maximum: must be a number"),
+            (json!({"anyOf": []}), "/anyOf: must be a non-empty array of schemas"),
+            (json!({"allOf": [{}, 3]}), "/allOf/1: a schema must be an object or a boolean"),
+            (json!({"required": [1]}), "/required: must be an array of property names"),
+            (json!({"pattern": "(a"}), "/pattern: invalid pattern: unclosed `(`"),
+        ];
+        for (schema, expected) in errors {
+            assert_eq!(Schema::compile(&schema).unwrap_err(), expected, "{schema}");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Periodic snapshots of every queue, a lighter way than the write-ahead log
+//! to keep messages across restarts at the cost of losing whatever changed
+//! since the last snapshot.
+
+use crate::{MessageService, QueueExport, GROUP_SEPARATOR};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// Everything needed to rebuild the queues.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    queues: Vec<QueueExport>,
+    /// Consumer groups by the queue they are registered on.
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Writes every message and consumer group to `path`, returning how many
+/// messages were written. The snapshot is written to a temporary file and
+/// renamed into place, so a crash mid-write leaves the previous one intact.
+pub async fn save(service: &MessageService, path: &Path) -> io::Result<usize> {
+    let queues = service.export().await.map_err(io::Error::other)?;
+    // A group's queue exists even while the queue it is registered on doesn't.
+    let names: BTreeSet<&str> = queues
+        .iter()
+        .map(|export| export.queue.split(GROUP_SEPARATOR).next().unwrap_or(&export.queue))
+        .collect();
+    let mut groups = BTreeMap::new();
+    for name in names {
+        let registered = service.groups(name).await.map_err(io::Error::other)?;
+        if !registered.is_empty() {
+            groups.insert(name.to_string(), registered);
+        }
+    }
+    let count = queues.iter().map(|export| export.messages.len()).sum();
+
+    let snapshot = Snapshot { queues, groups };
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || write(&snapshot, &path)).await??;
+    Ok(count)
+}
+
+/// Restores the messages and consumer groups saved at `path`, returning how
+/// many messages were restored. A missing file restores nothing.
+pub async fn load(service: &MessageService, path: &Path) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))?;
+
+    // Groups first, so their queues exist before the copies are put back.
+    for (queue, groups) in &snapshot.groups {
+        for group in groups {
+            service.register_group(queue, group).await.map_err(io::Error::other)?;
+        }
+    }
+    service.import(snapshot.queues, false).await.map_err(io::Error::other)
+}
+
+fn write(snapshot: &Snapshot, path: &Path) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut tmp, snapshot)?;
+    tmp.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::{group_queue, AddOptions, MessageBody, MessageState, QueueStats};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn service() -> MessageService {
+        MessageService::new(Arc::new(MemoryStorage::new()))
+    }
+
+    fn counts(stats: QueueStats) -> (usize, usize, usize) {
+        (stats.ready_count, stats.processing_count, stats.dead_letter_count)
+    }
+
+    #[tokio::test]
+    async fn restores_every_state_and_group() {
+        let original = service();
+        let dead = AddOptions { max_retries: Some(0), ..Default::default() };
+        let dead = original.add("jobs", "dead".into(), dead).await.unwrap();
+        original.get("jobs", None, 0).await.unwrap();
+        original.retry("jobs", vec![dead.id.to_string()], false).await.unwrap();
+        original.add("jobs", "processing".into(), AddOptions::default()).await.unwrap();
+        let processing = original.get("jobs", None, 0).await.unwrap().remove(0);
+        original.add("jobs", "ready".into(), AddOptions::default()).await.unwrap();
+        // Once a group is registered, new messages go to its queue instead.
+        original.register_group("jobs", "billing").await.unwrap();
+        original.add("jobs", "billed".into(), AddOptions::default()).await.unwrap();
+        let billing = group_queue("jobs", "billing");
+        assert_eq!(counts(original.stats("jobs").await.unwrap()), (1, 1, 1));
+        assert_eq!(counts(original.stats(&billing).await.unwrap()), (1, 0, 0));
+
+        let path = std::env::temp_dir().join(format!("smql-test-{}.json", Uuid::new_v4()));
+        assert_eq!(save(&original, &path).await.unwrap(), 4);
+        let restored = service();
+        assert_eq!(load(&restored, &path).await.unwrap(), 4);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.groups("jobs").await.unwrap(), ["billing"]);
+        assert_eq!(counts(restored.stats("jobs").await.unwrap()), (1, 1, 1));
+        assert_eq!(counts(restored.stats(&billing).await.unwrap()), (1, 0, 0));
+        let ready = restored.peek("jobs", Some(10)).await.unwrap();
+        assert_eq!(ready[0].body, MessageBody::Text("ready".into()));
+        let found = restored.get_by_id("jobs", processing.id.to_string()).await.unwrap().unwrap();
+        assert_eq!(found.state, MessageState::Processing);
+        assert_eq!(found.lock_until, processing.lock_until);
+        let dead_letters = restored.dead_letters("jobs", None).await.unwrap();
+        assert_eq!(dead_letters[0].id, dead.id);
+
+        // The restored group still receives what is added to the queue.
+        restored.add("jobs", "after".into(), AddOptions::default()).await.unwrap();
+        assert_eq!(restored.stats(&billing).await.unwrap().ready_count, 2);
+    }
+
+    #[tokio::test]
+    async fn missing_file_restores_nothing() {
+        let path = std::env::temp_dir().join(format!("smql-test-{}.json", Uuid::new_v4()));
+        let service = service();
+        assert_eq!(load(&service, &path).await.unwrap(), 0);
+        assert!(service.list_queues().await.unwrap().is_empty());
+    }
+}
@@ -1,116 +1,1950 @@
 
-use crate::{Message, MessageState};
+use crate::clock::{Clock, SharedClock};
+use crate::wal::{Wal, WalRecord};
+use crate::{
+    config, group_queue, CompressedBody, ExpiredMessage, Message, MessageBody, MessagePage,
+    MessageState, Order, QueueExport, QueueStats, Schedule, SearchFilter, MAX_SLOW_MESSAGE_IDS,
+};
 use async_trait::async_trait;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Notify, RwLock};
+use tokio::time::{timeout_at, Instant};
+use uuid::Uuid;
+
+mod sharded;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use sharded::ShardedStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+/// Represents the errors a `Storage` implementation can report.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The requested queue or message does not exist.
+    NotFound(String),
+    /// The operation conflicts with the current state of the queue.
+    Conflict(String),
+    /// The underlying backend failed, e.g. an I/O error.
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+    /// The backend timed out or is briefly unreachable. The same operation may
+    /// succeed if retried.
+    Unavailable(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound(what) => write!(f, "Not found: {what}"),
+            StorageError::Conflict(reason) => write!(f, "Conflict: {reason}"),
+            StorageError::Backend(source) => write!(f, "Storage backend error: {source}"),
+            StorageError::Unavailable(reason) => write!(f, "Storage unavailable: {reason}"),
+        }
+    }
+}
+
+/// A lease taken by a get: its messages lock for `secs` instead of the
+/// visibility timeout and carry `token`, which acks and extends must name.
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub token: String,
+    pub secs: u64,
+}
+
+/// What `Storage::retry` did with the ids it was given. Ids in neither list
+/// were not in the queue at all.
+#[derive(Debug, Default)]
+pub struct Retried {
+    /// Ids that were processing and were requeued or dead-lettered.
+    pub ids: Vec<String>,
+    /// Ids that were not processing because they were already ready.
+    pub already_ready: Vec<String>,
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Backend(source) => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 /// The `Storage` trait defines the interface for a message queue storage implementation.
+///
+/// Every operation is scoped to a named queue. Implementations must deliver a
+/// stored message to at most one `get` until it is retried or its lock lapses,
+/// and keep messages of the same priority in the order their adds completed,
+/// or document where they differ, as `ShardedStorage` does.
 #[async_trait]
 pub trait Storage: Send + Sync {
-    async fn add(&self, msg: Message) -> Result<(), String>;
-    async fn get(&self, count: usize) -> Result<Vec<Message>, String>;
-    async fn delete(&self, ids: Vec<String>) -> Result<(), String>;
-    async fn purge(&self) -> Result<(), String>;
-    async fn retry(&self, ids: Vec<String>) -> Result<(), String>;
-    async fn peek(&self, count: usize) -> Result<Vec<Message>, String>;
+    /// Adds a message, returning the stored message. If the message carries a
+    /// `dedup_id` seen within the dedup window, the earlier message is returned
+    /// and nothing is added. Returns `None` when the queue is at its maximum depth,
+    /// and fails with `Conflict` if the queue already holds a message with its id.
+    async fn add(&self, queue: &str, msg: Message) -> Result<Option<Message>, StorageError>;
+    /// Adds messages in order until the queue reaches its maximum depth,
+    /// returning how many were added.
+    async fn add_batch(&self, queue: &str, msgs: Vec<Message>) -> Result<usize, StorageError>;
+    /// Takes up to `count` messages, waiting up to `wait` for at least
+    /// `min_count` of them to be available. Once `wait` has passed, whatever
+    /// is available is returned, even if it is fewer.
+    ///
+    /// With `auto_ack`, the messages are removed as they are taken and
+    /// returned as `Done` instead of being locked as processing. They can't be
+    /// retried or redelivered, so a consumer that fails to handle one loses it:
+    /// at-most-once delivery, for consumers that prefer throughput to reliability.
+    /// Otherwise, with a `lease`, the messages are locked under it.
+    async fn get(
+        &self,
+        queue: &str,
+        count: usize,
+        min_count: usize,
+        wait: Duration,
+        auto_ack: bool,
+        lease: Option<&Lease>,
+    ) -> Result<Vec<Message>, StorageError>;
+    /// Removes processing messages whose `lease_token` is `lease_token`,
+    /// returning the ids that were removed. With `strict_delete` set, fails
+    /// with `NotFound` and removes nothing if any id is not processing so.
+    async fn delete(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        lease_token: Option<&str>,
+    ) -> Result<Vec<String>, StorageError>;
+    /// Removes every message in the queue, returning how many were removed.
+    async fn purge(&self, queue: &str) -> Result<usize, StorageError>;
+    /// Removes only the messages in `state`, returning how many were removed.
+    /// Ready includes delayed messages; nothing is ever stored as done.
+    async fn purge_state(&self, queue: &str, state: MessageState) -> Result<usize, StorageError>;
+    /// Returns processing messages to the queue, reporting the ids that were
+    /// retried and those that were already ready. With `front`, they go ahead
+    /// of the ready messages of their priority. `delay_secs` holds them back
+    /// for that long instead of for the retry backoff.
+    async fn retry(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        front: bool,
+        delay_secs: Option<u64>,
+    ) -> Result<Retried, StorageError>;
+    /// Returns every processing message to the queue as if its lock had expired,
+    /// returning their ids.
+    async fn requeue_all(&self, queue: &str) -> Result<Vec<String>, StorageError>;
+    /// Pushes the lock of processing messages `extra_secs` further out. Fails
+    /// with `NotFound` and extends nothing if any id is not processing, or its
+    /// `lease_token` is not `lease_token`.
+    async fn extend_lock(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        extra_secs: u64,
+        lease_token: Option<&str>,
+    ) -> Result<usize, StorageError>;
+    /// Returns up to `count` ready messages in delivery order without taking
+    /// them, skipping the first `offset`, and how many are ready in total.
+    async fn peek(
+        &self,
+        queue: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<MessagePage, StorageError>;
+    /// Returns ready messages matching `filter` in delivery order, then
+    /// processing ones oldest first, skipping the first `offset` matches.
+    async fn search(
+        &self,
+        queue: &str,
+        filter: &SearchFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Message>, StorageError>;
+    /// Replaces the body, signature and structured body of a ready or
+    /// processing message, keeping everything else about it. Returns `None` if
+    /// no such message is ready or processing.
+    async fn amend(
+        &self,
+        queue: &str,
+        id: &str,
+        body: MessageBody,
+        signature: Option<String>,
+        structured_body: Option<serde_json::Value>,
+    ) -> Result<Option<Message>, StorageError>;
+    /// Looks up a single message by id, whether ready, processing or dead-lettered.
+    async fn get_by_id(&self, queue: &str, id: &str) -> Result<Option<Message>, StorageError>;
+    async fn dead_letters(&self, queue: &str, count: usize) -> Result<Vec<Message>, StorageError>;
+    /// Returns up to `count` of the messages that most recently expired from
+    /// `queue`, newest first, out of the last `expired_archive_size`.
+    async fn expired(&self, queue: &str, count: usize)
+        -> Result<Vec<ExpiredMessage>, StorageError>;
+    async fn requeue_dead_letters(&self, queue: &str, ids: Vec<String>)
+        -> Result<(), StorageError>;
+    async fn list_queues(&self) -> Result<Vec<String>, StorageError>;
+    /// Registers a consumer group on `queue`: messages added to the queue from
+    /// then on are stored once per group, in the `group_queue` of each, instead
+    /// of in the queue itself. Returns false if the group was already registered.
+    async fn register_group(&self, queue: &str, group: &str) -> Result<bool, StorageError>;
+    /// Removes a consumer group and every message in its queue, returning how
+    /// many were removed. Fails with `NotFound` if the group is not registered.
+    async fn unregister_group(&self, queue: &str, group: &str) -> Result<usize, StorageError>;
+    /// Returns the consumer groups registered on `queue`, by name.
+    async fn groups(&self, queue: &str) -> Result<Vec<String>, StorageError>;
+    async fn stats(&self, queue: &str) -> Result<QueueStats, StorageError>;
+    /// Drops ready messages whose TTL has passed, returning how many were dropped.
+    async fn remove_expired(&self, queue: &str) -> Result<usize, StorageError>;
+    /// Returns every message of every queue, in queue name order.
+    async fn export(&self) -> Result<Vec<QueueExport>, StorageError>;
+    /// Stores exported messages as they are, keeping their ids and states.
+    /// With `replace`, every existing queue is emptied first; otherwise messages
+    /// whose id is already stored are skipped. Returns how many were imported.
+    async fn import(&self, queues: Vec<QueueExport>, replace: bool) -> Result<usize, StorageError>;
+    /// Flushes any buffered state before the server exits.
+    async fn shutdown(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+    /// Checks that the backend is reachable. Backends without a connection to
+    /// lose, such as `MemoryStorage`, are always healthy.
+    async fn health_check(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
 }
 
 #[derive(Default)]
 struct BaseMemoryStorage {
     queue: Vec<Message>,
     processing: HashMap<String, Message>,
+    dead_letters: Vec<Message>,
+    /// Ids of every message above, so a taken id is found without a scan.
+    ids: HashSet<Uuid>,
+    total_added: u64,
+    total_expired: u64,
+    total_throttled: u64,
+    total_evicted: u64,
+    /// The last `expired_archive_size` expired messages, oldest first.
+    expired: VecDeque<ExpiredMessage>,
+    /// Original and compressed sizes of the bodies compressed so far.
+    compressed_from_bytes: u64,
+    compressed_to_bytes: u64,
+    /// Recently added messages by `dedup_id`, oldest first in `dedup_order`.
+    dedup: HashMap<String, Message>,
+    /// Each dedup id with when it was added, in unix milliseconds.
+    dedup_order: VecDeque<(String, i64)>,
+    /// Smooth weighted round-robin credit per priority band, for `Schedule::Weighted`.
+    band_credit: HashMap<u8, i64>,
+    /// Changes not yet written to the write-ahead log, when one is enabled.
+    journal: Option<Vec<Change>>,
+    clock: SharedClock,
+}
+
+/// A change to a single queue, recorded for the write-ahead log.
+enum Change {
+    Put(Box<Message>),
+    Remove(String),
+    Purge,
+}
+
+impl Change {
+    fn into_record(self, queue: &str) -> WalRecord {
+        let queue = queue.to_string();
+        match self {
+            Change::Put(message) => WalRecord::Put { queue, message },
+            Change::Remove(id) => WalRecord::Remove { queue, id },
+            Change::Purge => WalRecord::Purge { queue },
+        }
+    }
 }
 
 impl BaseMemoryStorage {
-    fn add(&mut self, msg: Message) -> Result<(), String> {
-        self.queue.push(msg);
-        Ok(())
+    fn add(&mut self, msg: Message) -> Result<Option<Message>, StorageError> {
+        let window = i64::try_from(config().dedup_window_secs.saturating_mul(1000))
+            .unwrap_or(i64::MAX);
+        let dedup_id = msg.dedup_id.clone().filter(|_| window > 0);
+        let now = self.clock.now_millis();
+        if let Some(dedup_id) = &dedup_id {
+            self.evict_dedup(now, window);
+            if let Some(existing) = self.dedup.get(dedup_id) {
+                return Ok(Some(decompressed(existing.clone())));
+            }
+        }
+
+        if self.ids.contains(&msg.id) {
+            return Err(StorageError::Conflict(format!("message {} already exists", msg.id)));
+        }
+
+        if self.is_full() {
+            return Ok(None);
+        }
+
+        let mut stored = msg.clone();
+        self.compress(&mut stored);
+        if let Some(dedup_id) = dedup_id {
+            self.dedup.insert(dedup_id.clone(), stored.clone());
+            self.dedup_order.push_back((dedup_id, now));
+        }
+        self.ids.insert(stored.id);
+        self.enqueue(stored);
+        self.total_added += 1;
+        Ok(Some(msg))
+    }
+
+    /// Returns true once ready and processing messages reach the maximum queue depth.
+    fn is_full(&self) -> bool {
+        config()
+            .max_queue_depth
+            .is_some_and(|max_depth| self.queue.len() + self.processing.len() >= max_depth)
+    }
+
+    /// Forgets dedup ids added `window` milliseconds or more before `now`.
+    fn evict_dedup(&mut self, now: i64, window: i64) {
+        while let Some((_, added)) = self.dedup_order.front() {
+            if now.saturating_sub(*added) < window {
+                break;
+            }
+            if let Some((dedup_id, _)) = self.dedup_order.pop_front() {
+                self.dedup.remove(&dedup_id);
+            }
+        }
+    }
+
+    /// Inserts a new message where the configured `Order` delivers it: behind
+    /// the other messages of its priority for FIFO, ahead of them for LIFO.
+    fn enqueue(&mut self, msg: Message) {
+        match config().order {
+            Order::Fifo => self.enqueue_back(msg),
+            Order::Lifo => self.enqueue_front(msg),
+        }
+    }
+
+    /// Inserts a message behind every queued message of the same or higher
+    /// priority, keeping the queue ordered by priority.
+    fn enqueue_back(&mut self, mut msg: Message) {
+        self.compress(&mut msg);
+        self.record(|| Change::Put(Box::new(msg.clone())));
+        let position = self
+            .queue
+            .partition_point(|queued| queued.priority >= msg.priority);
+        self.queue.insert(position, msg);
     }
 
-    fn get(&mut self, count: usize) -> Result<Vec<Message>, String> {
-        let count = count.min(self.queue.len());
-        let mut messages: Vec<Message> = self.queue.drain(0..count).collect();
+    /// Inserts a message ahead of every queued message of the same priority.
+    fn enqueue_front(&mut self, mut msg: Message) {
+        self.compress(&mut msg);
+        self.record(|| Change::Put(Box::new(msg.clone())));
+        let position = self
+            .queue
+            .partition_point(|queued| queued.priority > msg.priority);
+        self.queue.insert(position, msg);
+    }
+
+    /// Compresses the body of a message above `compress_threshold`, if that
+    /// makes it smaller.
+    fn compress(&mut self, msg: &mut Message) {
+        let Some(threshold) = config().compress_threshold else {
+            return;
+        };
+        if msg.body.len() <= threshold {
+            return;
+        }
+
+        if let Some(compressed) = CompressedBody::compress(&msg.body) {
+            self.compressed_from_bytes += compressed.len() as u64;
+            self.compressed_to_bytes += compressed.compressed_len() as u64;
+            msg.body = MessageBody::Compressed(compressed);
+        }
+    }
+
+    /// Returns a message to the queue behind the others of its priority, in
+    /// either order, dropping it instead if its TTL has passed. A message of a
+    /// group goes back ahead of the rest of its group instead, so that the
+    /// group is still delivered in order, but never outside its own priority.
+    fn requeue(&mut self, mut msg: Message, now: i64) {
+        if is_expired(&msg, now) {
+            self.expire(msg);
+            return;
+        }
+
+        let group_position = msg.group_id.as_ref().and_then(|group_id| {
+            self.queue
+                .iter()
+                .position(|queued| queued.group_id.as_ref() == Some(group_id))
+        });
+        match group_position {
+            Some(position) => {
+                let band_start =
+                    self.queue.partition_point(|queued| queued.priority > msg.priority);
+                let band_end =
+                    self.queue.partition_point(|queued| queued.priority >= msg.priority);
+                self.compress(&mut msg);
+                self.record(|| Change::Put(Box::new(msg.clone())));
+                self.queue.insert(position.clamp(band_start, band_end), msg);
+            }
+            None => self.enqueue_back(msg),
+        }
+    }
+
+    /// Takes up to `count` messages, locking them as processing, under `lease`
+    /// if one is given. With `auto_ack` they are dropped instead and returned
+    /// as `Done`: nothing is kept to redeliver if the consumer then fails, so
+    /// they are delivered at most once.
+    fn get(
+        &mut self,
+        count: usize,
+        auto_ack: bool,
+        lease: Option<&Lease>,
+    ) -> Result<Vec<Message>, StorageError> {
+        let now = self.clock.now_secs();
+        self.sweep(now);
+
+        let mut count = self.in_flight_allowance(count);
+        if let Some(capacity) = config().processing_capacity {
+            count = count.min(capacity);
+        }
+        let lock_secs = lease.map_or(config().visibility_timeout_secs, |lease| lease.secs);
+        let lock_until = now.saturating_add(i64::try_from(lock_secs).unwrap_or(i64::MAX));
+        let mut messages = match config().schedule {
+            Schedule::Strict => self.take_strict(count, now),
+            Schedule::Weighted => self.take_weighted(count, now),
+        };
+
+        let now_ms = self.clock.now_millis();
+        if auto_ack {
+            for message in &mut messages {
+                message.state = MessageState::Done;
+                message.record_delivery(now_ms);
+                self.ids.remove(&message.id);
+                self.record(|| Change::Remove(message.id.to_string()));
+            }
+            return Ok(messages.into_iter().map(decompressed).collect());
+        }
         for message in &mut messages {
             message.state = MessageState::Processing;
+            message.lock_until = Some(lock_until);
+            message.lease_token = lease.map(|lease| lease.token.clone());
+            message.record_delivery(now_ms);
+            self.record(|| Change::Put(Box::new(message.clone())));
             self.processing
                 .insert(message.id.to_string(), message.clone());
         }
-        Ok(messages)
+        self.evict_over_capacity(now);
+        Ok(messages.into_iter().map(decompressed).collect())
+    }
+
+    /// Requeues the longest processing messages, without counting a retry,
+    /// until no more than `processing_capacity` are left.
+    fn evict_over_capacity(&mut self, now: i64) {
+        let Some(capacity) = config().processing_capacity else {
+            return;
+        };
+        let excess = self.processing.len().saturating_sub(capacity);
+        if excess == 0 {
+            return;
+        }
+
+        let mut oldest: Vec<(i64, &String)> = self
+            .processing
+            .iter()
+            .map(|(id, message)| {
+                let delivered_at = message.attempts.last().map_or(0, |attempt| attempt.delivered_at);
+                (delivered_at, id)
+            })
+            .collect();
+        oldest.sort_unstable();
+        let evicted: HashSet<String> =
+            oldest.into_iter().take(excess).map(|(_, id)| id.clone()).collect();
+        let ids = self.requeue_processing(now, |message| evicted.contains(&message.id.to_string()));
+        self.total_evicted += ids.len() as u64;
+        tracing::warn!(
+            count = ids.len(),
+            ids = %ids.join(","),
+            capacity,
+            "Processing capacity reached, requeued the longest processing messages"
+        );
     }
 
-    fn delete(&mut self, ids: Vec<String>) -> Result<(), String> {
+    /// Caps `count` so processing messages stay within `max_in_flight`, counting
+    /// the get as throttled when that leaves ready messages behind.
+    fn in_flight_allowance(&mut self, count: usize) -> usize {
+        let Some(max_in_flight) = config().max_in_flight else {
+            return count;
+        };
+
+        let allowed = max_in_flight.saturating_sub(self.processing.len());
+        if allowed < count && !self.queue.is_empty() {
+            self.total_throttled += 1;
+        }
+        count.min(allowed)
+    }
+
+    /// Returns how many messages a get could take right now, up to `count`.
+    fn available(&self, count: usize, now: i64) -> usize {
+        let allowed = config()
+            .max_in_flight
+            .map_or(count, |max_in_flight| max_in_flight.saturating_sub(self.processing.len()));
+        self.deliverable(now)
+            .into_iter()
+            .filter(|&deliverable| deliverable)
+            .take(count.min(allowed))
+            .count()
+    }
+
+    /// Returns, for each queued message in order, whether a get may take it:
+    /// its delay has passed, and it is the first queued message of its group
+    /// while no message of that group is processing.
+    fn deliverable(&self, now: i64) -> Vec<bool> {
+        let mut busy_groups: HashSet<&str> = self
+            .processing
+            .values()
+            .filter_map(|message| message.group_id.as_deref())
+            .collect();
+        self.queue
+            .iter()
+            .map(|message| match message.group_id.as_deref() {
+                // An earlier message of the group blocks the rest, delayed or not.
+                Some(group_id) => busy_groups.insert(group_id) && is_deliverable(message, now),
+                None => is_deliverable(message, now),
+            })
+            .collect()
+    }
+
+    /// Takes the first `count` deliverable messages in priority order.
+    fn take_strict(&mut self, count: usize, now: i64) -> Vec<Message> {
+        let deliverable = self.deliverable(now);
+        let mut messages = Vec::new();
+        let mut index = 0;
+        self.queue.retain(|message| {
+            let take = messages.len() < count && deliverable[index];
+            index += 1;
+            if take {
+                messages.push(message.clone());
+            }
+            !take
+        });
+        messages
+    }
+
+    /// Takes up to `count` deliverable messages, choosing between priority bands
+    /// by smooth weighted round-robin. Within a band, queue order is kept.
+    fn take_weighted(&mut self, count: usize, now: i64) -> Vec<Message> {
+        let config = config();
+        let deliverable = self.deliverable(now);
+        let mut bands: BTreeMap<u8, (i64, VecDeque<usize>)> = BTreeMap::new();
+        for (index, message) in self.queue.iter().enumerate() {
+            if deliverable[index] {
+                let (band, weight) = config.priority_band(message.priority);
+                bands
+                    .entry(band)
+                    .or_insert_with(|| (i64::from(weight), VecDeque::new()))
+                    .1
+                    .push_back(index);
+            }
+        }
+        // Credit left over by bands that have drained would skew the next round.
+        self.band_credit.retain(|band, _| bands.contains_key(band));
+
+        let mut picked = Vec::new();
+        while picked.len() < count {
+            let mut total = 0;
+            let mut best: Option<(u8, i64)> = None;
+            // Highest band first, so ties go to the higher priority.
+            for (&band, (weight, positions)) in bands.iter().rev() {
+                if positions.is_empty() {
+                    continue;
+                }
+                total += *weight;
+                let credit = self.band_credit.entry(band).or_default();
+                *credit += *weight;
+                if best.is_none_or(|(_, best_credit)| *credit > best_credit) {
+                    best = Some((band, *credit));
+                }
+            }
+
+            let Some((band, _)) = best else {
+                break;
+            };
+            *self.band_credit.entry(band).or_default() -= total;
+            if let Some((_, positions)) = bands.get_mut(&band) {
+                picked.extend(positions.pop_front());
+            }
+        }
+
+        // Remove from the back so earlier indexes stay valid.
+        picked.sort_unstable();
+        let mut messages: Vec<Message> =
+            picked.iter().rev().map(|&index| self.queue.remove(index)).collect();
+        messages.reverse();
+        messages
+    }
+
+    fn delete(
+        &mut self,
+        ids: Vec<String>,
+        lease_token: Option<&str>,
+    ) -> Result<Vec<String>, StorageError> {
+        // A message whose lock lapsed is no longer the consumer's to delete.
+        self.requeue_expired(self.clock.now_secs());
+        if config().strict_delete {
+            require_processing(&ids, |id| self.is_held(id, lease_token))?;
+        }
+
+        let mut deleted = Vec::new();
         for id in ids {
-            self.processing.remove(&id);
+            if self.is_held(&id, lease_token) {
+                if let Some(message) = self.processing.remove(&id) {
+                    self.ids.remove(&message.id);
+                }
+                self.record(|| Change::Remove(id.clone()));
+                deleted.push(id);
+            }
         }
-        Ok(())
+        Ok(deleted)
+    }
+
+    /// Returns true if the message is processing under `lease_token`, or
+    /// under no lease when that is `None`.
+    fn is_held(&self, id: &str, lease_token: Option<&str>) -> bool {
+        self.processing
+            .get(id)
+            .is_some_and(|message| message.lease_token.as_deref() == lease_token)
     }
 
-    fn purge(&mut self) -> Result<(), String> {
+    fn purge(&mut self) -> Result<usize, StorageError> {
+        let purged = self.queue.len() + self.processing.len() + self.dead_letters.len();
         self.queue.clear();
         self.processing.clear();
-        Ok(())
+        self.dead_letters.clear();
+        self.ids.clear();
+        self.dedup.clear();
+        self.dedup_order.clear();
+        self.record(|| Change::Purge);
+        Ok(purged)
+    }
+
+    fn purge_state(&mut self, state: MessageState) -> usize {
+        let ids: Vec<Uuid> = match state {
+            MessageState::Ready => self.queue.drain(..).map(|message| message.id).collect(),
+            MessageState::Processing => {
+                self.processing.drain().map(|(_, message)| message.id).collect()
+            }
+            MessageState::Dead => self.dead_letters.drain(..).map(|message| message.id).collect(),
+            MessageState::Done => Vec::new(),
+        };
+        let purged = ids.len();
+        for id in &ids {
+            self.ids.remove(id);
+            self.record(|| Change::Remove(id.to_string()));
+        }
+        // Purged messages are gone for good, so a producer may add them again.
+        let ids: HashSet<Uuid> = ids.into_iter().collect();
+        self.dedup.retain(|_, message| !ids.contains(&message.id));
+        let dedup = &self.dedup;
+        self.dedup_order.retain(|(dedup_id, _)| dedup.contains_key(dedup_id));
+        purged
     }
 
-    fn retry(&mut self, ids: Vec<String>) -> Result<(), String> {
+    fn retry(
+        &mut self,
+        ids: Vec<String>,
+        front: bool,
+        delay_secs: Option<u64>,
+    ) -> Result<Retried, StorageError> {
+        let now = self.clock.now_secs();
+        let now_ms = self.clock.now_millis();
+        // Nor is one whose lock lapsed the consumer's to retry.
+        self.requeue_expired(now);
         let mut retried_messages = Vec::new();
-        let ids_set: std::collections::HashSet<String> = ids.into_iter().collect();
+        let mut dead_messages = Vec::new();
+        let positions: HashMap<String, usize> =
+            ids.into_iter().enumerate().map(|(position, id)| (id, position)).collect();
 
         self.processing.retain(|id, message| {
-            if ids_set.contains(id) {
+            if positions.contains_key(id) {
+                let delay = delay_secs
+                    .unwrap_or_else(|| config().retry_backoff_secs(message.retry_count));
+                if delay > 0 {
+                    message.deliver_at = Some(now.saturating_add(delay as i64));
+                }
                 message.retry_count += 1;
+                message.lock_until = None;
+                message.lease_token = None;
+                message.record_retry(now_ms);
+                if message.retry_count > message.retry_limit() {
+                    message.state = MessageState::Dead;
+                    dead_messages.push(message.clone());
+                } else {
+                    message.state = MessageState::Ready;
+                    retried_messages.push(message.clone());
+                }
+                false
+            } else {
+                true
+            }
+        });
+
+        // Keep the order the ids were given in, whichever end they are requeued at.
+        retried_messages.sort_by_key(|message| positions[&message.id.to_string()]);
+        let retried: Vec<String> = retried_messages
+            .iter()
+            .chain(&dead_messages)
+            .map(|message| message.id.to_string())
+            .collect();
+        let mut already_ready = Vec::new();
+        if retried.len() < positions.len() {
+            let retried_set: HashSet<&String> = retried.iter().collect();
+            let ready: HashSet<String> =
+                self.queue.iter().map(|message| message.id.to_string()).collect();
+            already_ready = positions
+                .keys()
+                .filter(|id| !retried_set.contains(id) && ready.contains(*id))
+                .cloned()
+                .collect();
+            already_ready.sort_by_key(|id| positions[id]);
+        }
+        if front {
+            for message in retried_messages.into_iter().rev() {
+                if is_expired(&message, now) {
+                    self.requeue(message, now);
+                } else {
+                    self.enqueue_front(message);
+                }
+            }
+        } else {
+            for message in retried_messages {
+                self.requeue(message, now);
+            }
+        }
+        for message in dead_messages {
+            self.record(|| Change::Put(Box::new(message.clone())));
+            self.dead_letters.push(message);
+        }
+        Ok(Retried { ids: retried, already_ready })
+    }
+
+    fn extend_lock(
+        &mut self,
+        ids: Vec<String>,
+        extra_secs: u64,
+        lease_token: Option<&str>,
+    ) -> Result<usize, StorageError> {
+        // Locks that have already run out are released first, so they can't be extended.
+        let now = self.clock.now_secs();
+        self.requeue_expired(now);
+        require_processing(&ids, |id| self.is_held(id, lease_token))?;
+
+        let extra = i64::try_from(extra_secs).unwrap_or(i64::MAX);
+        let ids: HashSet<String> = ids.into_iter().collect();
+        for id in &ids {
+            if let Some(message) = self.processing.get_mut(id) {
+                message.lock_until = Some(message.lock_until.unwrap_or(now).saturating_add(extra));
+                let message = message.clone();
+                self.record(|| Change::Put(Box::new(message)));
+            }
+        }
+        Ok(ids.len())
+    }
+
+    /// Returns the next ready messages without taking them. Call `sweep` first
+    /// when `needs_sweep` says so, or lapsed messages will be missed.
+    fn peek(&self, offset: usize, count: usize, now: i64) -> MessagePage {
+        let ready = || self.queue.iter().filter(|message| is_deliverable(message, now));
+        let messages = ready().skip(offset).take(count).cloned().map(decompressed).collect();
+        MessagePage { messages, total: ready().count() }
+    }
+
+    fn search(&self, filter: &SearchFilter, offset: usize, limit: usize) -> Vec<Message> {
+        let mut processing: Vec<&Message> = self.processing.values().collect();
+        processing.sort_by_key(|message| (message.created_at, message.id));
+        self.queue
+            .iter()
+            .chain(processing)
+            .filter(|message| filter.matches(message))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .map(decompressed)
+            .collect()
+    }
+
+    fn dead_letters(&self, count: usize) -> Result<Vec<Message>, StorageError> {
+        let count = count.min(self.dead_letters.len());
+        Ok(self.dead_letters.iter().take(count).cloned().map(decompressed).collect())
+    }
+
+    fn amend(
+        &mut self,
+        id: &str,
+        body: MessageBody,
+        signature: Option<String>,
+        structured_body: Option<serde_json::Value>,
+    ) -> Option<Message> {
+        let position = self.queue.iter().position(|message| message.id.to_string() == id);
+        let mut message = match position {
+            Some(position) => self.queue[position].clone(),
+            None => self.processing.get(id)?.clone(),
+        };
+        message.body = body;
+        message.signature = signature;
+        message.structured_body = structured_body;
+        self.compress(&mut message);
+        self.record(|| Change::Put(Box::new(message.clone())));
+        match position {
+            Some(position) => self.queue[position] = message.clone(),
+            None => {
+                self.processing.insert(id.to_string(), message.clone());
+            }
+        }
+        Some(decompressed(message))
+    }
+
+    fn get_by_id(&self, id: &str) -> Option<Message> {
+        self.messages()
+            .find(|message| message.id.to_string() == id)
+            .cloned()
+            .map(decompressed)
+    }
+
+    fn requeue_dead_letters(&mut self, ids: Vec<String>) -> Result<(), StorageError> {
+        let now = self.clock.now_secs();
+        let mut requeued_messages = Vec::new();
+        let ids_set: std::collections::HashSet<String> = ids.into_iter().collect();
+
+        self.dead_letters.retain(|message| {
+            if ids_set.contains(&message.id.to_string()) {
+                let mut message = message.clone();
+                message.retry_count = 0;
                 message.state = MessageState::Ready;
-                retried_messages.push(message.clone());
+                requeued_messages.push(message);
                 false
             } else {
                 true
             }
         });
 
-        self.queue.extend(retried_messages);
+        for message in requeued_messages {
+            self.requeue(message, now);
+        }
         Ok(())
     }
 
-    fn peek(&mut self, count: usize) -> Result<Vec<Message>, String> {
-        let count = count.min(self.queue.len());
-        Ok(self.queue.iter().take(count).cloned().collect())
+    fn stats(&self) -> Result<QueueStats, StorageError> {
+        Ok(combined_stats([self]))
+    }
+
+    /// Places a recovered or imported message according to its state.
+    fn restore(&mut self, message: Message) {
+        let mut message = message.with_structured_body();
+        // Logs written before `created_at` existed; the v7 id carries the time.
+        if message.created_at == 0 {
+            if let Some(timestamp) = message.id.get_timestamp() {
+                let (secs, nanos) = timestamp.to_unix();
+                message.created_at = secs as i64 * 1000 + i64::from(nanos / 1_000_000);
+            }
+        }
+        self.ids.insert(message.id);
+        match message.state {
+            MessageState::Processing => {
+                self.record(|| Change::Put(Box::new(message.clone())));
+                self.processing.insert(message.id.to_string(), message);
+            }
+            MessageState::Dead => {
+                self.record(|| Change::Put(Box::new(message.clone())));
+                self.dead_letters.push(message);
+            }
+            _ => self.enqueue(message),
+        }
+    }
+
+    /// Returns every stored message: queued, processing and dead-lettered.
+    fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.queue
+            .iter()
+            .chain(self.processing.values())
+            .chain(self.dead_letters.iter())
+    }
+
+    fn record(&mut self, change: impl FnOnce() -> Change) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(change());
+        }
+    }
+
+    fn take_journal(&mut self) -> Vec<Change> {
+        self.journal.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Moves processing messages whose lock has elapsed back onto the queue.
+    /// Whether processing locks have lapsed or queued messages have expired.
+    fn needs_sweep(&self, now: i64) -> bool {
+        self.processing
+            .values()
+            .any(|message| message.lock_until.is_some_and(|lock_until| lock_until <= now))
+            || self.queue.iter().any(|message| is_expired(message, now))
+    }
+
+    /// Returns messages whose lock lapsed to the queue and drops expired ones.
+    fn sweep(&mut self, now: i64) {
+        self.requeue_expired(now);
+        self.remove_expired(now);
+    }
+
+    fn requeue_expired(&mut self, now: i64) {
+        self.requeue_processing(now, |message| {
+            message.lock_until.is_some_and(|lock_until| lock_until <= now)
+        });
+    }
+
+    /// Moves every processing message back onto the queue without counting a
+    /// retry, returning their ids.
+    fn requeue_all(&mut self) -> Vec<String> {
+        self.requeue_processing(self.clock.now_secs(), |_| true)
+    }
+
+    /// Moves the processing messages matching `filter` back onto the queue in
+    /// the order they were created, returning their ids.
+    fn requeue_processing(&mut self, now: i64, filter: impl Fn(&Message) -> bool) -> Vec<String> {
+        let mut released = Vec::new();
+
+        self.processing.retain(|_, message| {
+            if filter(message) {
+                message.state = MessageState::Ready;
+                message.lock_until = None;
+                message.lease_token = None;
+                released.push(message.clone());
+                false
+            } else {
+                true
+            }
+        });
+
+        released.sort_by_key(|message| (message.created_at, message.id));
+        let ids = released.iter().map(|message| message.id.to_string()).collect();
+        for message in released {
+            self.requeue(message, now);
+        }
+        ids
+    }
+
+    /// Drops queued messages whose TTL has passed, returning how many were dropped.
+    fn remove_expired(&mut self, now: i64) -> usize {
+        let (expired, queue) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition::<Vec<_>, _>(|message| is_expired(message, now));
+        self.queue = queue;
+
+        let count = expired.len();
+        for message in expired {
+            self.expire(message);
+        }
+        count
+    }
+
+    /// Drops a message whose TTL has passed, keeping it in the expired archive
+    /// when there is one.
+    fn expire(&mut self, message: Message) {
+        self.ids.remove(&message.id);
+        self.record(|| Change::Remove(message.id.to_string()));
+        self.total_expired += 1;
+
+        let capacity = config().expired_archive_size;
+        if capacity == 0 {
+            return;
+        }
+        if self.expired.len() >= capacity {
+            self.expired.pop_front();
+        }
+        self.expired.push_back(ExpiredMessage {
+            message: decompressed(message),
+            expired_at: self.clock.now_millis(),
+        });
+    }
+
+    fn expired(&self, count: usize) -> Vec<ExpiredMessage> {
+        self.expired.iter().rev().take(count).cloned().collect()
+    }
+}
+
+/// Returns the stats of a queue spread over one or more bases.
+fn combined_stats<'a>(bases: impl IntoIterator<Item = &'a BaseMemoryStorage>) -> QueueStats {
+    let mut stats = QueueStats::default();
+    let (mut compressed_from_bytes, mut compressed_to_bytes) = (0, 0);
+    let slow_after_ms = config().slow_consumer_secs.saturating_mul(1000);
+    let mut slow = Vec::new();
+    for base in bases {
+        let now_ms = base.clock.now_millis();
+        if slow_after_ms > 0 {
+            slow.extend(base.processing.values().filter_map(|message| {
+                let in_flight_ms = message.in_flight_ms_at(now_ms)?;
+                (in_flight_ms > slow_after_ms).then(|| (in_flight_ms, message.id.to_string()))
+            }));
+        }
+        stats.ready_count += base.queue.len();
+        stats.processing_count += base.processing.len();
+        stats.dead_letter_count += base.dead_letters.len();
+        let oldest_ms = base.queue.iter().map(|message| message.age_ms_at(now_ms)).max();
+        stats.oldest_message_age_ms = stats.oldest_message_age_ms.max(oldest_ms);
+        for created_at in base.queue.iter().map(|message| message.created_at) {
+            let oldest = stats.oldest_ready_at.map_or(created_at, |at| at.min(created_at));
+            let newest = stats.newest_ready_at.map_or(created_at, |at| at.max(created_at));
+            (stats.oldest_ready_at, stats.newest_ready_at) = (Some(oldest), Some(newest));
+        }
+        stats.total_added += base.total_added;
+        stats.total_expired += base.total_expired;
+        stats.total_throttled += base.total_throttled;
+        stats.total_evicted += base.total_evicted;
+        compressed_from_bytes += base.compressed_from_bytes;
+        compressed_to_bytes += base.compressed_to_bytes;
+    }
+
+    slow.sort_unstable_by(|a, b| b.cmp(a));
+    slow.truncate(MAX_SLOW_MESSAGE_IDS);
+    stats.slow_message_ids = slow.into_iter().map(|(_, id)| id).collect();
+    stats.oldest_message_age_secs = stats.oldest_message_age_ms.map(|age| age / 1000);
+    stats.compression_ratio = (compressed_from_bytes > 0)
+        .then(|| compressed_to_bytes as f64 / compressed_from_bytes as f64);
+    // `draining` is tracked by `MessageService`, not the storage.
+    stats
+}
+
+/// Fails with `NotFound`, naming them, if any of `ids` is not processing.
+fn require_processing(ids: &[String], is_processing: impl Fn(&str) -> bool) -> Result<(), StorageError> {
+    let missing: Vec<&str> = ids
+        .iter()
+        .map(String::as_str)
+        .filter(|id| !is_processing(id))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(StorageError::NotFound(format!("messages not processing: {}", missing.join(", "))))
     }
 }
 
+/// Undoes storage compression before a message is handed out.
+fn decompressed(mut message: Message) -> Message {
+    message.body = message.body.decompressed();
+    message
+}
+
+/// A message is deliverable once its `deliver_at` time, if any, has passed.
+fn is_deliverable(message: &Message, now: i64) -> bool {
+    message.deliver_at.is_none_or(|deliver_at| deliver_at <= now)
+}
+
+/// A message is expired once its `expires_at` time, if any, has passed.
+fn is_expired(message: &Message, now: i64) -> bool {
+    message.expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// In-memory storage holding one `BaseMemoryStorage` per named queue.
+///
+/// Queues are created lazily by the first `add`. Every other operation on a
+/// queue that was never created behaves as if the queue were empty: reads
+/// return no messages and writes are no-ops. Such operations do not create
+/// the queue.
+///
+/// When created with `with_wal`, every change is also appended to a
+/// write-ahead log that is replayed on startup; `SqliteStorage` keeps the same
+/// changes in a SQLite database instead.
+///
+/// Reads such as `stats`, `peek`, `get_by_id`, `dead_letters` and `export`
+/// share a read lock; anything that changes a queue takes the write lock.
+///
+/// Lock timeouts, TTLs and delays are checked against the `SystemClock`, or
+/// the clock given to `with_clock`.
 #[derive(Default)]
 pub struct MemoryStorage {
-    inner: Arc<Mutex<BaseMemoryStorage>>,
+    inner: Arc<RwLock<MemoryState>>,
+    /// Wakes consumers long-polling in `get` when messages become available.
+    available: Arc<Notify>,
+    clock: SharedClock,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    queues: HashMap<String, BaseMemoryStorage>,
+    /// Consumer groups registered on each queue.
+    groups: HashMap<String, BTreeSet<String>>,
+    /// Where changes are kept durably, if anywhere.
+    log: Option<Box<dyn Journal>>,
+    /// Handed to each queue as it is created.
+    clock: SharedClock,
+}
+
+impl MemoryState {
+    /// Returns the named queue, creating it if it doesn't exist yet.
+    fn queue_mut(&mut self, queue: &str) -> &mut BaseMemoryStorage {
+        let journaling = self.log.is_some();
+        let clock = &self.clock;
+        self.queues.entry(queue.to_string()).or_insert_with(|| BaseMemoryStorage {
+            journal: journaling.then(Vec::new),
+            clock: clock.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Returns the queues a message added to `queue` is stored in: the queue of
+    /// each of its consumer groups, or the queue itself when it has none.
+    fn targets(&self, queue: &str) -> Vec<String> {
+        match self.groups.get(queue) {
+            Some(groups) if !groups.is_empty() => {
+                groups.iter().map(|group| group_queue(queue, group)).collect()
+            }
+            _ => vec![queue.to_string()],
+        }
+    }
+
+    /// Adds a message to every target of `queue`, so each consumer group gets
+    /// its own copy, returning the message as added to the first. Nothing is
+    /// added when any target is full, keeping the groups in step.
+    fn add(&mut self, queue: &str, msg: Message) -> Result<Option<Message>, StorageError> {
+        let targets = self.targets(queue);
+        let full = |target: &String| self.queues.get(target).is_some_and(|base| base.is_full());
+        if targets.iter().any(full) {
+            return Ok(None);
+        }
+
+        let mut added = None;
+        for target in &targets {
+            let stored = self.queue_mut(target).add(msg.clone())?;
+            added = added.or(stored);
+        }
+        Ok(added)
+    }
+
+    /// Persists the pending changes of every target of `queue`.
+    fn persist_targets(&mut self, queue: &str) -> Result<(), StorageError> {
+        for target in self.targets(queue) {
+            self.persist(&target)?;
+        }
+        Ok(())
+    }
+
+    /// Appends a consumer group change to the log, if enabled.
+    fn persist_group(&mut self, record: WalRecord) -> Result<(), StorageError> {
+        match self.log.as_mut() {
+            Some(log) => log.append(&[record]),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends the pending changes of `queue` to the log, compacting the log
+    /// once it has grown enough.
+    fn persist(&mut self, queue: &str) -> Result<(), StorageError> {
+        let (Some(log), Some(base)) = (self.log.as_mut(), self.queues.get_mut(queue)) else {
+            return Ok(());
+        };
+
+        let records: Vec<WalRecord> = base
+            .take_journal()
+            .into_iter()
+            .map(|change| change.into_record(queue))
+            .collect();
+        log.append(&records)?;
+
+        if log.needs_compaction() {
+            let snapshot = Self::snapshot(&self.queues, &self.groups);
+            if let Some(log) = self.log.as_mut() {
+                log.compact(snapshot)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns records that rebuild the current state of every queue and its
+    /// consumer groups.
+    fn snapshot(
+        queues: &HashMap<String, BaseMemoryStorage>,
+        groups: &HashMap<String, BTreeSet<String>>,
+    ) -> Vec<WalRecord> {
+        let groups = groups.iter().flat_map(|(queue, groups)| {
+            groups.iter().map(move |group| WalRecord::Group {
+                queue: queue.clone(),
+                group: group.clone(),
+            })
+        });
+        let messages = queues.iter().flat_map(|(queue, base)| {
+            base.messages().map(move |message| WalRecord::Put {
+                queue: queue.clone(),
+                message: Box::new(message.clone()),
+            })
+        });
+        groups.chain(messages).collect()
+    }
+}
+
+/// Where `MemoryStorage` keeps a durable copy of its changes, to be replayed
+/// on startup.
+pub(crate) trait Journal: Send + Sync {
+    fn append(&mut self, records: &[WalRecord]) -> Result<(), StorageError>;
+    /// Whether enough has been appended that the log should be rewritten from
+    /// a snapshot.
+    fn needs_compaction(&self) -> bool;
+    /// Replaces the log with records describing the full current state.
+    fn compact(&mut self, records: Vec<WalRecord>) -> Result<(), StorageError>;
+}
+
+impl Journal for Wal {
+    fn append(&mut self, records: &[WalRecord]) -> Result<(), StorageError> {
+        Wal::append(self, records).map_err(wal_error)
+    }
+
+    fn needs_compaction(&self) -> bool {
+        Wal::needs_compaction(self)
+    }
+
+    fn compact(&mut self, records: Vec<WalRecord>) -> Result<(), StorageError> {
+        Wal::compact(self, records).map_err(wal_error)
+    }
+}
+
+fn wal_error(e: std::io::Error) -> StorageError {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::WouldBlock => {
+            StorageError::Unavailable(format!("write-ahead log: {e}"))
+        }
+        _ => StorageError::Backend(format!("write-ahead log: {e}").into()),
+    }
 }
 
 impl MemoryStorage {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Creates an empty `MemoryStorage` that tells the time with `clock`, e.g.
+    /// a `MockClock` to step through timeouts without waiting.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self::sharing(Arc::default(), SharedClock::new(clock))
+    }
+
+    /// Creates an empty `MemoryStorage` that wakes waiting consumers through
+    /// `available` and tells the time with `clock`, both shared with other
+    /// storages.
+    fn sharing(available: Arc<Notify>, clock: SharedClock) -> Self {
+        let state = MemoryState { clock: clock.clone(), ..Default::default() };
+        Self { inner: Arc::new(RwLock::new(state)), available, clock }
+    }
+
+    /// Creates a `MemoryStorage` backed by the write-ahead log at `path`,
+    /// rebuilding its queues from the log if it already exists.
+    ///
+    /// Messages that were processing when the log was written stay processing
+    /// and return to their queue once their lock expires.
+    pub fn with_wal(path: &Path) -> Result<Self, StorageError> {
+        let (wal, records) = Wal::open(path, config().wal_compact_every).map_err(wal_error)?;
+        Self::replaying(Box::new(wal), records, false)
+    }
+
+    /// Creates a `MemoryStorage` that keeps its changes in `log`, rebuilding its
+    /// queues from `records`, the contents of the log. With `release`, messages
+    /// that were processing are returned to their queue at once instead of
+    /// when their lock expires.
+    pub(crate) fn replaying(
+        mut log: Box<dyn Journal>,
+        records: Vec<WalRecord>,
+        release: bool,
+    ) -> Result<Self, StorageError> {
+        // Keep the last version of each message, remembering when it was written
+        // so queues are rebuilt in their original order.
+        let mut latest: HashMap<String, HashMap<String, (usize, Message)>> = HashMap::new();
+        let mut groups: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for (seq, record) in records.into_iter().enumerate() {
+            match record {
+                WalRecord::Put { queue, message } => {
+                    latest
+                        .entry(queue)
+                        .or_default()
+                        .insert(message.id.to_string(), (seq, *message));
+                }
+                WalRecord::Remove { queue, id } => {
+                    if let Some(messages) = latest.get_mut(&queue) {
+                        messages.remove(&id);
+                    }
+                }
+                WalRecord::Purge { queue } => {
+                    latest.remove(&queue);
+                }
+                WalRecord::Group { queue, group } => {
+                    groups.entry(queue).or_default().insert(group);
+                }
+                WalRecord::Ungroup { queue, group } => {
+                    if let Some(registered) = groups.get_mut(&queue) {
+                        registered.remove(&group);
+                    }
+                }
+            }
+        }
+
+        let mut queues = HashMap::new();
+        for (queue, messages) in latest {
+            let mut messages: Vec<(usize, Message)> = messages.into_values().collect();
+            messages.sort_by_key(|(seq, _)| *seq);
+
+            let mut base = BaseMemoryStorage::default();
+            for (_, message) in messages {
+                base.restore(message);
+            }
+            base.journal = Some(Vec::new());
+            if release {
+                base.requeue_all();
+            }
+            queues.insert(queue, base);
+        }
+
+        groups.retain(|_, registered| !registered.is_empty());
+        log.compact(MemoryState::snapshot(&queues, &groups))?;
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(MemoryState {
+                queues,
+                groups,
+                log: Some(log),
+                clock: SharedClock::default(),
+            })),
+            available: Arc::default(),
+            clock: SharedClock::default(),
+        })
+    }
 }
 
 #[async_trait]
 impl Storage for MemoryStorage {
-    async fn add(&self, msg: Message) -> Result<(), String> {
-        self.inner.lock().await.add(msg)
+    async fn add(&self, queue: &str, msg: Message) -> Result<Option<Message>, StorageError> {
+        let mut state = self.inner.write().await;
+        let msg = state.add(queue, msg)?;
+        state.persist_targets(queue)?;
+        self.available.notify_waiters();
+        Ok(msg)
     }
 
-    async fn get(&self, count: usize) -> Result<Vec<Message>, String> {
-        self.inner.lock().await.get(count)
+    async fn add_batch(&self, queue: &str, msgs: Vec<Message>) -> Result<usize, StorageError> {
+        let mut state = self.inner.write().await;
+        let mut added = 0;
+        for msg in msgs {
+            if state.add(queue, msg)?.is_none() {
+                break;
+            }
+            added += 1;
+        }
+        state.persist_targets(queue)?;
+        self.available.notify_waiters();
+        Ok(added)
     }
 
-    async fn delete(&self, ids: Vec<String>) -> Result<(), String> {
-        self.inner.lock().await.delete(ids)
+    async fn get(
+        &self,
+        queue: &str,
+        count: usize,
+        min_count: usize,
+        wait: Duration,
+        auto_ack: bool,
+        lease: Option<&Lease>,
+    ) -> Result<Vec<Message>, StorageError> {
+        let deadline = Instant::now() + wait;
+        let min_count = min_count.clamp(1, count.max(1));
+        loop {
+            // Register for notifications before checking the queue so an add
+            // landing in between is not missed.
+            let notified = self.available.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let timed_out = Instant::now() >= deadline;
+            let messages = {
+                let mut state = self.inner.write().await;
+                match state.queues.get_mut(queue) {
+                    Some(base) => {
+                        let now = self.clock.now_secs();
+                        base.sweep(now);
+                        let messages = if timed_out || base.available(count, now) >= min_count {
+                            base.get(count, auto_ack, lease)?
+                        } else {
+                            Vec::new()
+                        };
+                        state.persist(queue)?;
+                        messages
+                    }
+                    None => Vec::new(),
+                }
+            };
+
+            if !messages.is_empty() || count == 0 || timed_out {
+                return Ok(messages);
+            }
+
+            let _ = timeout_at(deadline, notified).await;
+        }
     }
 
-    async fn purge(&self) -> Result<(), String> {
-        self.inner.lock().await.purge()
+    async fn delete(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        lease_token: Option<&str>,
+    ) -> Result<Vec<String>, StorageError> {
+        let mut state = self.inner.write().await;
+        let deleted = match state.queues.get_mut(queue) {
+            Some(base) => base.delete(ids, lease_token)?,
+            None if config().strict_delete => {
+                return Err(StorageError::NotFound(format!("queue {queue}")));
+            }
+            None => return Ok(Vec::new()),
+        };
+        state.persist(queue)?;
+        // Frees room under `max_in_flight` for consumers waiting in `get`.
+        self.available.notify_waiters();
+        Ok(deleted)
     }
 
-    async fn retry(&self, ids: Vec<String>) -> Result<(), String> {
-        self.inner.lock().await.retry(ids)
+    async fn purge(&self, queue: &str) -> Result<usize, StorageError> {
+        let mut state = self.inner.write().await;
+        let purged = match state.queues.get_mut(queue) {
+            Some(base) => base.purge()?,
+            None => return Ok(0),
+        };
+        state.persist(queue)?;
+        Ok(purged)
     }
 
-    async fn peek(&self, count: usize) -> Result<Vec<Message>, String> {
-        self.inner.lock().await.peek(count)
+    async fn purge_state(
+        &self,
+        queue: &str,
+        message_state: MessageState,
+    ) -> Result<usize, StorageError> {
+        let mut state = self.inner.write().await;
+        let purged = match state.queues.get_mut(queue) {
+            Some(base) => base.purge_state(message_state),
+            None => return Ok(0),
+        };
+        state.persist(queue)?;
+        // Clearing processing messages frees room under `max_in_flight`.
+        self.available.notify_waiters();
+        Ok(purged)
+    }
+
+    async fn retry(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        front: bool,
+        delay_secs: Option<u64>,
+    ) -> Result<Retried, StorageError> {
+        let mut state = self.inner.write().await;
+        let retried = match state.queues.get_mut(queue) {
+            Some(base) => base.retry(ids, front, delay_secs)?,
+            None => return Ok(Retried::default()),
+        };
+        state.persist(queue)?;
+        self.available.notify_waiters();
+        Ok(retried)
+    }
+
+    async fn requeue_all(&self, queue: &str) -> Result<Vec<String>, StorageError> {
+        let mut state = self.inner.write().await;
+        let requeued = match state.queues.get_mut(queue) {
+            Some(base) => base.requeue_all(),
+            None => return Ok(Vec::new()),
+        };
+        state.persist(queue)?;
+        self.available.notify_waiters();
+        Ok(requeued)
+    }
+
+    async fn extend_lock(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        extra_secs: u64,
+        lease_token: Option<&str>,
+    ) -> Result<usize, StorageError> {
+        let mut state = self.inner.write().await;
+        let extended = match state.queues.get_mut(queue) {
+            Some(base) => base.extend_lock(ids, extra_secs, lease_token)?,
+            None => return Err(StorageError::NotFound(format!("queue {queue}"))),
+        };
+        state.persist(queue)?;
+        Ok(extended)
+    }
+
+    async fn peek(
+        &self,
+        queue: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<MessagePage, StorageError> {
+        let now = self.clock.now_secs();
+        {
+            let state = self.inner.read().await;
+            match state.queues.get(queue) {
+                Some(base) if !base.needs_sweep(now) => return Ok(base.peek(offset, count, now)),
+                Some(_) => {}
+                None => return Ok(MessagePage::default()),
+            }
+        }
+
+        // Lapsed locks and expired messages must be cleared up first, which
+        // needs the write lock.
+        let mut state = self.inner.write().await;
+        let page = match state.queues.get_mut(queue) {
+            Some(base) => {
+                base.sweep(now);
+                base.peek(offset, count, now)
+            }
+            None => return Ok(MessagePage::default()),
+        };
+        state.persist(queue)?;
+        Ok(page)
+    }
+
+    async fn search(
+        &self,
+        queue: &str,
+        filter: &SearchFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Message>, StorageError> {
+        Ok(match self.inner.read().await.queues.get(queue) {
+            Some(base) => base.search(filter, offset, limit),
+            None => Vec::new(),
+        })
+    }
+
+    async fn amend(
+        &self,
+        queue: &str,
+        id: &str,
+        body: MessageBody,
+        signature: Option<String>,
+        structured_body: Option<serde_json::Value>,
+    ) -> Result<Option<Message>, StorageError> {
+        let mut state = self.inner.write().await;
+        let amended = match state.queues.get_mut(queue) {
+            Some(base) => base.amend(id, body, signature, structured_body),
+            None => return Ok(None),
+        };
+        state.persist(queue)?;
+        Ok(amended)
+    }
+
+    async fn get_by_id(&self, queue: &str, id: &str) -> Result<Option<Message>, StorageError> {
+        Ok(self
+            .inner
+            .read()
+            .await
+            .queues
+            .get(queue)
+            .and_then(|base| base.get_by_id(id)))
+    }
+
+    async fn dead_letters(&self, queue: &str, count: usize) -> Result<Vec<Message>, StorageError> {
+        match self.inner.read().await.queues.get(queue) {
+            Some(base) => base.dead_letters(count),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn expired(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Result<Vec<ExpiredMessage>, StorageError> {
+        match self.inner.read().await.queues.get(queue) {
+            Some(base) => Ok(base.expired(count)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn requeue_dead_letters(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+    ) -> Result<(), StorageError> {
+        let mut state = self.inner.write().await;
+        match state.queues.get_mut(queue) {
+            Some(base) => base.requeue_dead_letters(ids)?,
+            None => return Ok(()),
+        }
+        state.persist(queue)?;
+        self.available.notify_waiters();
+        Ok(())
+    }
+
+    async fn list_queues(&self) -> Result<Vec<String>, StorageError> {
+        let mut queues: Vec<String> = self.inner.read().await.queues.keys().cloned().collect();
+        queues.sort();
+        Ok(queues)
+    }
+
+    async fn register_group(&self, queue: &str, group: &str) -> Result<bool, StorageError> {
+        let mut state = self.inner.write().await;
+        if !state.groups.entry(queue.to_string()).or_default().insert(group.to_string()) {
+            return Ok(false);
+        }
+        state.queue_mut(&group_queue(queue, group));
+        state.persist_group(WalRecord::Group {
+            queue: queue.to_string(),
+            group: group.to_string(),
+        })?;
+        Ok(true)
+    }
+
+    async fn unregister_group(&self, queue: &str, group: &str) -> Result<usize, StorageError> {
+        let mut state = self.inner.write().await;
+        let removed = state.groups.get_mut(queue).is_some_and(|groups| groups.remove(group));
+        if !removed {
+            return Err(StorageError::NotFound(format!("group {group} of queue {queue}")));
+        }
+        if state.groups.get(queue).is_some_and(|groups| groups.is_empty()) {
+            state.groups.remove(queue);
+        }
+        state.persist_group(WalRecord::Ungroup {
+            queue: queue.to_string(),
+            group: group.to_string(),
+        })?;
+
+        let target = group_queue(queue, group);
+        let purged = match state.queues.get_mut(&target) {
+            Some(base) => base.purge()?,
+            None => 0,
+        };
+        state.persist(&target)?;
+        state.queues.remove(&target);
+        self.available.notify_waiters();
+        Ok(purged)
+    }
+
+    async fn groups(&self, queue: &str) -> Result<Vec<String>, StorageError> {
+        let state = self.inner.read().await;
+        Ok(state.groups.get(queue).into_iter().flatten().cloned().collect())
+    }
+
+    async fn stats(&self, queue: &str) -> Result<QueueStats, StorageError> {
+        match self.inner.read().await.queues.get(queue) {
+            Some(base) => base.stats(),
+            None => Ok(QueueStats::default()),
+        }
+    }
+
+    async fn remove_expired(&self, queue: &str) -> Result<usize, StorageError> {
+        let mut state = self.inner.write().await;
+        let expired = match state.queues.get_mut(queue) {
+            Some(base) => base.remove_expired(self.clock.now_secs()),
+            None => return Ok(0),
+        };
+        state.persist(queue)?;
+        Ok(expired)
+    }
+
+    async fn export(&self) -> Result<Vec<QueueExport>, StorageError> {
+        let state = self.inner.read().await;
+        let mut queues: Vec<QueueExport> = state
+            .queues
+            .iter()
+            .map(|(queue, base)| QueueExport {
+                queue: queue.clone(),
+                messages: base.messages().cloned().map(decompressed).collect(),
+            })
+            .collect();
+        queues.sort_by(|a, b| a.queue.cmp(&b.queue));
+        Ok(queues)
+    }
+
+    async fn import(&self, queues: Vec<QueueExport>, replace: bool) -> Result<usize, StorageError> {
+        let mut state = self.inner.write().await;
+        if replace {
+            let names: Vec<String> = state.queues.keys().cloned().collect();
+            for name in names {
+                state.queue_mut(&name).purge()?;
+                state.persist(&name)?;
+            }
+        }
+
+        let mut imported = 0;
+        for QueueExport { queue, messages } in queues {
+            let base = state.queue_mut(&queue);
+            for message in messages {
+                if !base.ids.contains(&message.id) {
+                    base.restore(message);
+                    imported += 1;
+                }
+            }
+            state.persist(&queue)?;
+        }
+        self.available.notify_waiters();
+        Ok(imported)
+    }
+
+    /// Compacts the write-ahead log, if enabled, so the next startup replays a
+    /// snapshot instead of the full history.
+    async fn shutdown(&self) -> Result<(), StorageError> {
+        let mut state = self.inner.write().await;
+        let snapshot = MemoryState::snapshot(&state.queues, &state.groups);
+        match state.log.as_mut() {
+            Some(log) => log.compact(snapshot),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::Config;
+
+    #[tokio::test]
+    async fn reads_share_the_lock() {
+        let storage = MemoryStorage::new();
+        storage.add("jobs", Message::new("a")).await.unwrap();
+
+        // With the write lock, any of these would wait for this reader forever.
+        let _reader = storage.inner.read().await;
+        let reads = async {
+            tokio::join!(
+                storage.stats("jobs"),
+                storage.peek("jobs", 0, 10),
+                storage.dead_letters("jobs", 10),
+                storage.list_queues(),
+                storage.export(),
+            )
+        };
+        let (stats, page, dead_letters, queues, export) =
+            tokio::time::timeout(Duration::from_secs(1), reads)
+                .await
+                .expect("a read waited for the write lock");
+        assert_eq!(stats.unwrap().ready_count, 1);
+        assert_eq!(page.unwrap().messages.len(), 1);
+        assert!(dead_letters.unwrap().is_empty());
+        assert_eq!(queues.unwrap(), ["jobs"]);
+        assert_eq!(export.unwrap()[0].messages.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ids_are_reusable_once_removed() {
+        let storage = MemoryStorage::new();
+        let message = Message::new("a");
+        let id = message.id.to_string();
+        storage.add("jobs", message.clone()).await.unwrap();
+        let conflict = storage.add("jobs", message.clone()).await;
+        assert!(matches!(conflict, Err(StorageError::Conflict(_))));
+
+        storage.get("jobs", 1, 1, Duration::ZERO, false, None).await.unwrap();
+        let conflict = storage.add("jobs", message.clone()).await;
+        assert!(matches!(conflict, Err(StorageError::Conflict(_))));
+        storage.delete("jobs", vec![id], None).await.unwrap();
+        assert!(storage.add("jobs", message.clone()).await.unwrap().is_some());
+
+        storage.purge("jobs").await.unwrap();
+        assert!(storage.add("jobs", message).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn retried_group_messages_keep_their_priority() {
+        let storage = MemoryStorage::new();
+        let grouped =
+            |priority| Message { group_id: Some("g".into()), priority, ..Message::new("") };
+        let first = grouped(0);
+        let first_id = first.id.to_string();
+        storage.add("jobs", first).await.unwrap();
+        storage.get("jobs", 1, 1, Duration::ZERO, false, None).await.unwrap();
+
+        let other = Message { priority: 5, ..Message::new("") };
+        let second = grouped(9);
+        let (other_id, second_id) = (other.id, second.id);
+        storage.add("jobs", other).await.unwrap();
+        storage.add("jobs", second).await.unwrap();
+        storage.retry("jobs", vec![first_id.clone()], false, Some(0)).await.unwrap();
+
+        let page = storage.peek("jobs", 0, 10).await.unwrap();
+        let order: Vec<String> =
+            page.messages.iter().map(|message| message.id.to_string()).collect();
+        assert_eq!(order, [second_id.to_string(), other_id.to_string(), first_id]);
+    }
+
+
+    /// Returns a storage holding one dead, one processing and one ready message.
+    async fn one_of_each_state() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        add_one_of_each_state(&storage).await;
+        storage
+    }
+
+    /// Leaves one dead, one processing and one ready message in `storage`.
+    async fn add_one_of_each_state(storage: &MemoryStorage) {
+        let dead = Message { max_retries: Some(0), ..Message::new("dead") };
+        let dead_id = dead.id.to_string();
+        storage.add("jobs", dead).await.unwrap();
+        storage.get("jobs", 1, 1, Duration::ZERO, false, None).await.unwrap();
+        storage.retry("jobs", vec![dead_id], false, Some(0)).await.unwrap();
+        storage.add("jobs", Message::new("processing")).await.unwrap();
+        storage.get("jobs", 1, 1, Duration::ZERO, false, None).await.unwrap();
+        storage.add("jobs", Message::new("ready")).await.unwrap();
+    }
+
+    fn counts(stats: QueueStats) -> (usize, usize, usize) {
+        (stats.ready_count, stats.processing_count, stats.dead_letter_count)
+    }
+
+    #[tokio::test]
+    async fn purge_state_removes_only_that_state() {
+        let cases = [
+            (MessageState::Ready, 1, (0, 1, 1)),
+            (MessageState::Processing, 1, (1, 0, 1)),
+            (MessageState::Dead, 1, (1, 1, 0)),
+            (MessageState::Done, 0, (1, 1, 1)),
+        ];
+        for (state, purged, left) in cases {
+            let storage = one_of_each_state().await;
+            assert_eq!(counts(storage.stats("jobs").await.unwrap()), (1, 1, 1));
+            assert_eq!(storage.purge_state("jobs", state).await.unwrap(), purged, "{state:?}");
+            assert_eq!(counts(storage.stats("jobs").await.unwrap()), left, "{state:?}");
+        }
+    }
+
+
+    #[tokio::test]
+    async fn lapsed_locks_requeue_without_counting_a_retry() {
+        let clock = Arc::new(MockClock::now());
+        let storage = MemoryStorage::with_clock(clock.clone());
+        storage.add("jobs", Message::new("work")).await.unwrap();
+        let taken = storage.get("jobs", 1, 1, Duration::ZERO, false, None).await.unwrap();
+        let timeout = config().visibility_timeout_secs as i64;
+        assert_eq!(taken[0].lock_until, Some(clock.now_secs() + timeout));
+
+        clock.advance(Duration::from_secs(timeout as u64 - 1));
+        assert!(storage.get("jobs", 1, 1, Duration::ZERO, false, None).await.unwrap().is_empty());
+        clock.advance(Duration::from_secs(1));
+        let again = storage.get("jobs", 1, 1, Duration::ZERO, false, None).await.unwrap();
+        assert_eq!(again[0].id, taken[0].id);
+        assert_eq!(again[0].retry_count, 0);
+    }
+
+    /// Returns a path for a write-ahead log that doesn't exist yet.
+    fn wal_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("smql-test-{}.wal", Uuid::new_v4()))
+    }
+
+    /// Asserts that `storage` holds what `add_one_of_each_state` and a
+    /// `billing` group left in the `jobs` queue.
+    async fn assert_rebuilt(storage: &MemoryStorage) {
+        assert_eq!(counts(storage.stats("jobs").await.unwrap()), (1, 1, 1));
+        let ready = storage.peek("jobs", 0, 10).await.unwrap().messages;
+        assert_eq!(ready[0].body, MessageBody::Text("ready".into()));
+        let dead = storage.dead_letters("jobs", 10).await.unwrap();
+        assert_eq!(dead[0].body, MessageBody::Text("dead".into()));
+        assert_eq!(dead[0].state, MessageState::Dead);
+        assert_eq!(storage.groups("jobs").await.unwrap(), ["billing"]);
+    }
+
+    #[tokio::test]
+    async fn wal_rebuilds_every_state_on_reopen() {
+        let path = wal_path();
+        let storage = MemoryStorage::with_wal(&path).unwrap();
+        add_one_of_each_state(&storage).await;
+        storage.register_group("jobs", "billing").await.unwrap();
+        storage.add("gone", Message::new("purged")).await.unwrap();
+        storage.purge("gone").await.unwrap();
+        drop(storage);
+
+        let reopened = MemoryStorage::with_wal(&path).unwrap();
+        assert_rebuilt(&reopened).await;
+        assert_eq!(reopened.stats("gone").await.unwrap().ready_count, 0);
+        let export = reopened.export().await.unwrap();
+        let jobs = export.iter().find(|export| export.queue == "jobs").unwrap();
+        let processing =
+            jobs.messages.iter().find(|message| message.state == MessageState::Processing);
+        assert!(processing.unwrap().lock_until.is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wal_rebuilds_every_state_after_compaction() {
+        crate::set_test_config(Config { wal_compact_every: 1, ..Config::default() });
+        let path = wal_path();
+        let storage = MemoryStorage::with_wal(&path).unwrap();
+        add_one_of_each_state(&storage).await;
+        storage.add("jobs", Message::new("acked")).await.unwrap();
+        let acked = storage.get("jobs", 2, 1, Duration::ZERO, false, None).await.unwrap();
+        let acked: Vec<String> = acked
+            .into_iter()
+            .filter(|message| message.body == MessageBody::Text("acked".into()))
+            .map(|message| message.id.to_string())
+            .collect();
+        storage.delete("jobs", acked, None).await.unwrap();
+        storage.register_group("jobs", "billing").await.unwrap();
+        drop(storage);
+
+        // Compacted after every write, the log holds only the current state:
+        // a group and three messages, the ready one having been taken.
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 4, "{log}");
+        let reopened = MemoryStorage::with_wal(&path).unwrap();
+        assert_eq!(counts(reopened.stats("jobs").await.unwrap()), (0, 2, 1));
+        assert_eq!(reopened.groups("jobs").await.unwrap(), ["billing"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wal_survives_a_torn_final_record() {
+        let path = wal_path();
+        let storage = MemoryStorage::with_wal(&path).unwrap();
+        add_one_of_each_state(&storage).await;
+        storage.register_group("jobs", "billing").await.unwrap();
+        drop(storage);
+
+        // A crash part way through appending the next record.
+        let mut log = std::fs::read_to_string(&path).unwrap();
+        let record = serde_json::to_string(&WalRecord::Put {
+            queue: "jobs".into(),
+            message: Box::new(Message::new("torn")),
+        })
+        .unwrap();
+        log.push_str(&record[..record.len() / 2]);
+        std::fs::write(&path, log).unwrap();
+
+        let reopened = MemoryStorage::with_wal(&path).unwrap();
+        assert_rebuilt(&reopened).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wal_refuses_a_corrupt_record_before_the_end() {
+        let path = wal_path();
+        std::fs::write(&path, "not a record\n").unwrap();
+        assert!(MemoryStorage::with_wal(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn over_capacity_requeues_the_longest_processing() {
+        crate::set_test_config(Config { processing_capacity: Some(2), ..Config::default() });
+        let clock = Arc::new(MockClock::now());
+        let storage = MemoryStorage::with_clock(clock.clone());
+        let take = || storage.get("jobs", 1, 1, Duration::ZERO, false, None);
+        for body in ["a", "b", "c"] {
+            storage.add("jobs", Message::new(body)).await.unwrap();
+        }
+        let oldest = take().await.unwrap().remove(0);
+        storage.retry("jobs", vec![oldest.id.to_string()], true, Some(0)).await.unwrap();
+        assert_eq!(take().await.unwrap()[0].retry_count, 1);
+        clock.advance(Duration::from_secs(1));
+        take().await.unwrap();
+        assert_eq!(storage.stats("jobs").await.unwrap().total_evicted, 0);
+
+        clock.advance(Duration::from_secs(1));
+        take().await.unwrap();
+        let stats = storage.stats("jobs").await.unwrap();
+        assert_eq!(counts(stats.clone()), (1, 2, 0));
+        assert_eq!(serde_json::to_value(&stats).unwrap()["total_evicted"], 1);
+        let ready = storage.peek("jobs", 0, 10).await.unwrap().messages;
+        assert_eq!(ready[0].id, oldest.id);
+        assert_eq!(ready[0].state, MessageState::Ready);
+        assert_eq!(ready[0].retry_count, 1);
+        assert_eq!(ready[0].lock_until, None);
     }
 }
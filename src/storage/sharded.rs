@@ -0,0 +1,650 @@
+use super::{
+    combined_stats, require_processing, Lease, MemoryState, MemoryStorage, Retried, Storage,
+    StorageError,
+};
+use crate::clock::{Clock, SharedClock, SystemClock};
+use crate::{
+    config, ExpiredMessage, Message, MessageBody, MessagePage, MessageState, Order, QueueExport,
+    QueueStats, SearchFilter,
+};
+use async_trait::async_trait;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, RwLockReadGuard, RwLockWriteGuard};
+use tokio::time::{timeout_at, Instant};
+
+/// In-memory storage that spreads every queue over several `MemoryStorage`
+/// shards, each behind its own lock, so operations landing on different shards
+/// run in parallel.
+///
+/// A message is placed by hashing its `group_id`, else its `dedup_id`, else its
+/// id, so a group stays in order and duplicates still meet in the same shard. `get` takes from the shards in
+/// turn, starting at a different shard each call. Order is therefore only kept
+/// within a shard: across the queue, a later or lower priority message may be
+/// delivered before an earlier or higher priority one.
+///
+/// `max_queue_depth` and `max_in_flight` are checked against the whole queue,
+/// but not atomically with the add or get, so concurrent requests may overshoot
+/// them slightly. There is no write-ahead log.
+pub struct ShardedStorage {
+    shards: Vec<MemoryStorage>,
+    /// Shard the next `get` starts from.
+    next: AtomicUsize,
+    /// Wakes consumers long-polling in `get` when any shard gains messages.
+    available: Arc<Notify>,
+    clock: SharedClock,
+}
+
+impl ShardedStorage {
+    /// Creates a storage with `count` shards, at least one.
+    pub fn new(count: usize) -> Self {
+        Self::with_clock(count, Arc::new(SystemClock))
+    }
+
+    /// Creates a storage with `count` shards that all tell the time with `clock`.
+    pub fn with_clock(count: usize, clock: Arc<dyn Clock>) -> Self {
+        let available = Arc::new(Notify::new());
+        let clock = SharedClock::new(clock);
+        Self {
+            shards: (0..count.max(1))
+                .map(|_| MemoryStorage::sharing(available.clone(), clock.clone()))
+                .collect(),
+            next: AtomicUsize::new(0),
+            available,
+            clock,
+        }
+    }
+
+    /// Returns the index of the shard a message belongs in. A message group
+    /// stays on one shard, so its messages are still delivered in order.
+    fn shard_index(&self, message: &Message) -> usize {
+        let mut hasher = DefaultHasher::new();
+        match (&message.group_id, &message.dedup_id) {
+            (Some(group_id), _) => group_id.hash(&mut hasher),
+            (None, Some(dedup_id)) => dedup_id.hash(&mut hasher),
+            (None, None) => message.id.hash(&mut hasher),
+        }
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    async fn read_all(&self) -> Vec<RwLockReadGuard<'_, MemoryState>> {
+        let mut states = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            states.push(shard.inner.read().await);
+        }
+        states
+    }
+
+    /// Locks every shard for writing, always in the same order.
+    async fn write_all(&self) -> Vec<RwLockWriteGuard<'_, MemoryState>> {
+        let mut states = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            states.push(shard.inner.write().await);
+        }
+        states
+    }
+
+    /// Returns true once the ready and processing messages of all shards reach
+    /// the maximum queue depth, in the queue or any of its consumer groups.
+    async fn is_full(&self, queue: &str) -> bool {
+        let Some(max_depth) = config().max_queue_depth else {
+            return false;
+        };
+        let states = self.read_all().await;
+        states[0].targets(queue).iter().any(|target| {
+            let depth: usize = states
+                .iter()
+                .filter_map(|state| state.queues.get(target))
+                .map(|base| base.queue.len() + base.processing.len())
+                .sum();
+            depth >= max_depth
+        })
+    }
+
+    /// Returns how many of `count` messages may be delivered without exceeding
+    /// `max_in_flight` across all shards.
+    async fn in_flight_allowance(&self, queue: &str, count: usize) -> usize {
+        let Some(max_in_flight) = config().max_in_flight else {
+            return count;
+        };
+        let processing: usize = self
+            .read_all()
+            .await
+            .iter()
+            .filter_map(|state| state.queues.get(queue))
+            .map(|base| base.processing.len())
+            .sum();
+        count.min(max_in_flight.saturating_sub(processing))
+    }
+
+    /// Returns how many messages of all shards a get could take right now, up
+    /// to `count`.
+    async fn available(&self, queue: &str, count: usize) -> usize {
+        let now = self.clock.now_secs();
+        self.read_all()
+            .await
+            .iter()
+            .filter_map(|state| state.queues.get(queue))
+            .map(|base| base.available(count, now))
+            .sum::<usize>()
+            .min(count)
+    }
+
+    /// Splits `ids` by the shard holding them as processing under `lease_token`,
+    /// after failing if any is not held so in any shard.
+    fn split_processing(
+        states: &[RwLockWriteGuard<'_, MemoryState>],
+        queue: &str,
+        ids: &[String],
+        lease_token: Option<&str>,
+    ) -> Result<Vec<Vec<String>>, StorageError> {
+        let holder = |id: &str| {
+            states.iter().position(|state| {
+                state.queues.get(queue).is_some_and(|base| base.is_held(id, lease_token))
+            })
+        };
+        require_processing(ids, |id| holder(id).is_some())?;
+
+        let mut split = vec![Vec::new(); states.len()];
+        for id in ids {
+            if let Some(shard) = holder(id) {
+                split[shard].push(id.clone());
+            }
+        }
+        Ok(split)
+    }
+}
+
+#[async_trait]
+impl Storage for ShardedStorage {
+    async fn add(&self, queue: &str, msg: Message) -> Result<Option<Message>, StorageError> {
+        if self.is_full(queue).await {
+            return Ok(None);
+        }
+        self.shards[self.shard_index(&msg)].add(queue, msg).await
+    }
+
+    async fn add_batch(&self, queue: &str, msgs: Vec<Message>) -> Result<usize, StorageError> {
+        let mut added = 0;
+        for msg in msgs {
+            if self.add(queue, msg).await?.is_none() {
+                break;
+            }
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    async fn get(
+        &self,
+        queue: &str,
+        count: usize,
+        min_count: usize,
+        wait: Duration,
+        auto_ack: bool,
+        lease: Option<&Lease>,
+    ) -> Result<Vec<Message>, StorageError> {
+        let deadline = Instant::now() + wait;
+        let min_count = min_count.clamp(1, count.max(1));
+        loop {
+            // Register for notifications before checking the shards so an add
+            // landing in between is not missed.
+            let notified = self.available.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let timed_out = Instant::now() >= deadline;
+            let mut remaining = self.in_flight_allowance(queue, count).await;
+            if !timed_out && min_count > 1 && self.available(queue, remaining).await < min_count {
+                remaining = 0;
+            }
+            let mut messages = Vec::new();
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            for offset in 0..self.shards.len() {
+                if remaining == 0 {
+                    break;
+                }
+                let shard = &self.shards[(start + offset) % self.shards.len()];
+                let taken = shard.get(queue, remaining, 1, Duration::ZERO, auto_ack, lease).await?;
+                remaining -= taken.len();
+                messages.extend(taken);
+            }
+
+            if !messages.is_empty() || count == 0 || timed_out {
+                return Ok(messages);
+            }
+
+            let _ = timeout_at(deadline, notified).await;
+        }
+    }
+
+    async fn delete(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        lease_token: Option<&str>,
+    ) -> Result<Vec<String>, StorageError> {
+        if !config().strict_delete {
+            let mut deleted = Vec::new();
+            for shard in &self.shards {
+                deleted.extend(shard.delete(queue, ids.clone(), lease_token).await?);
+            }
+            return Ok(deleted);
+        }
+
+        // Check every shard before deleting anything, so a missing id leaves
+        // the queue untouched.
+        let mut states = self.write_all().await;
+        if states.iter().all(|state| !state.queues.contains_key(queue)) {
+            return Err(StorageError::NotFound(format!("queue {queue}")));
+        }
+        let split = Self::split_processing(&states, queue, &ids, lease_token)?;
+
+        let mut deleted = Vec::new();
+        for (state, ids) in states.iter_mut().zip(split) {
+            if let Some(base) = state.queues.get_mut(queue) {
+                deleted.extend(base.delete(ids, lease_token)?);
+                state.persist(queue)?;
+            }
+        }
+        self.available.notify_waiters();
+        Ok(deleted)
+    }
+
+    async fn purge(&self, queue: &str) -> Result<usize, StorageError> {
+        let mut purged = 0;
+        for shard in &self.shards {
+            purged += shard.purge(queue).await?;
+        }
+        Ok(purged)
+    }
+
+    async fn purge_state(&self, queue: &str, state: MessageState) -> Result<usize, StorageError> {
+        let mut purged = 0;
+        for shard in &self.shards {
+            purged += shard.purge_state(queue, state).await?;
+        }
+        Ok(purged)
+    }
+
+    async fn retry(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        front: bool,
+        delay_secs: Option<u64>,
+    ) -> Result<Retried, StorageError> {
+        let mut retried = Retried::default();
+        for shard in &self.shards {
+            let shard_retried = shard.retry(queue, ids.clone(), front, delay_secs).await?;
+            retried.ids.extend(shard_retried.ids);
+            retried.already_ready.extend(shard_retried.already_ready);
+        }
+        Ok(retried)
+    }
+
+    async fn requeue_all(&self, queue: &str) -> Result<Vec<String>, StorageError> {
+        let mut requeued = Vec::new();
+        for shard in &self.shards {
+            requeued.extend(shard.requeue_all(queue).await?);
+        }
+        Ok(requeued)
+    }
+
+    async fn extend_lock(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        extra_secs: u64,
+        lease_token: Option<&str>,
+    ) -> Result<usize, StorageError> {
+        let mut states = self.write_all().await;
+        if states.iter().all(|state| !state.queues.contains_key(queue)) {
+            return Err(StorageError::NotFound(format!("queue {queue}")));
+        }
+        // Locks that have already run out are released first, so they can't be extended.
+        let now = self.clock.now_secs();
+        for state in states.iter_mut() {
+            if let Some(base) = state.queues.get_mut(queue) {
+                base.requeue_expired(now);
+                state.persist(queue)?;
+            }
+        }
+        let split = Self::split_processing(&states, queue, &ids, lease_token)?;
+
+        let mut extended = 0;
+        for (state, ids) in states.iter_mut().zip(split) {
+            if let Some(base) = state.queues.get_mut(queue) {
+                extended += base.extend_lock(ids, extra_secs, lease_token)?;
+                state.persist(queue)?;
+            }
+        }
+        Ok(extended)
+    }
+
+    async fn peek(
+        &self,
+        queue: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<MessagePage, StorageError> {
+        let mut messages = Vec::new();
+        let mut total = 0;
+        for shard in &self.shards {
+            let page = shard.peek(queue, 0, offset.saturating_add(count)).await?;
+            messages.extend(page.messages);
+            total += page.total;
+        }
+        messages.sort_by_key(delivery_order);
+        let messages = messages.into_iter().skip(offset).take(count).collect();
+        Ok(MessagePage { messages, total })
+    }
+
+    async fn search(
+        &self,
+        queue: &str,
+        filter: &SearchFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Message>, StorageError> {
+        let mut messages = Vec::new();
+        for shard in &self.shards {
+            messages.extend(shard.search(queue, filter, 0, offset.saturating_add(limit)).await?);
+        }
+        messages.sort_by_key(|message| match message.state {
+            MessageState::Processing => (1, (Reverse(0), message.created_at)),
+            _ => (0, delivery_order(message)),
+        });
+        Ok(messages.into_iter().skip(offset).take(limit).collect())
+    }
+
+    async fn amend(
+        &self,
+        queue: &str,
+        id: &str,
+        body: MessageBody,
+        signature: Option<String>,
+        structured_body: Option<serde_json::Value>,
+    ) -> Result<Option<Message>, StorageError> {
+        for shard in &self.shards {
+            let amended = shard
+                .amend(queue, id, body.clone(), signature.clone(), structured_body.clone())
+                .await?;
+            if let Some(message) = amended {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_by_id(&self, queue: &str, id: &str) -> Result<Option<Message>, StorageError> {
+        for shard in &self.shards {
+            if let Some(message) = shard.get_by_id(queue, id).await? {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn dead_letters(&self, queue: &str, count: usize) -> Result<Vec<Message>, StorageError> {
+        let mut messages = Vec::new();
+        for shard in &self.shards {
+            messages.extend(shard.dead_letters(queue, count).await?);
+        }
+        messages.sort_by_key(|message| message.created_at);
+        messages.truncate(count);
+        Ok(messages)
+    }
+
+    async fn expired(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Result<Vec<ExpiredMessage>, StorageError> {
+        let mut messages = Vec::new();
+        for shard in &self.shards {
+            messages.extend(shard.expired(queue, count).await?);
+        }
+        // Each shard keeps its own archive; show no more than one queue would.
+        messages.sort_by_key(|expired| Reverse(expired.expired_at));
+        messages.truncate(count.min(config().expired_archive_size));
+        Ok(messages)
+    }
+
+    async fn requeue_dead_letters(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+    ) -> Result<(), StorageError> {
+        for shard in &self.shards {
+            shard.requeue_dead_letters(queue, ids.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn list_queues(&self) -> Result<Vec<String>, StorageError> {
+        let mut queues: Vec<String> = self
+            .read_all()
+            .await
+            .iter()
+            .flat_map(|state| state.queues.keys().cloned())
+            .collect();
+        queues.sort();
+        queues.dedup();
+        Ok(queues)
+    }
+
+    /// Registers the group on every shard, since any of them may receive the
+    /// queue's next message.
+    async fn register_group(&self, queue: &str, group: &str) -> Result<bool, StorageError> {
+        let mut registered = false;
+        for shard in &self.shards {
+            registered |= shard.register_group(queue, group).await?;
+        }
+        Ok(registered)
+    }
+
+    async fn unregister_group(&self, queue: &str, group: &str) -> Result<usize, StorageError> {
+        let mut removed = 0;
+        for shard in &self.shards {
+            removed += shard.unregister_group(queue, group).await?;
+        }
+        Ok(removed)
+    }
+
+    async fn groups(&self, queue: &str) -> Result<Vec<String>, StorageError> {
+        self.shards[0].groups(queue).await
+    }
+
+    async fn stats(&self, queue: &str) -> Result<QueueStats, StorageError> {
+        let states = self.read_all().await;
+        Ok(combined_stats(states.iter().filter_map(|state| state.queues.get(queue))))
+    }
+
+    async fn remove_expired(&self, queue: &str) -> Result<usize, StorageError> {
+        let mut expired = 0;
+        for shard in &self.shards {
+            expired += shard.remove_expired(queue).await?;
+        }
+        Ok(expired)
+    }
+
+    async fn export(&self) -> Result<Vec<QueueExport>, StorageError> {
+        let mut queues: BTreeMap<String, Vec<Message>> = BTreeMap::new();
+        for shard in &self.shards {
+            for QueueExport { queue, messages } in shard.export().await? {
+                queues.entry(queue).or_default().extend(messages);
+            }
+        }
+        Ok(queues
+            .into_iter()
+            .map(|(queue, mut messages)| {
+                messages.sort_by_key(|message| message.created_at);
+                QueueExport { queue, messages }
+            })
+            .collect())
+    }
+
+    async fn import(&self, queues: Vec<QueueExport>, replace: bool) -> Result<usize, StorageError> {
+        let mut split: Vec<HashMap<String, Vec<Message>>> = vec![HashMap::new(); self.shards.len()];
+        for QueueExport { queue, messages } in queues {
+            for message in messages {
+                let shard = self.shard_index(&message);
+                split[shard].entry(queue.clone()).or_default().push(message);
+            }
+        }
+
+        let mut imported = 0;
+        for (shard, queues) in self.shards.iter().zip(split) {
+            let queues = queues
+                .into_iter()
+                .map(|(queue, messages)| QueueExport { queue, messages })
+                .collect();
+            imported += shard.import(queues, replace).await?;
+        }
+        Ok(imported)
+    }
+}
+
+/// Sorts ready messages from different shards in the order `get` takes them
+/// within a shard: by priority, then by age as set by `Order`.
+fn delivery_order(message: &Message) -> (Reverse<u8>, i64) {
+    let age_order = match config().order {
+        Order::Fifo => message.created_at,
+        Order::Lifo => -message.created_at,
+    };
+    (Reverse(message.priority), age_order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{set_test_config, Config};
+
+    const SHARDS: usize = 8;
+
+    async fn take(storage: &ShardedStorage, count: usize) -> Vec<Message> {
+        storage.get("jobs", count, 1, Duration::ZERO, false, None).await.unwrap()
+    }
+
+    /// Returns how many ready messages each shard holds.
+    async fn shard_depths(storage: &ShardedStorage) -> Vec<usize> {
+        let mut depths = Vec::new();
+        for shard in &storage.shards {
+            depths.push(shard.stats("jobs").await.unwrap().ready_count);
+        }
+        depths
+    }
+
+    #[tokio::test]
+    async fn duplicates_meet_in_one_shard() {
+        let storage = ShardedStorage::new(SHARDS);
+        let first = Message { dedup_id: Some("order-1".into()), ..Message::new("a") };
+        let duplicate = Message { dedup_id: Some("order-1".into()), ..Message::new("b") };
+        assert_eq!(storage.shard_index(&first), storage.shard_index(&duplicate));
+
+        storage.add("jobs", first.clone()).await.unwrap();
+        let returned = storage.add("jobs", duplicate).await.unwrap().unwrap();
+        assert_eq!(returned.id, first.id);
+        assert_eq!(storage.stats("jobs").await.unwrap().ready_count, 1);
+    }
+
+    #[tokio::test]
+    async fn groups_stay_in_one_shard() {
+        let storage = ShardedStorage::new(SHARDS);
+        let mut ids = Vec::new();
+        for i in 0..20 {
+            // A dedup id would place the message elsewhere, were it not grouped.
+            let message = Message {
+                group_id: Some("customer-1".into()),
+                dedup_id: Some(format!("order-{i}")),
+                ..Message::new(i.to_string())
+            };
+            ids.push(message.id);
+            storage.add("jobs", message).await.unwrap();
+        }
+        let depths = shard_depths(&storage).await;
+        assert_eq!(depths.iter().filter(|&&depth| depth > 0).count(), 1, "{depths:?}");
+
+        // Only the head of a group is deliverable, so the group keeps its order.
+        for id in ids {
+            let taken = take(&storage, SHARDS).await;
+            assert_eq!(taken.len(), 1);
+            assert_eq!(taken[0].id, id);
+            storage.delete("jobs", vec![id.to_string()], None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn get_takes_from_every_shard() {
+        let storage = ShardedStorage::new(SHARDS);
+        for i in 0..100 {
+            storage.add("jobs", Message::new(i.to_string())).await.unwrap();
+        }
+        let depths = shard_depths(&storage).await;
+        assert!(depths.iter().all(|&depth| depth > 0), "{depths:?}");
+
+        let taken = take(&storage, 100).await;
+        assert_eq!(taken.len(), 100);
+        let stats = storage.stats("jobs").await.unwrap();
+        assert_eq!((stats.ready_count, stats.processing_count), (0, 100));
+        assert!(take(&storage, 1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queue_depth_covers_every_shard() {
+        set_test_config(Config { max_queue_depth: Some(10), ..Config::default() });
+        let storage = ShardedStorage::new(SHARDS);
+        for i in 0..10 {
+            assert!(storage.add("jobs", Message::new(i.to_string())).await.unwrap().is_some());
+        }
+        // Full whichever shard the next message would land in.
+        for i in 0..20 {
+            assert!(storage.add("jobs", Message::new(i.to_string())).await.unwrap().is_none());
+        }
+        let taken = take(&storage, 1).await;
+        assert!(storage.add("jobs", Message::new("more")).await.unwrap().is_none());
+        storage.delete("jobs", vec![taken[0].id.to_string()], None).await.unwrap();
+        assert!(storage.add("jobs", Message::new("more")).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn in_flight_limit_covers_every_shard() {
+        set_test_config(Config { max_in_flight: Some(5), ..Config::default() });
+        let storage = ShardedStorage::new(SHARDS);
+        for i in 0..40 {
+            storage.add("jobs", Message::new(i.to_string())).await.unwrap();
+        }
+        let taken = take(&storage, 40).await;
+        assert_eq!(taken.len(), 5);
+        assert!(take(&storage, 40).await.is_empty());
+
+        storage.delete("jobs", vec![taken[0].id.to_string()], None).await.unwrap();
+        assert_eq!(take(&storage, 40).await.len(), 1);
+        assert_eq!(storage.stats("jobs").await.unwrap().processing_count, 5);
+    }
+
+    #[tokio::test]
+    async fn one_shard_keeps_strict_order() {
+        let storage = ShardedStorage::new(1);
+        let mut expected = Vec::new();
+        for (i, priority) in [0, 5, 0, 9, 5, 0, 9, 1].into_iter().enumerate() {
+            let message = Message { priority, ..Message::new(i.to_string()) };
+            expected.push((Reverse(priority), i, message.id));
+            storage.add("jobs", message).await.unwrap();
+        }
+        expected.sort();
+
+        let mut delivered = Vec::new();
+        while let Some(message) = take(&storage, 1).await.pop() {
+            delivered.push(message.id);
+        }
+        let expected: Vec<_> = expected.into_iter().map(|(_, _, id)| id).collect();
+        assert_eq!(delivered, expected);
+    }
+}
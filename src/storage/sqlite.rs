@@ -0,0 +1,656 @@
+use super::{Journal, Lease, MemoryStorage, Retried, Storage, StorageError};
+use crate::wal::WalRecord;
+use crate::{
+    ExpiredMessage, Message, MessageBody, MessagePage, MessageState, QueueExport, QueueStats,
+    SearchFilter,
+};
+use async_trait::async_trait;
+use std::ffi::{c_int, CStr, CString};
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        queue TEXT NOT NULL,
+        id TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        lock_until INTEGER,
+        retry_count INTEGER NOT NULL,
+        message TEXT NOT NULL,
+        PRIMARY KEY (queue, id)
+    );
+    CREATE TABLE IF NOT EXISTS groups (
+        queue TEXT NOT NULL,
+        name TEXT NOT NULL,
+        PRIMARY KEY (queue, name)
+    );
+";
+
+const PUT: &str = "
+    INSERT INTO messages (queue, id, seq, state, lock_until, retry_count, message)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+    ON CONFLICT (queue, id) DO UPDATE SET
+        seq = excluded.seq,
+        state = excluded.state,
+        lock_until = excluded.lock_until,
+        retry_count = excluded.retry_count,
+        message = excluded.message
+";
+
+/// How long a write waits for another connection to the database, such as
+/// the `sqlite3` shell, to let go of it.
+const BUSY_TIMEOUT_MS: c_int = 5_000;
+
+/// A `MemoryStorage` that keeps every message, with its state, `lock_until`
+/// and `retry_count`, in a table of a SQLite database, so queues survive a
+/// restart.
+///
+/// Each change is written in its own transaction before the call that made it
+/// returns. Deliveries are decided in memory under the storage's write lock,
+/// so concurrent `get`s never hand out the same message; the database is only
+/// read on startup and is meant for one server at a time.
+pub struct SqliteStorage {
+    inner: MemoryStorage,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if needed, and rebuilds the
+    /// queues it holds. Messages that were processing when the server stopped
+    /// are returned to their queue.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let (log, records) = SqliteLog::open(path)?;
+        let inner = MemoryStorage::replaying(Box::new(log), records, true)?;
+        Ok(Self { inner })
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn add(&self, queue: &str, msg: Message) -> Result<Option<Message>, StorageError> {
+        self.inner.add(queue, msg).await
+    }
+
+    async fn add_batch(&self, queue: &str, msgs: Vec<Message>) -> Result<usize, StorageError> {
+        self.inner.add_batch(queue, msgs).await
+    }
+
+    async fn get(
+        &self,
+        queue: &str,
+        count: usize,
+        min_count: usize,
+        wait: Duration,
+        auto_ack: bool,
+        lease: Option<&Lease>,
+    ) -> Result<Vec<Message>, StorageError> {
+        self.inner.get(queue, count, min_count, wait, auto_ack, lease).await
+    }
+
+    async fn delete(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        lease_token: Option<&str>,
+    ) -> Result<Vec<String>, StorageError> {
+        self.inner.delete(queue, ids, lease_token).await
+    }
+
+    async fn purge(&self, queue: &str) -> Result<usize, StorageError> {
+        self.inner.purge(queue).await
+    }
+
+    async fn purge_state(&self, queue: &str, state: MessageState) -> Result<usize, StorageError> {
+        self.inner.purge_state(queue, state).await
+    }
+
+    async fn retry(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        front: bool,
+        delay_secs: Option<u64>,
+    ) -> Result<Retried, StorageError> {
+        self.inner.retry(queue, ids, front, delay_secs).await
+    }
+
+    async fn requeue_all(&self, queue: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.requeue_all(queue).await
+    }
+
+    async fn extend_lock(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+        extra_secs: u64,
+        lease_token: Option<&str>,
+    ) -> Result<usize, StorageError> {
+        self.inner.extend_lock(queue, ids, extra_secs, lease_token).await
+    }
+
+    async fn peek(
+        &self,
+        queue: &str,
+        offset: usize,
+        count: usize,
+    ) -> Result<MessagePage, StorageError> {
+        self.inner.peek(queue, offset, count).await
+    }
+
+    async fn search(
+        &self,
+        queue: &str,
+        filter: &SearchFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Message>, StorageError> {
+        self.inner.search(queue, filter, offset, limit).await
+    }
+
+    async fn amend(
+        &self,
+        queue: &str,
+        id: &str,
+        body: MessageBody,
+        signature: Option<String>,
+        structured_body: Option<serde_json::Value>,
+    ) -> Result<Option<Message>, StorageError> {
+        self.inner.amend(queue, id, body, signature, structured_body).await
+    }
+
+    async fn get_by_id(&self, queue: &str, id: &str) -> Result<Option<Message>, StorageError> {
+        self.inner.get_by_id(queue, id).await
+    }
+
+    async fn dead_letters(&self, queue: &str, count: usize) -> Result<Vec<Message>, StorageError> {
+        self.inner.dead_letters(queue, count).await
+    }
+
+    async fn expired(
+        &self,
+        queue: &str,
+        count: usize,
+    ) -> Result<Vec<ExpiredMessage>, StorageError> {
+        self.inner.expired(queue, count).await
+    }
+
+    async fn requeue_dead_letters(
+        &self,
+        queue: &str,
+        ids: Vec<String>,
+    ) -> Result<(), StorageError> {
+        self.inner.requeue_dead_letters(queue, ids).await
+    }
+
+    async fn list_queues(&self) -> Result<Vec<String>, StorageError> {
+        self.inner.list_queues().await
+    }
+
+    async fn register_group(&self, queue: &str, group: &str) -> Result<bool, StorageError> {
+        self.inner.register_group(queue, group).await
+    }
+
+    async fn unregister_group(&self, queue: &str, group: &str) -> Result<usize, StorageError> {
+        self.inner.unregister_group(queue, group).await
+    }
+
+    async fn groups(&self, queue: &str) -> Result<Vec<String>, StorageError> {
+        self.inner.groups(queue).await
+    }
+
+    async fn stats(&self, queue: &str) -> Result<QueueStats, StorageError> {
+        self.inner.stats(queue).await
+    }
+
+    async fn remove_expired(&self, queue: &str) -> Result<usize, StorageError> {
+        self.inner.remove_expired(queue).await
+    }
+
+    async fn export(&self) -> Result<Vec<QueueExport>, StorageError> {
+        self.inner.export().await
+    }
+
+    async fn import(&self, queues: Vec<QueueExport>, replace: bool) -> Result<usize, StorageError> {
+        self.inner.import(queues, replace).await
+    }
+
+    async fn shutdown(&self) -> Result<(), StorageError> {
+        self.inner.shutdown().await
+    }
+}
+
+/// The `messages` and `groups` tables, kept in step with the storage's changes.
+struct SqliteLog {
+    db: Connection,
+    /// Written with each message, so queues are rebuilt in the order their
+    /// messages were last changed, as from the write-ahead log.
+    next_seq: i64,
+}
+
+impl SqliteLog {
+    /// Opens the database, creating the tables if needed, and returns it
+    /// together with records that rebuild what it holds.
+    fn open(path: &Path) -> Result<(SqliteLog, Vec<WalRecord>), StorageError> {
+        let db = Connection::open(path)?;
+        db.execute_batch(SCHEMA)?;
+
+        let mut records = Vec::new();
+        let mut groups = db.prepare("SELECT queue, name FROM groups ORDER BY queue, name")?;
+        while groups.step()? {
+            records.push(WalRecord::Group { queue: groups.text(0), group: groups.text(1) });
+        }
+        drop(groups);
+
+        let mut next_seq = 0;
+        let mut messages = db.prepare("SELECT queue, seq, message FROM messages ORDER BY seq")?;
+        while messages.step()? {
+            let message = serde_json::from_str(&messages.text(2)).map_err(|e| {
+                StorageError::Backend(format!("sqlite: unreadable message: {e}").into())
+            })?;
+            records.push(WalRecord::Put { queue: messages.text(0), message });
+            next_seq = messages.int(1) + 1;
+        }
+        drop(messages);
+
+        Ok((SqliteLog { db, next_seq }, records))
+    }
+
+    fn apply(&mut self, record: &WalRecord) -> Result<(), StorageError> {
+        match record {
+            WalRecord::Put { queue, message } => {
+                let json = serde_json::to_string(message).map_err(|e| {
+                    StorageError::Backend(format!("sqlite: unwritable message: {e}").into())
+                })?;
+                let state = serde_json::to_value(message.state)
+                    .ok()
+                    .and_then(|state| state.as_str().map(str::to_string))
+                    .unwrap_or_default();
+                let mut put = self.db.prepare(PUT)?;
+                put.bind_text(1, queue)?;
+                put.bind_text(2, &message.id.to_string())?;
+                put.bind_int(3, self.next_seq)?;
+                put.bind_text(4, &state)?;
+                match message.lock_until {
+                    Some(lock_until) => put.bind_int(5, lock_until)?,
+                    None => put.bind_null(5)?,
+                }
+                put.bind_int(6, i64::from(message.retry_count))?;
+                put.bind_text(7, &json)?;
+                put.run()?;
+                self.next_seq += 1;
+            }
+            WalRecord::Remove { queue, id } => {
+                let sql = "DELETE FROM messages WHERE queue = ?1 AND id = ?2";
+                let mut remove = self.db.prepare(sql)?;
+                remove.bind_text(1, queue)?;
+                remove.bind_text(2, id)?;
+                remove.run()?;
+            }
+            WalRecord::Purge { queue } => {
+                let mut purge = self.db.prepare("DELETE FROM messages WHERE queue = ?1")?;
+                purge.bind_text(1, queue)?;
+                purge.run()?;
+            }
+            WalRecord::Group { queue, group } => {
+                let sql = "INSERT OR IGNORE INTO groups (queue, name) VALUES (?1, ?2)";
+                let mut insert = self.db.prepare(sql)?;
+                insert.bind_text(1, queue)?;
+                insert.bind_text(2, group)?;
+                insert.run()?;
+            }
+            WalRecord::Ungroup { queue, group } => {
+                let sql = "DELETE FROM groups WHERE queue = ?1 AND name = ?2";
+                let mut remove = self.db.prepare(sql)?;
+                remove.bind_text(1, queue)?;
+                remove.bind_text(2, group)?;
+                remove.run()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `write` in a transaction, rolling it back if it fails.
+    fn transaction(
+        &mut self,
+        write: impl FnOnce(&mut Self) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        self.db.execute_batch("BEGIN IMMEDIATE")?;
+        match write(self) {
+            Ok(()) => self.db.execute_batch("COMMIT"),
+            Err(e) => {
+                let _ = self.db.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Journal for SqliteLog {
+    fn append(&mut self, records: &[WalRecord]) -> Result<(), StorageError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.transaction(|log| records.iter().try_for_each(|record| log.apply(record)))
+    }
+
+    /// The tables hold the current state only, so never grow stale.
+    fn needs_compaction(&self) -> bool {
+        false
+    }
+
+    fn compact(&mut self, records: Vec<WalRecord>) -> Result<(), StorageError> {
+        self.transaction(|log| {
+            log.db.execute_batch("DELETE FROM messages; DELETE FROM groups;")?;
+            log.next_seq = 0;
+            records.iter().try_for_each(|record| log.apply(record))
+        })
+    }
+}
+
+/// An open SQLite database, through the C library's own API.
+struct Connection {
+    handle: *mut ffi::Sqlite3,
+}
+
+// SAFETY: SQLite's default threading mode is serialized, so the handle may be
+// used from any thread, and `SqliteLog` only uses it through `&mut self` under
+// the storage's write lock.
+unsafe impl Send for Connection {}
+unsafe impl Sync for Connection {}
+
+impl Connection {
+    fn open(path: &Path) -> Result<Connection, StorageError> {
+        let path = path.to_str().and_then(|path| CString::new(path).ok()).ok_or_else(|| {
+            StorageError::Backend(format!("sqlite: unusable path {}", path.display()).into())
+        })?;
+        let mut handle = ptr::null_mut();
+        let flags = ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        // SAFETY: `path` is NUL-terminated and `handle` is written by the call.
+        let code = unsafe { ffi::sqlite3_open_v2(path.as_ptr(), &mut handle, flags, ptr::null()) };
+        // A handle is returned even on failure, so the error can be read and it closed.
+        let db = Connection { handle };
+        if code != ffi::SQLITE_OK {
+            return Err(db.error());
+        }
+        // SAFETY: `handle` is open.
+        unsafe { ffi::sqlite3_busy_timeout(db.handle, BUSY_TIMEOUT_MS) };
+        Ok(db)
+    }
+
+    fn execute_batch(&self, sql: &str) -> Result<(), StorageError> {
+        let sql = CString::new(sql).map_err(|e| StorageError::Backend(e.into()))?;
+        // SAFETY: `sql` is NUL-terminated; no callback or error message is asked for.
+        let code = unsafe {
+            ffi::sqlite3_exec(self.handle, sql.as_ptr(), None, ptr::null_mut(), ptr::null_mut())
+        };
+        self.check(code)
+    }
+
+    fn prepare(&self, sql: &str) -> Result<Statement<'_>, StorageError> {
+        let mut handle = ptr::null_mut();
+        // SAFETY: `sql` is valid for `sql.len()` bytes and `handle` is written by the call.
+        let code = unsafe {
+            ffi::sqlite3_prepare_v2(
+                self.handle,
+                sql.as_ptr().cast(),
+                sql.len() as c_int,
+                &mut handle,
+                ptr::null_mut(),
+            )
+        };
+        self.check(code)?;
+        Ok(Statement { db: self, handle })
+    }
+
+    fn check(&self, code: c_int) -> Result<(), StorageError> {
+        match code {
+            ffi::SQLITE_OK => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn error(&self) -> StorageError {
+        // SAFETY: SQLite returns a NUL-terminated message owned by the handle,
+        // copied out before anything else is done with it.
+        let message = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.handle)) };
+        let message = format!("sqlite: {}", message.to_string_lossy());
+        if message.contains("locked") || message.contains("busy") {
+            return StorageError::Unavailable(message);
+        }
+        StorageError::Backend(message.into())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // SAFETY: every statement borrows the connection, so all are finalized.
+        unsafe { ffi::sqlite3_close(self.handle) };
+    }
+}
+
+/// A prepared statement, finalized when dropped.
+struct Statement<'db> {
+    db: &'db Connection,
+    handle: *mut ffi::Sqlite3Stmt,
+}
+
+impl Statement<'_> {
+    fn bind_text(&mut self, index: c_int, value: &str) -> Result<(), StorageError> {
+        // SAFETY: SQLite copies the bytes before returning, as told by `SQLITE_TRANSIENT`.
+        let code = unsafe {
+            ffi::sqlite3_bind_text(
+                self.handle,
+                index,
+                value.as_ptr().cast(),
+                value.len() as c_int,
+                ffi::SQLITE_TRANSIENT,
+            )
+        };
+        self.db.check(code)
+    }
+
+    fn bind_int(&mut self, index: c_int, value: i64) -> Result<(), StorageError> {
+        // SAFETY: `handle` is a live statement.
+        self.db.check(unsafe { ffi::sqlite3_bind_int64(self.handle, index, value) })
+    }
+
+    fn bind_null(&mut self, index: c_int) -> Result<(), StorageError> {
+        // SAFETY: `handle` is a live statement.
+        self.db.check(unsafe { ffi::sqlite3_bind_null(self.handle, index) })
+    }
+
+    /// Advances to the next row, returning false once there are none left.
+    fn step(&mut self) -> Result<bool, StorageError> {
+        // SAFETY: `handle` is a live statement.
+        match unsafe { ffi::sqlite3_step(self.handle) } {
+            ffi::SQLITE_ROW => Ok(true),
+            ffi::SQLITE_DONE => Ok(false),
+            _ => Err(self.db.error()),
+        }
+    }
+
+    /// Runs a statement that returns no rows.
+    fn run(&mut self) -> Result<(), StorageError> {
+        self.step().map(|_| ())
+    }
+
+    fn text(&self, column: c_int) -> String {
+        // SAFETY: the text is valid for the reported number of bytes until the
+        // next step, and is copied out at once.
+        unsafe {
+            let text = ffi::sqlite3_column_text(self.handle, column);
+            if text.is_null() {
+                return String::new();
+            }
+            let len = ffi::sqlite3_column_bytes(self.handle, column) as usize;
+            String::from_utf8_lossy(std::slice::from_raw_parts(text, len)).into_owned()
+        }
+    }
+
+    fn int(&self, column: c_int) -> i64 {
+        // SAFETY: `handle` is a live statement positioned on a row.
+        unsafe { ffi::sqlite3_column_int64(self.handle, column) }
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `handle` is a live statement, not used again.
+        unsafe { ffi::sqlite3_finalize(self.handle) };
+    }
+}
+
+/// The parts of the SQLite C API used above, linked from the system library.
+mod ffi {
+    use std::ffi::{c_char, c_int, c_void};
+
+    pub(super) enum Sqlite3 {}
+    pub(super) enum Sqlite3Stmt {}
+
+    pub(super) const SQLITE_OK: c_int = 0;
+    pub(super) const SQLITE_ROW: c_int = 100;
+    pub(super) const SQLITE_DONE: c_int = 101;
+    pub(super) const SQLITE_OPEN_READWRITE: c_int = 0x02;
+    pub(super) const SQLITE_OPEN_CREATE: c_int = 0x04;
+    /// Tells SQLite to copy bound values, as `(sqlite3_destructor_type)-1`.
+    pub(super) const SQLITE_TRANSIENT: isize = -1;
+
+    type Callback =
+        unsafe extern "C" fn(*mut c_void, c_int, *mut *mut c_char, *mut *mut c_char) -> c_int;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        pub(super) fn sqlite3_open_v2(
+            filename: *const c_char,
+            db: *mut *mut Sqlite3,
+            flags: c_int,
+            vfs: *const c_char,
+        ) -> c_int;
+        pub(super) fn sqlite3_close(db: *mut Sqlite3) -> c_int;
+        pub(super) fn sqlite3_busy_timeout(db: *mut Sqlite3, ms: c_int) -> c_int;
+        pub(super) fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+        pub(super) fn sqlite3_exec(
+            db: *mut Sqlite3,
+            sql: *const c_char,
+            callback: Option<Callback>,
+            arg: *mut c_void,
+            errmsg: *mut *mut c_char,
+        ) -> c_int;
+        pub(super) fn sqlite3_prepare_v2(
+            db: *mut Sqlite3,
+            sql: *const c_char,
+            len: c_int,
+            stmt: *mut *mut Sqlite3Stmt,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        pub(super) fn sqlite3_bind_text(
+            stmt: *mut Sqlite3Stmt,
+            index: c_int,
+            value: *const c_char,
+            len: c_int,
+            destructor: isize,
+        ) -> c_int;
+        pub(super) fn sqlite3_bind_int64(stmt: *mut Sqlite3Stmt, index: c_int, value: i64) -> c_int;
+        pub(super) fn sqlite3_bind_null(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
+        pub(super) fn sqlite3_step(stmt: *mut Sqlite3Stmt) -> c_int;
+        pub(super) fn sqlite3_column_text(stmt: *mut Sqlite3Stmt, column: c_int) -> *const u8;
+        pub(super) fn sqlite3_column_bytes(stmt: *mut Sqlite3Stmt, column: c_int) -> c_int;
+        pub(super) fn sqlite3_column_int64(stmt: *mut Sqlite3Stmt, column: c_int) -> i64;
+        pub(super) fn sqlite3_finalize(stmt: *mut Sqlite3Stmt) -> c_int;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AddOptions;
+    use crate::MessageService;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn db_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("smql-test-{}.db", Uuid::new_v4()))
+    }
+
+    fn service(path: &Path) -> MessageService {
+        MessageService::new(Arc::new(SqliteStorage::open(path).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn queues_survive_a_reopen() {
+        let path = db_path();
+        let original = service(&path);
+        for body in ["a", "b", "c", "d"] {
+            original.add("jobs", body.into(), AddOptions::default()).await.unwrap();
+        }
+        let taken = original.get("jobs", Some(2), 0).await.unwrap();
+        original.ack("jobs", vec![taken[0].id.to_string()]).await.unwrap();
+        original.register_group("events", "audit").await.unwrap();
+        original.add("events", "e".into(), AddOptions::default()).await.unwrap();
+        drop(original);
+
+        let reopened = service(&path);
+        // The message still processing is back, as if its lock had expired.
+        let bodies: Vec<String> = reopened
+            .peek("jobs", Some(10))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| match message.body {
+                MessageBody::Text(text) => text,
+                body => panic!("not text: {body:?}"),
+            })
+            .collect();
+        assert_eq!(bodies, ["c", "d", "b"]);
+        let stats = reopened.stats("jobs").await.unwrap();
+        assert_eq!((stats.ready_count, stats.processing_count), (3, 0));
+        assert_eq!(reopened.groups("events").await.unwrap(), ["audit"]);
+        assert_eq!(reopened.stats("events:audit").await.unwrap().ready_count, 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_state_lock_and_retries_in_columns() {
+        let path = db_path();
+        let service = service(&path);
+        service.add("jobs", "a".into(), AddOptions::default()).await.unwrap();
+        let taken = service.get("jobs", Some(1), 0).await.unwrap();
+
+        let db = Connection::open(&path).unwrap();
+        let sql = "SELECT state, lock_until, retry_count FROM messages WHERE queue = 'jobs'";
+        let mut row = db.prepare(sql).unwrap();
+        assert!(row.step().unwrap());
+        assert_eq!(row.text(0), serde_json::to_value(MessageState::Processing).unwrap());
+        assert_eq!(Some(row.int(1)), taken[0].lock_until);
+        assert_eq!(row.int(2), 0);
+        assert!(!row.step().unwrap());
+        drop(row);
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn purges_and_deletes_reach_the_database() {
+        let path = db_path();
+        let service = service(&path);
+        for body in ["a", "b"] {
+            service.add("jobs", body.into(), AddOptions::default()).await.unwrap();
+            service.add("other", body.into(), AddOptions::default()).await.unwrap();
+        }
+        service.purge("other").await.unwrap();
+        let taken = service.get("jobs", Some(1), 0).await.unwrap();
+        service.ack("jobs", vec![taken[0].id.to_string()]).await.unwrap();
+        drop(service);
+
+        let db = Connection::open(&path).unwrap();
+        let mut rows = db.prepare("SELECT queue, message FROM messages").unwrap();
+        assert!(rows.step().unwrap());
+        assert_eq!(rows.text(0), "jobs");
+        assert!(rows.text(1).contains(r#""body":"b""#));
+        assert!(!rows.step().unwrap());
+        drop(rows);
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+//! Bodies sent in several requests and added as one message once complete,
+//! for messages too large to send comfortably in a single request.
+
+use crate::{AddOptions, MessageBody};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Uploads in progress by queue and upload id, each forgotten once it has
+/// gone `upload_timeout_secs` without a chunk.
+#[derive(Default)]
+pub(crate) struct Uploads {
+    uploads: Mutex<HashMap<(String, String), Upload>>,
+}
+
+/// A body being put together from chunks, with the settings of the message it
+/// will become.
+pub(crate) struct Upload {
+    pub(crate) options: AddOptions,
+    data: Vec<u8>,
+    /// Set once a chunk arrives as base64, making the whole body binary.
+    binary: bool,
+    /// Unix milliseconds after which the upload is abandoned.
+    expires_at: i64,
+}
+
+impl Upload {
+    /// Returns the body assembled so far: text if every chunk was text.
+    pub(crate) fn body(&self) -> MessageBody {
+        if self.binary {
+            return MessageBody::Binary { base64: self.data.clone() };
+        }
+        // Chunks may split a character, so only the joined text need be valid UTF-8.
+        match String::from_utf8(self.data.clone()) {
+            Ok(text) => MessageBody::Text(text),
+            Err(e) => MessageBody::Binary { base64: e.into_bytes() },
+        }
+    }
+}
+
+impl Uploads {
+    /// Starts an upload, returning its id.
+    pub(crate) fn begin(&self, queue: &str, options: AddOptions, expires_at: i64) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let upload = Upload { options, data: Vec::new(), binary: false, expires_at };
+        self.lock().insert((queue.to_string(), id.clone()), upload);
+        id
+    }
+
+    /// Appends a chunk unless it would take the body past `limit` bytes,
+    /// returning the size so far, or `None` if there is no such upload.
+    /// `Err` carries the size the body would have had.
+    pub(crate) fn append(
+        &self,
+        queue: &str,
+        id: &str,
+        chunk: MessageBody,
+        limit: usize,
+        now: i64,
+        expires_at: i64,
+    ) -> Option<Result<usize, usize>> {
+        let mut uploads = self.lock();
+        let upload = uploads
+            .get_mut(&(queue.to_string(), id.to_string()))
+            .filter(|upload| upload.expires_at > now)?;
+        let size = upload.data.len() + chunk.len();
+        if size > limit {
+            return Some(Err(size));
+        }
+        match chunk {
+            MessageBody::Text(text) => upload.data.extend_from_slice(text.as_bytes()),
+            MessageBody::Binary { base64 } => {
+                upload.data.extend_from_slice(&base64);
+                upload.binary = true;
+            }
+            // Never sent by clients; bodies are only compressed once stored.
+            MessageBody::Compressed(_) => {}
+        }
+        upload.expires_at = expires_at;
+        Some(Ok(size))
+    }
+
+    /// Takes an upload out to be completed.
+    pub(crate) fn take(&self, queue: &str, id: &str, now: i64) -> Option<Upload> {
+        let upload = self.lock().remove(&(queue.to_string(), id.to_string()))?;
+        (upload.expires_at > now).then_some(upload)
+    }
+
+    /// Puts back an upload whose message could not be added, so completing it
+    /// can be retried.
+    pub(crate) fn restore(&self, queue: &str, id: &str, upload: Upload) {
+        self.lock().insert((queue.to_string(), id.to_string()), upload);
+    }
+
+    /// Forgets uploads abandoned before `now`, returning how many there were.
+    pub(crate) fn remove_expired(&self, now: i64) -> usize {
+        let mut uploads = self.lock();
+        let before = uploads.len();
+        uploads.retain(|_, upload| upload.expires_at > now);
+        before - uploads.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(String, String), Upload>> {
+        self.uploads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::storage::MemoryStorage;
+    use crate::{set_test_config, Config, Error, MessageService};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn service() -> (Arc<MockClock>, MessageService) {
+        let clock = Arc::new(MockClock::now());
+        let service = MessageService::with_clock(Arc::new(MemoryStorage::new()), clock.clone());
+        (clock, service)
+    }
+
+    fn text(text: &str) -> MessageBody {
+        MessageBody::Text(text.into())
+    }
+
+    #[tokio::test]
+    async fn text_chunks_make_a_text_body() {
+        let (_, service) = service();
+        let id = service.begin_upload("jobs", AddOptions::default()).unwrap();
+        assert_eq!(service.append_upload("jobs", &id, text("ca")).unwrap(), 2);
+        assert_eq!(service.append_upload("jobs", &id, text("fé")).unwrap(), 5);
+        let message = service.complete_upload("jobs", &id).await.unwrap();
+        assert_eq!(message.body, text("café"));
+    }
+
+    #[tokio::test]
+    async fn base64_chunks_make_a_binary_body() {
+        let (_, service) = service();
+        let id = service.begin_upload("jobs", AddOptions::default()).unwrap();
+        // "é" is two bytes, sent in separate chunks.
+        let [first, second] = "é".as_bytes() else { unreachable!() };
+        service.append_upload("jobs", &id, text("caf")).unwrap();
+        service.append_upload("jobs", &id, vec![*first].into()).unwrap();
+        assert_eq!(service.append_upload("jobs", &id, vec![*second].into()).unwrap(), 5);
+        let message = service.complete_upload("jobs", &id).await.unwrap();
+        assert_eq!(message.body, MessageBody::Binary { base64: "café".as_bytes().to_vec() });
+    }
+
+    #[tokio::test]
+    async fn chunks_past_the_size_limit_leave_the_upload_intact() {
+        set_test_config(Config { max_message_size: 8, ..Config::default() });
+        let (_, service) = service();
+        let id = service.begin_upload("jobs", AddOptions::default()).unwrap();
+        service.append_upload("jobs", &id, text("12345")).unwrap();
+        let refused = service.append_upload("jobs", &id, text("6789"));
+        assert!(matches!(refused, Err(Error::BodyTooLarge { size: 9, limit: 8 })), "{refused:?}");
+        assert_eq!(service.append_upload("jobs", &id, text("678")).unwrap(), 8);
+        let message = service.complete_upload("jobs", &id).await.unwrap();
+        assert_eq!(message.body, text("12345678"));
+    }
+
+    #[tokio::test]
+    async fn abandoned_uploads_expire() {
+        let (clock, service) = service();
+        let timeout = Duration::from_secs(Config::default().upload_timeout_secs);
+        let id = service.begin_upload("jobs", AddOptions::default()).unwrap();
+        // Each chunk restarts the timeout.
+        clock.advance(timeout - Duration::from_secs(1));
+        service.append_upload("jobs", &id, text("a")).unwrap();
+        clock.advance(timeout - Duration::from_secs(1));
+        service.append_upload("jobs", &id, text("b")).unwrap();
+
+        clock.advance(timeout);
+        let appended = service.append_upload("jobs", &id, text("c"));
+        assert!(matches!(appended, Err(Error::UploadNotFound(_))), "{appended:?}");
+        let completed = service.complete_upload("jobs", &id).await;
+        assert!(matches!(completed, Err(Error::UploadNotFound(_))), "{completed:?}");
+        assert_eq!(service.stats("jobs").await.unwrap().ready_count, 0);
+    }
+
+    #[tokio::test]
+    async fn uploads_are_kept_when_the_add_fails() {
+        set_test_config(Config { max_queue_depth: Some(1), ..Config::default() });
+        let (_, service) = service();
+        service.add("jobs", text("first"), AddOptions::default()).await.unwrap();
+        let options = AddOptions { priority: 3, ..Default::default() };
+        let id = service.begin_upload("jobs", options).unwrap();
+        service.append_upload("jobs", &id, text("second")).unwrap();
+        let full = service.complete_upload("jobs", &id).await;
+        assert!(matches!(full, Err(Error::QueueFull)), "{full:?}");
+
+        service.purge("jobs").await.unwrap();
+        let message = service.complete_upload("jobs", &id).await.unwrap();
+        assert_eq!((message.body, message.priority), (text("second"), 3));
+        let again = service.complete_upload("jobs", &id).await;
+        assert!(matches!(again, Err(Error::UploadNotFound(_))), "{again:?}");
+    }
+
+    #[test]
+    fn remove_expired_forgets_only_abandoned_uploads() {
+        let uploads = Uploads::default();
+        uploads.begin("jobs", AddOptions::default(), 1_000);
+        let kept = uploads.begin("jobs", AddOptions::default(), 2_000);
+        assert_eq!(uploads.remove_expired(1_000), 1);
+        assert!(uploads.take("jobs", &kept, 1_000).is_some());
+        // Ids are per queue.
+        let other = uploads.begin("other", AddOptions::default(), 2_000);
+        assert!(uploads.take("jobs", &other, 0).is_none());
+    }
+}
@@ -0,0 +1,117 @@
+use crate::Message;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// A single entry in the write-ahead log.
+///
+/// Records describe the resulting state of a message rather than the operation
+/// that produced it, so replaying them does not depend on the clock or config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum WalRecord {
+    /// The message was stored with the given state, replacing any previous version.
+    Put { queue: String, message: Box<Message> },
+    /// The message was removed from the queue.
+    Remove { queue: String, id: String },
+    /// Every message in the queue was removed.
+    Purge { queue: String },
+    /// A consumer group was registered on the queue.
+    Group { queue: String, group: String },
+    /// A consumer group was removed from the queue.
+    Ungroup { queue: String, group: String },
+}
+
+/// An append-only log of `WalRecord`s stored as JSON lines.
+pub(crate) struct Wal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    records_since_compaction: usize,
+    compact_every: usize,
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if needed, and returns it together
+    /// with the records it already contains.
+    pub(crate) fn open(path: &Path, compact_every: usize) -> io::Result<(Wal, Vec<WalRecord>)> {
+        let records = match File::open(path) {
+            Ok(file) => Self::read_records(file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let wal = Wal {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(Self::open_append(path)?),
+            records_since_compaction: records.len(),
+            compact_every,
+        };
+        Ok((wal, records))
+    }
+
+    /// Appends records and flushes them to the OS.
+    pub(crate) fn append(&mut self, records: &[WalRecord]) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        for record in records {
+            Self::write_record(&mut self.writer, record)?;
+        }
+        self.writer.flush()?;
+        self.records_since_compaction += records.len();
+        Ok(())
+    }
+
+    /// Returns true once enough records have been appended to warrant a compaction.
+    pub(crate) fn needs_compaction(&self) -> bool {
+        self.records_since_compaction >= self.compact_every
+    }
+
+    /// Replaces the log with the given records, which should describe the full
+    /// current state. The new log is written to a temporary file and renamed into
+    /// place so a crash mid-compaction leaves the old log intact.
+    pub(crate) fn compact(&mut self, records: impl IntoIterator<Item = WalRecord>) -> io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        let mut written = 0;
+        for record in records {
+            Self::write_record(&mut tmp, &record)?;
+            written += 1;
+        }
+        tmp.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.writer = BufWriter::new(Self::open_append(&self.path)?);
+        self.records_since_compaction = written;
+        Ok(())
+    }
+
+    fn open_append(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn read_records(file: File) -> io::Result<Vec<WalRecord>> {
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                // A torn final write from a crash; everything before it is intact.
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+        Ok(records)
+    }
+
+    fn write_record(writer: &mut impl Write, record: &WalRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut *writer, record)?;
+        writer.write_all(b"\n")
+    }
+}
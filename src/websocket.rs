@@ -0,0 +1,387 @@
+//! Push delivery over WebSocket (RFC 6455): the frame codec, and the loop
+//! serving one consumer connection on a queue.
+//!
+//! A consumer may send `{"batch": n}` to hold up to `n` unacknowledged
+//! messages (default 1, capped at `max_get_count`), then `{"ack": [ids]}` or
+//! `{"nack": [ids]}` to settle them. The server pushes `{"messages": [...]}`
+//! as soon as messages arrive, waking with long-polling gets, and answers
+//! settlements with `{"acked": ...}` or `{"nacked": ...}`. Messages still
+//! unsettled when the connection ends are returned to the queue.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::{base64, config, sha1, DeleteOutcome, Error, Message, MessageService, RetryOutcome};
+use crate::MAX_WAIT_SECS;
+
+/// Appended to the client's `Sec-WebSocket-Key` before hashing it.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message accepted from a client, whole or reassembled from
+/// fragments. Clients only send small commands.
+const MAX_PAYLOAD_LEN: usize = 1 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// Returns the `Sec-WebSocket-Accept` answering a `Sec-WebSocket-Key`.
+pub(crate) fn accept_key(key: &str) -> String {
+    base64::encode(&sha1::digest(format!("{key}{GUID}").as_bytes()))
+}
+
+/// A whole message or control frame.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// A close, with its status code if the peer gave one.
+    Close(Option<u16>),
+}
+
+impl Frame {
+    /// Returns the frame on the wire, masked with `mask` if given, as clients
+    /// must and servers must not.
+    pub(crate) fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let (opcode, payload) = match self {
+            Frame::Text(text) => (OPCODE_TEXT, text.as_bytes().to_vec()),
+            Frame::Binary(bytes) => (OPCODE_BINARY, bytes.clone()),
+            Frame::Ping(bytes) => (OPCODE_PING, bytes.clone()),
+            Frame::Pong(bytes) => (OPCODE_PONG, bytes.clone()),
+            Frame::Close(code) => {
+                (OPCODE_CLOSE, code.map(|code| code.to_be_bytes().to_vec()).unwrap_or_default())
+            }
+        };
+
+        let mut frame = vec![0x80 | opcode];
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                frame.extend_from_slice(&mask);
+                frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(byte, key)| byte ^ key));
+            }
+            None => frame.extend_from_slice(&payload),
+        }
+        frame
+    }
+}
+
+/// Reads frames from a peer, reassembling fragmented messages around any
+/// control frames sent in between.
+pub(crate) struct Reader<R> {
+    inner: R,
+    /// Whether frames must be masked, i.e. the peer is a client.
+    masked: bool,
+    /// The opcode and payload so far of a fragmented message.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    pub(crate) fn new(inner: R, masked: bool) -> Self {
+        Self { inner, masked, partial: None }
+    }
+
+    /// Returns the next message or control frame. Not cancel-safe: a frame
+    /// read halfway is lost.
+    pub(crate) async fn next(&mut self) -> io::Result<Frame> {
+        loop {
+            let (fin, opcode, payload) = self.read_frame().await?;
+            match opcode {
+                OPCODE_PING => return Ok(Frame::Ping(payload)),
+                OPCODE_PONG => return Ok(Frame::Pong(payload)),
+                OPCODE_CLOSE => {
+                    let code = payload.get(..2).map(|code| u16::from_be_bytes([code[0], code[1]]));
+                    return Ok(Frame::Close(code));
+                }
+                OPCODE_TEXT | OPCODE_BINARY if self.partial.is_none() => {
+                    self.partial = Some((opcode, payload));
+                }
+                OPCODE_CONTINUATION if self.partial.is_some() => {
+                    let (_, message) = self.partial.as_mut().expect("checked above");
+                    if message.len() + payload.len() > MAX_PAYLOAD_LEN {
+                        return Err(invalid("message too large"));
+                    }
+                    message.extend_from_slice(&payload);
+                }
+                _ => return Err(invalid("unexpected opcode")),
+            }
+
+            if fin {
+                let (opcode, message) = self.partial.take().expect("set above");
+                return match opcode {
+                    OPCODE_TEXT => String::from_utf8(message)
+                        .map(Frame::Text)
+                        .map_err(|_| invalid("text is not UTF-8")),
+                    _ => Ok(Frame::Binary(message)),
+                };
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        self.inner.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 {
+            return Err(invalid("reserved bits set"));
+        }
+        if (head[1] & 0x80 != 0) != self.masked {
+            return Err(invalid("wrong masking"));
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => u64::from(self.inner.read_u16().await?),
+            127 => self.inner.read_u64().await?,
+            len => u64::from(len),
+        };
+        let control = opcode & 0x8 != 0;
+        if control && (!fin || len > 125) {
+            return Err(invalid("bad control frame"));
+        }
+        if len > MAX_PAYLOAD_LEN as u64 {
+            return Err(invalid("message too large"));
+        }
+
+        let mut mask = [0; 4];
+        if self.masked {
+            self.inner.read_exact(&mut mask).await?;
+        }
+        let mut payload = vec![0; len as usize];
+        self.inner.read_exact(&mut payload).await?;
+        if self.masked {
+            payload.iter_mut().zip(mask.iter().cycle()).for_each(|(byte, key)| *byte ^= key);
+        }
+        Ok((fin, opcode, payload))
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// A text frame from the consumer.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    /// Hold up to this many unsettled messages.
+    Batch(usize),
+    Ack(Vec<String>),
+    Nack(Vec<String>),
+}
+
+/// A text frame to the consumer.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+    Messages(Vec<Message>),
+    Acked(DeleteOutcome),
+    Nacked(RetryOutcome),
+    Error(String),
+}
+
+type Delivery = Pin<Box<dyn Future<Output = Result<Vec<Message>, Error>> + Send>>;
+
+/// Serves one consumer on an upgraded connection until either side closes it,
+/// then returns the consumer's unsettled messages to the queue.
+pub(crate) async fn serve<S>(service: MessageService, queue: String, stream: S)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    // Frames are read on their own task, as a frame read halfway can't be
+    // abandoned when a delivery is ready first.
+    let (frames_tx, mut frames) = mpsc::channel(16);
+    let reader = tokio::spawn(async move {
+        let mut reader = Reader::new(read, true);
+        loop {
+            let frame = reader.next().await;
+            let failed = frame.is_err();
+            if frames_tx.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let mut batch = 1;
+    let mut in_flight: Vec<String> = Vec::new();
+    let mut delivery: Option<Delivery> = None;
+    loop {
+        if delivery.is_none() && in_flight.len() < batch {
+            let (service, queue) = (service.clone(), queue.clone());
+            let count = batch - in_flight.len();
+            let wait = Duration::from_secs(MAX_WAIT_SECS);
+            delivery = Some(Box::pin(async move {
+                service.get_batch(&queue, Some(count), None, wait, false, None).await
+            }));
+        }
+        let delivered = async {
+            match delivery.as_mut() {
+                Some(delivery) => delivery.await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let reply = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(Ok(Frame::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(Command::Batch(count)) => {
+                        batch = count.clamp(1, config().max_get_count);
+                        continue;
+                    }
+                    Ok(Command::Ack(ids)) => {
+                        in_flight.retain(|id| !ids.contains(id));
+                        match service.ack(&queue, ids).await {
+                            Ok(outcome) => Frame::Text(json(&Reply::Acked(outcome))),
+                            Err(e) => Frame::Text(json(&Reply::Error(e.to_string()))),
+                        }
+                    }
+                    Ok(Command::Nack(ids)) => {
+                        in_flight.retain(|id| !ids.contains(id));
+                        match service.nack(&queue, ids).await {
+                            Ok(outcome) => Frame::Text(json(&Reply::Nacked(outcome))),
+                            Err(e) => Frame::Text(json(&Reply::Error(e.to_string()))),
+                        }
+                    }
+                    Err(e) => Frame::Text(json(&Reply::Error(format!("Invalid command: {e}")))),
+                },
+                Some(Ok(Frame::Binary(_))) => {
+                    Frame::Text(json(&Reply::Error("Commands must be text frames".to_string())))
+                }
+                Some(Ok(Frame::Ping(payload))) => Frame::Pong(payload),
+                Some(Ok(Frame::Pong(_))) => continue,
+                Some(Ok(Frame::Close(_))) => {
+                    let _ = write.write_all(&Frame::Close(Some(CLOSE_NORMAL)).encode(None)).await;
+                    break;
+                }
+                Some(Err(e)) => {
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        let close = Frame::Close(Some(CLOSE_PROTOCOL_ERROR));
+                        let _ = write.write_all(&close.encode(None)).await;
+                    }
+                    break;
+                }
+                None => break,
+            },
+            messages = delivered => {
+                delivery = None;
+                match messages {
+                    Ok(messages) if messages.is_empty() => continue,
+                    Ok(messages) => {
+                        in_flight.extend(messages.iter().map(|message| message.id.to_string()));
+                        Frame::Text(json(&Reply::Messages(messages)))
+                    }
+                    Err(e) => {
+                        tracing::error!("WebSocket delivery on {} failed: {}", queue, e);
+                        let close = Frame::Close(Some(CLOSE_INTERNAL_ERROR));
+                        let _ = write.write_all(&close.encode(None)).await;
+                        break;
+                    }
+                }
+            }
+        };
+
+        if write.write_all(&reply.encode(None)).await.is_err() {
+            break;
+        }
+    }
+
+    reader.abort();
+    drop(delivery);
+    if !in_flight.is_empty() {
+        if let Err(e) = service.nack(&queue, in_flight).await {
+            tracing::error!("Failed to return unacknowledged messages to {}: {}", queue, e);
+        }
+    }
+}
+
+fn json(reply: &Reply) -> String {
+    serde_json::to_string(reply).expect("replies serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn decode(bytes: Vec<u8>, masked: bool) -> io::Result<Frame> {
+        Reader::new(bytes.as_slice(), masked).next().await
+    }
+
+    #[test]
+    fn answers_the_rfc_handshake_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn round_trips_every_length_encoding() {
+        for len in [0, 125, 126, 0xffff, 0x10000] {
+            let frame = Frame::Binary(vec![7; len]);
+            let masked = frame.encode(Some([1, 2, 3, 4]));
+            assert_eq!(decode(masked, true).await.unwrap(), frame);
+            assert_eq!(decode(frame.encode(None), false).await.unwrap(), frame);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_unmasked_client_frames() {
+        let frame = Frame::Text("{}".to_string()).encode(None);
+        let error = decode(frame, true).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragments_around_control_frames() {
+        let mask = [9, 8, 7, 6];
+        let mut first = Frame::Text("hel".to_string()).encode(Some(mask));
+        first[0] &= !0x80;
+        let ping = Frame::Ping(b"hi".to_vec()).encode(Some(mask));
+        let mut last = Frame::Text("lo".to_string()).encode(Some(mask));
+        last[0] = 0x80 | OPCODE_CONTINUATION;
+        let bytes = [first, ping, last].concat();
+
+        let mut reader = Reader::new(bytes.as_slice(), true);
+        assert_eq!(reader.next().await.unwrap(), Frame::Ping(b"hi".to_vec()));
+        assert_eq!(reader.next().await.unwrap(), Frame::Text("hello".to_string()));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_messages() {
+        let frame = Frame::Binary(vec![0; MAX_PAYLOAD_LEN + 1]).encode(Some([1, 2, 3, 4]));
+        let error = decode(frame, true).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn reads_close_codes() {
+        let frame = Frame::Close(Some(CLOSE_NORMAL)).encode(Some([1, 2, 3, 4]));
+        assert_eq!(decode(frame, true).await.unwrap(), Frame::Close(Some(CLOSE_NORMAL)));
+        let frame = Frame::Close(None).encode(Some([1, 2, 3, 4]));
+        assert_eq!(decode(frame, true).await.unwrap(), Frame::Close(None));
+    }
+}
@@ -0,0 +1,75 @@
+//! Pins the ordering contract of the default storage: with many producers
+//! adding at once and a single consumer taking, every message is delivered
+//! exactly once, and each producer's messages arrive in the order it added them.
+
+use smql::storage::MemoryStorage;
+use smql::{AddOptions, MessageBody, MessageService};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+const QUEUE: &str = "ordering";
+const PRODUCERS: usize = 16;
+const PER_PRODUCER: usize = 500;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_adds_are_delivered_once_in_producer_order() {
+    let service = MessageService::new(Arc::new(MemoryStorage::new()));
+
+    let producers: Vec<_> = (0..PRODUCERS)
+        .map(|producer| {
+            let service = service.clone();
+            tokio::spawn(async move {
+                for seq in 0..PER_PRODUCER {
+                    let body = format!("{producer}:{seq}").into();
+                    service.add(QUEUE, body, AddOptions::default()).await.unwrap();
+                }
+            })
+        })
+        .collect();
+
+    // Takes and acks while the producers are still adding, so gets race adds.
+    let consumer = {
+        let service = service.clone();
+        tokio::spawn(async move {
+            let mut received = Vec::new();
+            while received.len() < PRODUCERS * PER_PRODUCER {
+                let messages = service.get(QUEUE, Some(100), 1).await.unwrap();
+                if messages.is_empty() {
+                    continue;
+                }
+                let ids = messages.iter().map(|message| message.id.to_string()).collect();
+                service.ack(QUEUE, ids).await.unwrap();
+                received.extend(messages.into_iter().map(|message| match message.body {
+                    MessageBody::Text(text) => text,
+                    body => panic!("unexpected body {body:?}"),
+                }));
+            }
+            received
+        })
+    };
+
+    for producer in producers {
+        producer.await.unwrap();
+    }
+    // A lost message would leave the consumer waiting for it forever.
+    let received = tokio::time::timeout(Duration::from_secs(30), consumer)
+        .await
+        .expect("messages were lost")
+        .unwrap();
+
+    let unique: HashSet<&String> = received.iter().collect();
+    assert_eq!(unique.len(), received.len(), "a message was delivered twice");
+    assert_eq!(received.len(), PRODUCERS * PER_PRODUCER);
+
+    let mut next = [0; PRODUCERS];
+    for body in &received {
+        let (producer, seq) = body.split_once(':').unwrap();
+        let (producer, seq): (usize, usize) = (producer.parse().unwrap(), seq.parse().unwrap());
+        assert_eq!(seq, next[producer], "producer {producer} was delivered out of order");
+        next[producer] += 1;
+    }
+
+    let stats = service.stats(QUEUE).await.unwrap();
+    assert_eq!((stats.ready_count, stats.processing_count), (0, 0));
+}